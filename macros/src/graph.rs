@@ -0,0 +1,179 @@
+//! Decision graphs of `when!`, written when the `KIAM_GRAPH_DIR` environment variable is set.
+
+use std::{fmt::Write as _, fs, io, path::Path};
+
+use proc_macro::{Span, TokenStream, TokenTree};
+
+use crate::parse::{Arm, When};
+
+/// The environment variable with the directory the graphs are written to.
+pub(crate) const DIR_VAR: &str = "KIAM_GRAPH_DIR";
+
+/// Branches longer than this are shortened, so that blocks don't take over the graph.
+const MAX_LEN: usize = 60;
+
+/// Writes `<crate>-<file>-<line>-<column>.dot` (Graphviz) and `<crate>-<file>-<line>-<column>.mmd`
+/// (Mermaid) to `dir`, where `<file>`, `<line>` and `<column>` are the location of `span`.
+///
+/// The name of the crate being compiled is included, since crates of a workspace usually have files
+/// with the same paths (`src/lib.rs`). It is omitted if the crate isn't compiled by Cargo.
+pub(crate) fn write(dir: &Path, span: Span, when: &When<'_>) -> io::Result<()> {
+    let graph = Graph::new(when);
+    let location = format!("{}-{}-{}", span.file(), span.line(), span.column());
+    let name: String = match std::env::var("CARGO_CRATE_NAME") {
+        Ok(krate) => format!("{}-{}", krate, location),
+        Err(_) => location,
+    }
+    .chars()
+    .map(|c| {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            c
+        } else {
+            '_'
+        }
+    })
+    .collect();
+
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(format!("{}.dot", name)), graph.dot())?;
+    fs::write(dir.join(format!("{}.mmd", name)), graph.mermaid())
+}
+
+/// The conditions of `when!`, each leading to a branch if it holds and to the next condition (or
+/// to the default branch) otherwise.
+struct Graph {
+    /// The subject, or `when!`
+    start: String,
+    /// The conditions and branches of the arms, except for the default one
+    arms: Vec<(String, String)>,
+    /// The default branch, if there is a default arm
+    default: Option<String>,
+}
+
+impl Graph {
+    fn new(when: &When<'_>) -> Self {
+        let mut graph = Graph {
+            start: match when.subject {
+                Some(subject) => text(subject),
+                None => "when!".to_owned(),
+            },
+            arms: Vec::new(),
+            default: None,
+        };
+
+        for arm in &when.arms {
+            match arm {
+                Arm::Line { branch, .. } if arm.is_default() => graph.default = Some(text(branch)),
+                Arm::Line {
+                    label,
+                    condition,
+                    branch,
+                    ..
+                } => {
+                    let condition = match label {
+                        Some(label) => format!("{}: {}", label, text(condition)),
+                        None => text(condition),
+                    };
+                    graph.arms.push((condition, text(branch)));
+                }
+                Arm::Macro(tokens) => graph.arms.push((text(tokens), "...".to_owned())),
+            }
+        }
+
+        graph
+    }
+
+    /// The node after the last condition.
+    fn end(&self) -> (&str, &str) {
+        match &self.default {
+            Some(default) => ("default", default),
+            None => ("none", "no arm fired"),
+        }
+    }
+
+    /// The nodes after `start` and after each condition which doesn't hold.
+    fn next(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.arms.len())
+            .map(|i| format!("cond{}", i))
+            .chain(Some(self.end().0.to_owned()))
+    }
+
+    fn dot(&self) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::from("digraph when {\n");
+        let (end, end_label) = self.end();
+
+        let _ = writeln!(
+            out,
+            "    start [label=\"{}\", shape=oval];",
+            escape(&self.start)
+        );
+        for (i, (condition, branch)) in self.arms.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "    cond{} [label=\"{}\", shape=diamond];",
+                i,
+                escape(condition)
+            );
+            let _ = writeln!(
+                out,
+                "    branch{} [label=\"{}\", shape=box];",
+                i,
+                escape(branch)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "    {} [label=\"{}\", shape=box];",
+            end,
+            escape(end_label)
+        );
+
+        for (i, next) in self.next().enumerate() {
+            let _ = match i {
+                0 => writeln!(out, "    start -> {};", next),
+                _ => writeln!(out, "    cond{} -> {} [label=\"false\"];", i - 1, next),
+            };
+        }
+        for i in 0..self.arms.len() {
+            let _ = writeln!(out, "    cond{} -> branch{} [label=\"true\"];", i, i);
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    fn mermaid(&self) -> String {
+        let escape = |s: &str| s.replace('"', "#quot;");
+        let mut out = String::from("flowchart TD\n");
+        let (end, end_label) = self.end();
+
+        let _ = writeln!(out, "    start([\"{}\"])", escape(&self.start));
+        for (i, (condition, branch)) in self.arms.iter().enumerate() {
+            let _ = writeln!(out, "    cond{}{{\"{}\"}}", i, escape(condition));
+            let _ = writeln!(out, "    branch{}[\"{}\"]", i, escape(branch));
+        }
+        let _ = writeln!(out, "    {}[\"{}\"]", end, escape(end_label));
+
+        for (i, next) in self.next().enumerate() {
+            let _ = match i {
+                0 => writeln!(out, "    start --> {}", next),
+                _ => writeln!(out, "    cond{} -- false --> {}", i - 1, next),
+            };
+        }
+        for i in 0..self.arms.len() {
+            let _ = writeln!(out, "    cond{} -- true --> branch{}", i, i);
+        }
+
+        out
+    }
+}
+
+/// Returns `tokens` as written (modulo whitespace), shortened to `MAX_LEN` characters.
+fn text(tokens: &[TokenTree]) -> String {
+    let text = tokens.iter().cloned().collect::<TokenStream>().to_string();
+    match text.char_indices().nth(MAX_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
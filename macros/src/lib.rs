@@ -0,0 +1,143 @@
+//! Proc-macro backend of [`kiam`], enabled by its `macros` feature.
+//!
+//! This crate is an implementation detail, use [`kiam::when!`] instead of depending on it directly.
+//!
+//! [`kiam`]: https://docs.rs/kiam
+//! [`kiam::when!`]: https://docs.rs/kiam/latest/kiam/macro.when.html
+#![forbid(unsafe_code)]
+#![deny(missing_docs)]
+
+extern crate proc_macro;
+
+mod graph;
+mod lints;
+mod lower;
+mod parse;
+mod when_fn;
+mod which;
+
+use proc_macro::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use crate::lints::Warning;
+
+/// Checks the arms of `when!` and expands to `$crate::__when!`, preceded by warnings, if any.
+///
+/// If the `KIAM_GRAPH_DIR` environment variable is set, also writes the decision graph of the arms
+/// to that directory.
+///
+/// The input is the path to `kiam` (`$crate`) in brackets, followed by the input of `when!`.
+#[doc(hidden)]
+#[proc_macro]
+pub fn when(input: TokenStream) -> TokenStream {
+    check(input, "when", false)
+}
+
+/// Same as [`when!`](macro@when), but expands to a `match` if the arms can be lowered to one.
+///
+/// The input is the path to `kiam` (`$crate`) in brackets, followed by the input of `match_when!`.
+#[doc(hidden)]
+#[proc_macro]
+pub fn match_when(input: TokenStream) -> TokenStream {
+    check(input, "match_when", true)
+}
+
+fn check(input: TokenStream, name: &str, lower: bool) -> TokenStream {
+    let mut input = input.into_iter();
+    let krate = match input.next() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Bracket => group.stream(),
+        _ => panic!(
+            "`kiam_macros::{0}!` must only be used through `kiam::{0}!`",
+            name
+        ),
+    };
+    let tokens: Vec<TokenTree> = input.collect();
+
+    let (warnings, lowered) = match parse::parse(&tokens) {
+        Ok(when) => {
+            if let (Some(dir), Some(first)) = (std::env::var_os(graph::DIR_VAR), tokens.first()) {
+                if let Err(error) = graph::write(dir.as_ref(), first.span(), &when) {
+                    let message =
+                        format!("failed to write the decision graph of `when!`: {}", error);
+                    return parse::Error::at(first.span(), message).into_compile_error();
+                }
+            }
+
+            let lowered = match lower {
+                true => lower::lower(&when),
+                false => None,
+            };
+            (lints::lint(&when), lowered)
+        }
+        Err(error) => return error.into_compile_error(),
+    };
+
+    let output = match lowered {
+        Some(lowered) => lowered,
+        None => {
+            let mut output = krate;
+            output.extend(vec![
+                TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+                TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+                TokenTree::Ident(Ident::new("__when", Span::call_site())),
+                TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+                TokenTree::Group(Group::new(Delimiter::Brace, tokens.into_iter().collect())),
+            ]);
+            output
+        }
+    };
+
+    if warnings.is_empty() {
+        return output;
+    }
+
+    // `{ <warnings> <output> }`
+    let mut block: TokenStream = warnings.into_iter().map(Warning::into_tokens).collect();
+    block.extend(output);
+    TokenTree::Group(Group::new(Delimiter::Brace, block)).into()
+}
+
+/// Expands to `$crate::when!` with the branches replaced by `(<branch>, Which::<variant>)`, and the
+/// definition of `enum Which`, with a variant per arm, unless the input starts with `enum <name>;`.
+///
+/// The input is the path to `kiam` (`$crate`) in brackets, followed by the input of `when_which!`.
+#[doc(hidden)]
+#[proc_macro]
+pub fn when_which(input: TokenStream) -> TokenStream {
+    let mut input = input.into_iter();
+    let krate = match input.next() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Bracket => group.stream(),
+        _ => panic!("`kiam_macros::when_which!` must only be used through `kiam::when_which!`"),
+    };
+    let tokens: Vec<TokenTree> = input.collect();
+
+    // `enum <path>;` names an enum defined by the caller
+    let (enum_, tokens) = match tokens.first() {
+        Some(TokenTree::Ident(ident)) if ident.to_string() == "enum" => {
+            match tokens.iter().position(
+                |token| matches!(token, TokenTree::Punct(punct) if punct.as_char() == ';'),
+            ) {
+                Some(semi) if semi > 1 => (Some(&tokens[1..semi]), &tokens[semi + 1..]),
+                _ => {
+                    let message = "expected `enum <name>;`";
+                    return parse::Error::spanning(&tokens, message).into_compile_error();
+                }
+            }
+        }
+        _ => (None, &*tokens),
+    };
+
+    match parse::parse(tokens).and_then(|when| which::expand(krate, enum_, &when)) {
+        Ok(output) => output,
+        Err(error) => error.into_compile_error(),
+    }
+}
+
+/// Defines a function whose body is arms of `when!`, with the default inferred from the return
+/// type, see [`kiam::when_fn`](https://docs.rs/kiam/latest/kiam/attr.when_fn.html).
+#[proc_macro_attribute]
+pub fn when_fn(attr: TokenStream, item: TokenStream) -> TokenStream {
+    match when_fn::expand(attr, item) {
+        Ok(output) => output,
+        Err(error) => error.into_compile_error(),
+    }
+}
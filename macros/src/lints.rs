@@ -0,0 +1,384 @@
+//! Warnings about arms which are accepted, but are most likely mistakes.
+
+use std::{collections::HashSet, str::FromStr};
+
+use proc_macro::{Delimiter, Group, Literal, Spacing, Span, TokenStream, TokenTree};
+
+use crate::parse::{Arm, When};
+
+/// A warning pointing at `span`.
+pub(crate) struct Warning {
+    span: Span,
+    /// The name of the deprecated constant, which is shown in the warning
+    name: &'static str,
+    message: String,
+}
+
+impl Warning {
+    /// Returns a block which uses a deprecated constant, since proc-macros can't emit warnings on
+    /// stable.
+    pub(crate) fn into_tokens(self) -> TokenStream {
+        let note = Literal::string(&self.message);
+        let block = format!(
+            "{{ #[deprecated(note = {note})] #[allow(non_upper_case_globals)] const {name}: u8 = 0; let _ = {name}; }}",
+            note = note,
+            name = self.name,
+        );
+
+        respan(TokenStream::from_str(&block).unwrap(), self.span)
+    }
+}
+
+/// Returns the warnings about the arms of `when`, at most one per arm.
+pub(crate) fn lint(when: &When<'_>) -> Vec<Warning> {
+    let mut warnings: Vec<Option<Warning>> = when.arms.iter().map(|_| None).collect();
+    unreachable_after_true(when, &mut warnings);
+    duplicate_conditions(when, &mut warnings);
+    always_false(when, &mut warnings);
+    ranges(when, &mut warnings);
+    warnings.into_iter().flatten().collect()
+}
+
+/// Arms after an arm with a condition which is always `true` are unreachable.
+fn unreachable_after_true(when: &When<'_>, warnings: &mut [Option<Warning>]) {
+    // In the subject form `true` is compared with the subject
+    if when.subject.is_some() {
+        return;
+    }
+
+    // An arm with attributes may be removed by `#[cfg]`
+    let always = when.arms.iter().position(|arm| match arm {
+        Arm::Line {
+            attrs: [],
+            condition,
+            ..
+        } => eval(condition) == Some(true),
+        _ => false,
+    });
+
+    let always = match always {
+        Some(always) => always,
+        None => return,
+    };
+
+    for (arm, warning) in when.arms.iter().zip(warnings).skip(always + 1) {
+        warning.get_or_insert_with(|| Warning {
+            span: arm.tokens()[0].span(),
+            name: "unreachable_arm",
+            message: "this arm is unreachable, the condition of a previous arm is always `true`"
+                .into(),
+        });
+    }
+}
+
+/// An arm with the same condition (and attributes) as a previous arm is unreachable, unless the
+/// condition has side effects.
+fn duplicate_conditions(when: &When<'_>, warnings: &mut [Option<Warning>]) {
+    let mut seen = HashSet::new();
+
+    for (arm, warning) in when.arms.iter().zip(warnings) {
+        let (attrs, condition) = match arm {
+            Arm::Line {
+                attrs, condition, ..
+            } if !arm.is_default() => (attrs, condition),
+            _ => continue,
+        };
+
+        let key = attrs
+            .iter()
+            .chain(condition.iter())
+            .cloned()
+            .collect::<TokenStream>()
+            .to_string();
+
+        if !seen.insert(key) {
+            warning.get_or_insert_with(|| Warning {
+                span: condition[0].span(),
+                name: "duplicate_condition",
+                message: "this arm is unreachable, a previous arm has the same condition".into(),
+            });
+        }
+    }
+}
+
+/// Arms with a condition which is always `false` never fire, they are usually unfinished.
+fn always_false(when: &When<'_>, warnings: &mut [Option<Warning>]) {
+    // In the subject form `false` is compared with the subject
+    if when.subject.is_some() {
+        return;
+    }
+
+    for (arm, warning) in when.arms.iter().zip(warnings) {
+        let condition = match arm {
+            Arm::Line { condition, .. } => condition,
+            Arm::Macro(_) => continue,
+        };
+
+        if eval(condition) == Some(false) {
+            warning.get_or_insert_with(|| Warning {
+                span: condition[0].span(),
+                name: "always_false",
+                message: "this arm never fires, its condition is always `false`".into(),
+            });
+        }
+    }
+}
+
+/// In the subject form, an arm whose integer values and ranges are all covered by the previous arms
+/// is unreachable. If all the arms compare the subject with integers (and some with ranges), the
+/// values between the ranges aren't handled, which is usually an off-by-one mistake.
+fn ranges(when: &When<'_>, warnings: &mut [Option<Warning>]) {
+    if when.subject.is_none() {
+        return;
+    }
+
+    // Disjoint, sorted inclusive ranges of the values handled by the previous arms
+    let mut covered: Vec<(i128, i128)> = Vec::new();
+    // The start of every range, with the index of the arm, to point at the arm after a gap
+    let mut starts = Vec::new();
+    // Gaps between single values (like `1 => ..., 3 => ...`) are usually intentional
+    let mut has_ranges = false;
+    let mut only_ints = true;
+
+    for (index, (arm, warning)) in when.arms.iter().zip(warnings.iter_mut()).enumerate() {
+        // An arm with attributes may be removed by `#[cfg]`
+        let ranges = match arm {
+            Arm::Line {
+                attrs: [],
+                condition,
+                ..
+            } => int_ranges(condition),
+            _ => None,
+        };
+
+        let ranges = match ranges {
+            Some(ranges) => ranges,
+            None => {
+                only_ints = false;
+                continue;
+            }
+        };
+
+        let is_covered =
+            |&(start, end): &(i128, i128)| covered.iter().any(|&(s, e)| s <= start && end <= e);
+        if ranges.iter().all(is_covered) {
+            warning.get_or_insert_with(|| Warning {
+                span: arm.tokens()[0].span(),
+                name: "unreachable_range",
+                message: "this arm is unreachable, its values are covered by the previous arms"
+                    .into(),
+            });
+        }
+
+        for range in ranges {
+            has_ranges |= range.0 != range.1;
+            starts.push((range.0, index));
+            insert(&mut covered, range);
+        }
+    }
+
+    if !only_ints || !has_ranges {
+        return;
+    }
+
+    for pair in covered.windows(2) {
+        let (gap_start, gap_end) = (pair[0].1 + 1, pair[1].0 - 1);
+        let gap = if gap_start == gap_end {
+            format!("`{}` is", gap_start)
+        } else {
+            format!("values in `{}..={}` are", gap_start, gap_end)
+        };
+
+        let index = starts
+            .iter()
+            .filter(|&&(start, _)| start == pair[1].0)
+            .map(|&(_, index)| index)
+            .min();
+        if let Some(index) = index {
+            warnings[index].get_or_insert_with(|| Warning {
+                span: when.arms[index].tokens()[0].span(),
+                name: "range_gap",
+                message: format!(
+                    "{} not handled by any arm, and there is no default arm",
+                    gap
+                ),
+            });
+        }
+    }
+}
+
+/// Returns the inclusive ranges of integers a subject form condition compares the subject with:
+/// `in <range>`, `let <range patterns>` and lists of values.
+fn int_ranges(condition: &[TokenTree]) -> Option<Vec<(i128, i128)>> {
+    let (separator, tokens) = match condition {
+        [TokenTree::Ident(kw), rest @ ..] if kw.to_string() == "in" => {
+            return Some(vec![range(rest)?])
+        }
+        [TokenTree::Ident(kw), rest @ ..] if kw.to_string() == "let" => ('|', rest),
+        _ => (',', condition),
+    };
+
+    let mut ranges = Vec::new();
+    for value in tokens.split(
+        |t| matches!(t, TokenTree::Punct(p) if p.as_char() == separator || p.as_char() == '|'),
+    ) {
+        ranges.push(range(value)?);
+    }
+    Some(ranges)
+}
+
+/// Parses `<int>`, `<int>..<int>` or `<int>..=<int>` (the bounds of a range are optional) into
+/// an inclusive range. Returns `None` for empty ranges.
+fn range(tokens: &[TokenTree]) -> Option<(i128, i128)> {
+    let (start, rest) = match int(tokens) {
+        Some((start, rest)) => (Some(start), rest),
+        None => (None, tokens),
+    };
+
+    let (inclusive, rest) = match rest {
+        [] => return start.map(|start| (start, start)),
+        [TokenTree::Punct(a), TokenTree::Punct(b), TokenTree::Punct(c), rest @ ..]
+            if a.as_char() == '.'
+                && b.as_char() == '.'
+                && c.as_char() == '='
+                && b.spacing() == Spacing::Joint =>
+        {
+            (true, rest)
+        }
+        [TokenTree::Punct(a), TokenTree::Punct(b), rest @ ..]
+            if a.as_char() == '.' && b.as_char() == '.' =>
+        {
+            (false, rest)
+        }
+        _ => return None,
+    };
+
+    let end = match rest {
+        [] if !inclusive => i128::MAX,
+        _ => match int(rest)? {
+            (end, []) if inclusive => end,
+            (end, []) => end - 1,
+            _ => return None,
+        },
+    };
+
+    let start = start.unwrap_or(i128::MIN);
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Adds `range` to the disjoint, sorted `ranges`, merging it with the overlapping and adjacent
+/// ones.
+fn insert(ranges: &mut Vec<(i128, i128)>, (mut start, mut end): (i128, i128)) {
+    ranges.retain(|&(s, e)| {
+        let merge = s <= end.saturating_add(1) && start <= e.saturating_add(1);
+        if merge {
+            start = start.min(s);
+            end = end.max(e);
+        }
+        !merge
+    });
+
+    let index = ranges.partition_point(|&(s, _)| s < start);
+    ranges.insert(index, (start, end));
+}
+
+/// Evaluates simple constant conditions: `true`, `false`, `(<bool>)`, `!<bool>` and comparisons of
+/// integer literals (like `0 > 1`).
+fn eval(condition: &[TokenTree]) -> Option<bool> {
+    match condition {
+        [TokenTree::Ident(ident)] => match &*ident.to_string() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        },
+        [TokenTree::Group(group)] if group.delimiter() == Delimiter::Parenthesis => {
+            eval(&group.stream().into_iter().collect::<Vec<_>>())
+        }
+        // `!` binds tighter than comparisons (`!0 > 1` is `(!0) > 1`), so it's only folded over a
+        // single operand
+        [TokenTree::Punct(not), operand @ ..]
+            if not.as_char() == '!'
+                && (operand.len() == 1
+                    || matches!(operand.first(), Some(TokenTree::Punct(p)) if p.as_char() == '!')) =>
+        {
+            eval(operand).map(|b| !b)
+        }
+        _ => {
+            let (lhs, rest) = int(condition)?;
+            let (op, rhs) = match rest {
+                [TokenTree::Punct(a), TokenTree::Punct(b), rhs @ ..]
+                    if a.spacing() == Spacing::Joint =>
+                {
+                    ([a.as_char(), b.as_char()], rhs)
+                }
+                [TokenTree::Punct(a), rhs @ ..] => ([a.as_char(), ' '], rhs),
+                _ => return None,
+            };
+            let rhs = match int(rhs)? {
+                (rhs, []) => rhs,
+                _ => return None,
+            };
+
+            match op {
+                ['=', '='] => Some(lhs == rhs),
+                ['!', '='] => Some(lhs != rhs),
+                ['<', '='] => Some(lhs <= rhs),
+                ['>', '='] => Some(lhs >= rhs),
+                ['<', ' '] => Some(lhs < rhs),
+                ['>', ' '] => Some(lhs > rhs),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Parses an integer literal, optionally preceded by `-`, at the start of `tokens`.
+pub(crate) fn int(tokens: &[TokenTree]) -> Option<(i128, &[TokenTree])> {
+    let (negative, tokens) = match tokens {
+        [TokenTree::Punct(minus), rest @ ..] if minus.as_char() == '-' => (true, rest),
+        _ => (false, tokens),
+    };
+
+    let (literal, rest) = match tokens {
+        [TokenTree::Literal(literal), rest @ ..] => (literal.to_string(), rest),
+        _ => return None,
+    };
+
+    let literal = literal.replace('_', "");
+    let (digits, radix) = match literal.get(..2) {
+        Some("0x") => (&literal[2..], 16),
+        Some("0o") => (&literal[2..], 8),
+        Some("0b") => (&literal[2..], 2),
+        _ => (&*literal, 10),
+    };
+
+    // Integer suffixes (`u8`, `isize`, ...)
+    let digits = match digits.find(['i', 'u']) {
+        Some(suffix) => &digits[..suffix],
+        None => digits,
+    };
+
+    let value = i128::from_str_radix(digits, radix).ok()?;
+    Some((if negative { -value } else { value }, rest))
+}
+
+/// Sets the span of all the tokens in `stream` to `span`.
+fn respan(stream: TokenStream, span: Span) -> TokenStream {
+    stream
+        .into_iter()
+        .map(|mut token| {
+            if let TokenTree::Group(group) = &token {
+                let mut respanned = Group::new(group.delimiter(), respan(group.stream(), span));
+                respanned.set_span(span);
+                token = TokenTree::Group(respanned);
+            } else {
+                token.set_span(span);
+            }
+
+            token
+        })
+        .collect()
+}
@@ -0,0 +1,347 @@
+//! Lowering of `when!` to something faster than a chain of `if`s, when that's possible.
+
+use std::str::FromStr;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use crate::{
+    lints::int,
+    parse::{Arm, When},
+};
+
+/// Lowers chains of comparisons of the same `x` with literals to a `match`:
+///
+/// - `x == <integer literal>` is lowered to `match x { <literal> => ... }`, so that LLVM can build
+///   a jump table
+/// - `x == "<string literal>"` is lowered to a `match` on the length of `x`, so that at most the
+///   literals of the same length are compared with `x`
+///
+/// `x` must be a variable or a field (`self.op`), so evaluating it once doesn't change anything.
+pub(crate) fn lower(when: &When<'_>) -> Option<TokenStream> {
+    let chain = chain(when)?;
+    let lowered = match chain.kind {
+        Kind::Int => int_match(&chain),
+        Kind::Str => str_match(&chain),
+    };
+
+    // `{ <preamble> <lowered> }`
+    let mut block: TokenStream = when.preamble.iter().cloned().collect();
+    block.extend(lowered);
+    Some(group(Delimiter::Brace, block).into())
+}
+
+/// Arms comparing the same place with literals of the same kind.
+struct Chain<'a> {
+    place: &'a [TokenTree],
+    kind: Kind,
+    arms: Vec<ChainArm<'a>>,
+    default: Option<&'a [TokenTree]>,
+}
+
+struct ChainArm<'a> {
+    attrs: &'a [TokenTree],
+    literal: &'a [TokenTree],
+    /// The length of the string literal, for `Kind::Str`
+    len: usize,
+    branch: &'a [TokenTree],
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Int,
+    Str,
+}
+
+fn chain<'a>(when: &When<'a>) -> Option<Chain<'a>> {
+    if when.subject.is_some() {
+        return None;
+    }
+
+    let mut place = None;
+    let mut kind = None;
+    let mut values = Vec::new();
+    let mut arms = Vec::new();
+    let mut default = None;
+
+    for arm in &when.arms {
+        let (attrs, condition, branch) = match arm {
+            Arm::Line {
+                attrs,
+                condition,
+                branch,
+                ..
+            } => (*attrs, *condition, *branch),
+            Arm::Macro(_) => return None,
+        };
+
+        if arm.is_default() {
+            default = Some(branch);
+            continue;
+        }
+
+        // `#[likely]`, `#[unlikely]` and `#[cost(...)]` are not valid on `match` arms
+        if attrs.iter().any(is_kiam_attr) {
+            return None;
+        }
+
+        let (lhs, rhs) = split_eq(condition)?;
+        if !is_place(lhs) {
+            return None;
+        }
+
+        let lhs_string = lhs.iter().cloned().collect::<TokenStream>().to_string();
+        match &place {
+            None => place = Some((lhs, lhs_string)),
+            Some((_, first)) if *first == lhs_string => {}
+            Some(_) => return None,
+        }
+
+        let (arm_kind, value, len) = match (int(rhs), rhs) {
+            (Some((value, [])), _) => (Kind::Int, value.to_string(), 0),
+            (_, [TokenTree::Literal(literal)]) => {
+                let value = str_value(literal)?;
+                (Kind::Str, value.to_owned(), value.len())
+            }
+            _ => return None,
+        };
+
+        if *kind.get_or_insert(arm_kind) != arm_kind {
+            return None;
+        }
+
+        // Duplicate patterns would be reported as unreachable by rustc
+        if values.contains(&value) {
+            return None;
+        }
+        values.push(value);
+
+        arms.push(ChainArm {
+            attrs,
+            literal: rhs,
+            len,
+            branch,
+        });
+    }
+
+    // A single comparison doesn't need a `match`
+    match (place, kind) {
+        (Some((place, _)), Some(kind)) if arms.len() >= 2 => Some(Chain {
+            place,
+            kind,
+            arms,
+            default,
+        }),
+        _ => None,
+    }
+}
+
+/// `match <place> { <literal> => <branch>, ... _ => <default> }`
+fn int_match(chain: &Chain<'_>) -> TokenStream {
+    let mut arms = TokenStream::new();
+    for arm in &chain.arms {
+        arms.extend(arm.attrs.iter().cloned());
+        arms.extend(arm.literal.iter().cloned());
+        arms.extend(arrow());
+        arms.extend(arm.branch.iter().cloned());
+        arms.extend(comma());
+    }
+    arms.extend(default_arm(chain.default));
+
+    let mut output = TokenStream::from(ident("match"));
+    output.extend(chain.place.iter().cloned());
+    output.extend(Some(group(Delimiter::Brace, arms)));
+    output
+}
+
+/// ```text
+/// let s: &str = AsRef::<str>::as_ref(&<place>);
+/// let arm: usize = match s.len() {
+///     <len> => match s { <literal> => <index>, ... _ => <arms count> },
+///     ...
+///     _ => <arms count>,
+/// };
+/// match arm { <index> => <branch>, ... _ => <default> }
+/// ```
+fn str_match(chain: &Chain<'_>) -> TokenStream {
+    let count = Literal::usize_unsuffixed(chain.arms.len());
+
+    let mut lens: Vec<usize> = chain.arms.iter().map(|arm| arm.len).collect();
+    lens.sort_unstable();
+    lens.dedup();
+
+    let mut by_len = TokenStream::new();
+    for len in lens {
+        let mut inner = TokenStream::new();
+        for (index, arm) in chain.arms.iter().enumerate() {
+            if arm.len == len {
+                inner.extend(arm.attrs.iter().cloned());
+                inner.extend(arm.literal.iter().cloned());
+                inner.extend(arrow());
+                inner.extend(Some(TokenTree::Literal(Literal::usize_unsuffixed(index))));
+                inner.extend(comma());
+            }
+        }
+        inner.extend(wildcard());
+        inner.extend(Some(TokenTree::Literal(count.clone())));
+
+        by_len.extend(Some(TokenTree::Literal(Literal::usize_unsuffixed(len))));
+        by_len.extend(arrow());
+        by_len.extend(Some(ident("match")));
+        by_len.extend(Some(local("s")));
+        by_len.extend(Some(group(Delimiter::Brace, inner)));
+        by_len.extend(comma());
+    }
+    by_len.extend(wildcard());
+    by_len.extend(Some(TokenTree::Literal(count)));
+
+    let mut branches = TokenStream::new();
+    for (index, arm) in chain.arms.iter().enumerate() {
+        branches.extend(arm.attrs.iter().cloned());
+        branches.extend(Some(TokenTree::Literal(Literal::usize_unsuffixed(index))));
+        branches.extend(arrow());
+        branches.extend(arm.branch.iter().cloned());
+        branches.extend(comma());
+    }
+    branches.extend(default_arm(chain.default));
+
+    let place: TokenStream = chain.place.iter().cloned().collect();
+    TokenStream::from_str(
+        "let s: &::core::primitive::str = ::core::convert::AsRef::<::core::primitive::str>::as_ref(&PLACE);
+         let arm: ::core::primitive::usize = match s.len() { BY_LEN };
+         match arm { BRANCHES }",
+    )
+    .unwrap()
+    .into_iter()
+    .flat_map(|token| replace(token, &place, &by_len, &branches))
+    .collect()
+}
+
+/// Replaces `PLACE`, `BY_LEN` and `BRANCHES` in `token` and makes `s` and `arm` locals.
+fn replace(
+    token: TokenTree,
+    place: &TokenStream,
+    by_len: &TokenStream,
+    branches: &TokenStream,
+) -> TokenStream {
+    match &token {
+        TokenTree::Ident(ident) => match &*ident.to_string() {
+            "PLACE" => place.clone(),
+            "BY_LEN" => by_len.clone(),
+            "BRANCHES" => branches.clone(),
+            "s" | "arm" => local(&ident.to_string()).into(),
+            _ => token.into(),
+        },
+        TokenTree::Group(old) => {
+            let stream = old
+                .stream()
+                .into_iter()
+                .flat_map(|token| replace(token, place, by_len, branches))
+                .collect();
+            let mut new = Group::new(old.delimiter(), stream);
+            new.set_span(old.span());
+            TokenTree::Group(new).into()
+        }
+        _ => token.into(),
+    }
+}
+
+/// Returns the value of a string literal without escapes.
+fn str_value(literal: &Literal) -> Option<String> {
+    let literal = literal.to_string();
+    let (raw, rest) = match literal.strip_prefix('r') {
+        Some(rest) => (true, rest),
+        None => (false, &*literal),
+    };
+
+    let hashes = rest.len() - rest.trim_start_matches('#').len();
+    let value = rest[hashes..]
+        .strip_prefix('"')?
+        .strip_suffix(&rest[..hashes])?
+        .strip_suffix('"')?;
+
+    if !raw && value.contains('\\') {
+        return None;
+    }
+
+    Some(value.to_owned())
+}
+
+/// Splits `<lhs> == <rhs>` at the first `==`.
+fn split_eq(condition: &[TokenTree]) -> Option<(&[TokenTree], &[TokenTree])> {
+    let eq = (0..condition.len()).find(|&i| match (&condition[i], condition.get(i + 1)) {
+        (TokenTree::Punct(a), Some(TokenTree::Punct(b))) => {
+            a.as_char() == '=' && a.spacing() == Spacing::Joint && b.as_char() == '='
+        }
+        _ => false,
+    })?;
+
+    Some((&condition[..eq], &condition[eq + 2..]))
+}
+
+/// Returns `true` for `a` and `a.b.c`.
+fn is_place(tokens: &[TokenTree]) -> bool {
+    !tokens.is_empty()
+        && tokens.iter().enumerate().all(|(i, token)| match token {
+            TokenTree::Ident(_) => i % 2 == 0,
+            TokenTree::Punct(dot) => i % 2 == 1 && dot.as_char() == '.',
+            _ => false,
+        })
+        && tokens.len() % 2 == 1
+}
+
+/// Returns `true` for the `[likely]`, `[unlikely]` and `[cost(...)]` groups of attributes.
+fn is_kiam_attr(token: &TokenTree) -> bool {
+    match token {
+        TokenTree::Group(group) if group.delimiter() == Delimiter::Bracket => {
+            match group.stream().into_iter().next() {
+                Some(TokenTree::Ident(name)) => {
+                    matches!(&*name.to_string(), "likely" | "unlikely" | "cost")
+                }
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// `_ => <default>` or `_ => {}`, like `if` without `else`
+fn default_arm(default: Option<&[TokenTree]>) -> TokenStream {
+    let mut arm = wildcard();
+    match default {
+        Some(branch) => arm.extend(branch.iter().cloned()),
+        None => arm.extend(Some(group(Delimiter::Brace, TokenStream::new()))),
+    }
+    arm
+}
+
+/// `_ =>`
+fn wildcard() -> TokenStream {
+    let mut tokens = TokenStream::from(ident("_"));
+    tokens.extend(arrow());
+    tokens
+}
+
+/// `=>`
+fn arrow() -> [TokenTree; 2] {
+    [
+        TokenTree::Punct(Punct::new('=', Spacing::Joint)),
+        TokenTree::Punct(Punct::new('>', Spacing::Alone)),
+    ]
+}
+
+fn comma() -> Option<TokenTree> {
+    Some(TokenTree::Punct(Punct::new(',', Spacing::Alone)))
+}
+
+fn ident(name: &str) -> TokenTree {
+    TokenTree::Ident(Ident::new(name, Span::call_site()))
+}
+
+/// A local variable, which is not visible to the tokens from the input.
+fn local(name: &str) -> TokenTree {
+    TokenTree::Ident(Ident::new(name, Span::mixed_site()))
+}
+
+fn group(delimiter: Delimiter, stream: TokenStream) -> TokenTree {
+    TokenTree::Group(Group::new(delimiter, stream))
+}
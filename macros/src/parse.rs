@@ -0,0 +1,441 @@
+//! A shallow parser of the `when!` input.
+//!
+//! Conditions and branches are not parsed, the parser only finds the boundaries of the arms, which
+//! is enough to point at the exact arm which is malformed. Everything else is left to the
+//! declarative implementation.
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// An error spanning from `start` to `end`.
+pub(crate) struct Error {
+    start: Span,
+    end: Span,
+    message: String,
+}
+
+impl Error {
+    /// Creates an error spanning `tokens`, which must not be empty.
+    pub(crate) fn spanning(tokens: &[TokenTree], message: impl Into<String>) -> Self {
+        Self {
+            start: tokens[0].span(),
+            end: tokens[tokens.len() - 1].span(),
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn at(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            start: span,
+            end: span,
+            message: message.into(),
+        }
+    }
+
+    /// Returns `::core::compile_error! { "<message>" }`.
+    ///
+    /// Spans can't be joined on stable, but the compiler reports the span of the whole macro
+    /// invocation, so the tokens before the braces are spanned with `start` and the braces with
+    /// `end`.
+    pub(crate) fn into_compile_error(self) -> TokenStream {
+        let punct = |ch, spacing| {
+            let mut punct = Punct::new(ch, spacing);
+            punct.set_span(self.start);
+            TokenTree::Punct(punct)
+        };
+
+        let mut message = Literal::string(&self.message);
+        message.set_span(self.end);
+        let mut group = Group::new(Delimiter::Brace, TokenTree::Literal(message).into());
+        group.set_span(self.end);
+
+        vec![
+            punct(':', Spacing::Joint),
+            punct(':', Spacing::Alone),
+            TokenTree::Ident(Ident::new("core", self.start)),
+            punct(':', Spacing::Joint),
+            punct(':', Spacing::Alone),
+            TokenTree::Ident(Ident::new("compile_error", self.start)),
+            punct('!', Spacing::Alone),
+            TokenTree::Group(group),
+        ]
+        .into_iter()
+        .collect()
+    }
+}
+
+/// The input of `when!`.
+pub(crate) struct When<'a> {
+    /// The `let` statements before the arms
+    pub(crate) preamble: &'a [TokenTree],
+    /// The subject, if this is the subject form
+    pub(crate) subject: Option<&'a [TokenTree]>,
+    pub(crate) arms: Vec<Arm<'a>>,
+}
+
+/// An arm of `when!`.
+pub(crate) enum Arm<'a> {
+    /// `<attributes> <label>: <condition> => <branch>`, the label is optional
+    Line {
+        /// All the tokens of the arm, without the trailing `,`
+        tokens: &'a [TokenTree],
+        attrs: &'a [TokenTree],
+        label: Option<&'a TokenTree>,
+        /// The tokens before `=>`, without attributes and the label
+        condition: &'a [TokenTree],
+        /// The tokens after `=>`
+        branch: &'a [TokenTree],
+    },
+    /// A macro invocation generating arms
+    Macro(&'a [TokenTree]),
+}
+
+impl<'a> Arm<'a> {
+    pub(crate) fn tokens(&self) -> &'a [TokenTree] {
+        match *self {
+            Arm::Line { tokens, .. } | Arm::Macro(tokens) => tokens,
+        }
+    }
+
+    /// Returns `true` for `_ => ...` and `else => ...`.
+    pub(crate) fn is_default(&self) -> bool {
+        match self {
+            Arm::Line { condition, .. } => match condition {
+                [TokenTree::Ident(ident)] => {
+                    let ident = ident.to_string();
+                    ident == "_" || ident == "else"
+                }
+                _ => false,
+            },
+            Arm::Macro(_) => false,
+        }
+    }
+}
+
+/// Parses the input of `when!`.
+pub(crate) fn parse(input: &[TokenTree]) -> Result<When<'_>, Error> {
+    let mut tokens = input;
+
+    // `let <pat> = <expr>;` preamble
+    while is_ident(tokens.first(), "let") {
+        match find_semi(tokens) {
+            Some(semi) => tokens = &tokens[semi + 1..],
+            None => break,
+        }
+    }
+
+    let preamble = &input[..input.len() - tokens.len()];
+
+    // `<subject>;`
+    let mut subject = None;
+    if let Some(semi) = find_semi(tokens) {
+        if semi == 0 {
+            return Err(Error::at(tokens[0].span(), "expected a subject before `;`"));
+        }
+
+        subject = Some(&tokens[..semi]);
+        tokens = &tokens[semi + 1..];
+    }
+
+    let mut arms = Vec::new();
+    while !tokens.is_empty() {
+        let (arm, rest) = parse_arm(tokens)?;
+        arms.push(arm);
+        tokens = rest;
+    }
+
+    if let Some(default) = arms.iter().position(Arm::is_default) {
+        let unreachable = &arms[default + 1..];
+        if let (Some(first), Some(last)) = (unreachable.first(), unreachable.last()) {
+            let (first, last) = (first.tokens(), last.tokens());
+            return Err(Error {
+                start: first[0].span(),
+                end: last[last.len() - 1].span(),
+                message: "default arm must be last; following arms are unreachable".into(),
+            });
+        }
+    }
+
+    Ok(When {
+        preamble,
+        subject,
+        arms,
+    })
+}
+
+/// Parses an arm and the `,` after it, returns the arm and the rest of the tokens.
+fn parse_arm(tokens: &[TokenTree]) -> Result<(Arm<'_>, &[TokenTree]), Error> {
+    if let Some(len) = macro_call_len(tokens) {
+        return Ok((Arm::Macro(&tokens[..len]), skip_comma(&tokens[len..])));
+    }
+
+    // `if` chains pasted into `when!`
+    if let [else_, next, ..] = strip_label(strip_attrs(tokens)) {
+        if is_ident(Some(else_), "else") {
+            if is_ident(Some(next), "if") {
+                return Err(Error::spanning(
+                    &[else_.clone(), next.clone()],
+                    "unexpected `else if`: arms of `when!` are already checked in order, remove `else`",
+                ));
+            }
+
+            if matches!(next, TokenTree::Group(group) if group.delimiter() == Delimiter::Brace) {
+                return Err(Error::spanning(
+                    &[else_.clone(), next.clone()],
+                    "unexpected `else { ... }`: use `_ => { ... }` for the default arm",
+                ));
+            }
+        }
+    }
+
+    let arrow = match find_arrow(tokens) {
+        Some(arrow) => arrow,
+        None => {
+            let arm = match tokens {
+                [arm @ .., comma] if is_punct(Some(comma), ',') => arm,
+                _ => tokens,
+            };
+            check_head(arm)?;
+            return Err(Error::spanning(arm, "missing `=>` after the condition"));
+        }
+    };
+
+    check_head(&tokens[..arrow])?;
+
+    let head = strip_attrs(&tokens[..arrow]);
+    let condition = strip_label(head);
+    if condition.is_empty() {
+        return Err(Error::at(
+            tokens[arrow].span(),
+            "expected a condition before `=>`",
+        ));
+    }
+
+    let after_arrow = &tokens[arrow + 2..];
+    let end = find_comma(after_arrow).unwrap_or(after_arrow.len());
+    let branch = &after_arrow[..end];
+
+    let branch_len = match (branch.first(), find_arrow(branch)) {
+        (None, _) => {
+            return Err(Error::at(
+                tokens[arrow + 1].span(),
+                "expected a branch after `=>`",
+            ));
+        }
+        // Branches which are blocks don't need a `,` after them
+        (Some(TokenTree::Group(group)), Some(_)) if group.delimiter() == Delimiter::Brace => 1,
+        // `else { ... }` after a block is reported as the next arm, a literal or an identifier
+        // after a block starts the next arm (like `{ ... } 1, 2 => ...`)
+        (Some(TokenTree::Group(group)), None)
+            if group.delimiter() == Delimiter::Brace
+                && match branch.get(1) {
+                    Some(TokenTree::Literal(_)) => true,
+                    Some(TokenTree::Ident(ident)) => ident.to_string() != "as",
+                    _ => false,
+                } =>
+        {
+            1
+        }
+        (Some(_), None) => branch.len(),
+        (Some(_), Some(next_arrow)) => {
+            return Err(Error::spanning(
+                &branch[..next_arrow],
+                "missing `,` after the branch",
+            ));
+        }
+    };
+
+    let arm_len = arrow + 2 + branch_len;
+    let arm = Arm::Line {
+        tokens: &tokens[..arm_len],
+        attrs: &tokens[..arrow - head.len()],
+        label: if head.len() > condition.len() {
+            Some(&head[0])
+        } else {
+            None
+        },
+        condition,
+        branch: &branch[..branch_len],
+    };
+
+    Ok((arm, skip_comma(&tokens[arm_len..])))
+}
+
+/// Reports common typos in the tokens before `=>`: `->` instead of `=>` and a missing `=>` before
+/// the default arm (`cond branch, _ => ...`).
+fn check_head(head: &[TokenTree]) -> Result<(), Error> {
+    // The type in `is` arms may contain `->`
+    if !is_ident(strip_label(strip_attrs(head)).first(), "is") {
+        let rarrow = find_top_level(head, |tokens, i| {
+            is_joint(&tokens[i], '-') && is_punct(tokens.get(i + 1), '>')
+        });
+
+        if let Some(i) = rarrow {
+            return Err(Error::spanning(
+                &head[i..i + 2],
+                "expected `=>`, found `->`",
+            ));
+        }
+    }
+
+    if let [arm @ .., comma, default] = head {
+        if !arm.is_empty()
+            && is_punct(Some(comma), ',')
+            && (is_ident(Some(default), "_") || is_ident(Some(default), "else"))
+        {
+            return Err(Error::spanning(arm, "missing `=>` after the condition"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the number of tokens in `..<path>!(...)`, if `tokens` start with a macro invocation
+/// marked with `..` which is followed by `,` or nothing.
+fn macro_call_len(tokens: &[TokenTree]) -> Option<usize> {
+    if !is_punct(tokens.first(), '.') || !is_punct(tokens.get(1), '.') {
+        return None;
+    }
+
+    let mut i = 2;
+    loop {
+        match tokens.get(i) {
+            Some(TokenTree::Ident(_)) => i += 1,
+            _ => return None,
+        }
+
+        if is_punct(tokens.get(i), ':') && is_punct(tokens.get(i + 1), ':') {
+            i += 2;
+        } else {
+            break;
+        }
+    }
+
+    if !is_punct(tokens.get(i), '!') {
+        return None;
+    }
+
+    match tokens.get(i + 1) {
+        Some(TokenTree::Group(group)) if group.delimiter() != Delimiter::None => {}
+        _ => return None,
+    }
+
+    match tokens.get(i + 2) {
+        None => Some(i + 2),
+        Some(token) if is_punct(Some(token), ',') => Some(i + 2),
+        Some(_) => None,
+    }
+}
+
+/// Skips attributes (`#[...]`) at the start of `tokens`.
+fn strip_attrs(mut tokens: &[TokenTree]) -> &[TokenTree] {
+    while let [TokenTree::Punct(pound), TokenTree::Group(group), rest @ ..] = tokens {
+        if pound.as_char() != '#' || group.delimiter() != Delimiter::Bracket {
+            break;
+        }
+
+        tokens = rest;
+    }
+
+    tokens
+}
+
+/// Skips the label (`"label":`) at the start of `tokens`.
+fn strip_label(tokens: &[TokenTree]) -> &[TokenTree] {
+    match tokens {
+        [label, TokenTree::Punct(colon), rest @ ..]
+            if !matches!(label, TokenTree::Punct(_))
+                && colon.as_char() == ':'
+                && colon.spacing() == Spacing::Alone =>
+        {
+            rest
+        }
+        _ => tokens,
+    }
+}
+
+fn skip_comma(tokens: &[TokenTree]) -> &[TokenTree] {
+    match tokens.first() {
+        Some(token) if is_punct(Some(token), ',') => &tokens[1..],
+        _ => tokens,
+    }
+}
+
+/// Returns the index of the first `=>`.
+fn find_arrow(tokens: &[TokenTree]) -> Option<usize> {
+    (0..tokens.len()).find(|&i| is_arrow(tokens, i))
+}
+
+/// Returns the index of the first `;`, if it's before the first `=>`.
+fn find_semi(tokens: &[TokenTree]) -> Option<usize> {
+    let end = find_arrow(tokens).unwrap_or(tokens.len());
+    tokens[..end]
+        .iter()
+        .position(|token| is_punct(Some(token), ';'))
+}
+
+/// Returns the index of the first `,` which is not inside a turbofish (`::<A, B>`) or the
+/// parameters of a closure (`|a, b|`).
+fn find_comma(tokens: &[TokenTree]) -> Option<usize> {
+    find_top_level(tokens, |tokens, i| is_punct(tokens.get(i), ','))
+}
+
+/// Returns the first index for which `f` returns `true`, skipping tokens inside a turbofish and
+/// the parameters of closures.
+fn find_top_level(tokens: &[TokenTree], f: impl Fn(&[TokenTree], usize) -> bool) -> Option<usize> {
+    let mut depth = 0_usize;
+    let mut in_params = false;
+    for i in 0..tokens.len() {
+        if depth == 0 && !in_params && f(tokens, i) {
+            return Some(i);
+        }
+
+        let punct = match &tokens[i] {
+            TokenTree::Punct(punct) => punct.as_char(),
+            _ => continue,
+        };
+
+        match punct {
+            '|' if in_params => in_params = false,
+            // `|` starts the parameters of a closure where an expression starts, otherwise it's
+            // a binary operator (`a | b`). `||` is handled as empty parameters.
+            '|' if depth == 0 && starts_expr(tokens, i) => in_params = true,
+            '<' if depth > 0 || (i >= 2 && is_punct(tokens.get(i - 1), ':')) => depth += 1,
+            // `->` is not a closing bracket
+            '>' if depth > 0 && !is_joint(&tokens[i - 1], '-') => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Returns `true` if an expression can start at `tokens[i]`, i.e. it's the first token, follows
+/// `move` or an operator.
+fn starts_expr(tokens: &[TokenTree], i: usize) -> bool {
+    match i.checked_sub(1).map(|prev| &tokens[prev]) {
+        None => true,
+        Some(TokenTree::Ident(ident)) => ident.to_string() == "move",
+        Some(TokenTree::Punct(punct)) => punct.as_char() != '?',
+        Some(_) => false,
+    }
+}
+
+fn is_arrow(tokens: &[TokenTree], i: usize) -> bool {
+    // `==>` or `<=>` are not arrows
+    let after_punct =
+        i > 0 && matches!(&tokens[i - 1], TokenTree::Punct(p) if p.spacing() == Spacing::Joint);
+
+    is_joint(&tokens[i], '=') && is_punct(tokens.get(i + 1), '>') && !after_punct
+}
+
+fn is_joint(token: &TokenTree, ch: char) -> bool {
+    matches!(token, TokenTree::Punct(punct) if punct.as_char() == ch && punct.spacing() == Spacing::Joint)
+}
+
+fn is_punct(token: Option<&TokenTree>, ch: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == ch)
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    matches!(token, Some(TokenTree::Ident(ident)) if ident.to_string() == name)
+}
@@ -0,0 +1,195 @@
+//! `#[when_fn]`: functions whose bodies are arms of `when!`, with the default inferred from the
+//! return type.
+
+use std::str::FromStr;
+
+use proc_macro::{Delimiter, Group, Spacing, Span, TokenStream, TokenTree};
+
+use crate::parse::Error;
+
+/// What the function returns if none of the arms fired.
+enum Fallback {
+    /// `None`, the branches are wrapped in `Some`
+    None,
+    /// `Err(From::from(NoMatch))`, the branches are wrapped in `Ok`
+    Err,
+    /// `Default::default()`
+    Default,
+    /// Nothing, the function doesn't have a return type
+    Unit,
+}
+
+/// Replaces the `when! { ... }` body of the function `item` with the expansion of the arms, with the
+/// default depending on the return type.
+pub(crate) fn expand(attr: TokenStream, item: TokenStream) -> Result<TokenStream, Error> {
+    if let Some(token) = attr.into_iter().next() {
+        return Err(Error::at(
+            token.span(),
+            "`#[when_fn]` doesn't accept arguments",
+        ));
+    }
+
+    let mut tokens: Vec<TokenTree> = item.into_iter().collect();
+    let body = match tokens.pop() {
+        Some(TokenTree::Group(body)) if body.delimiter() == Delimiter::Brace => body,
+        _ => {
+            return Err(Error::at(
+                Span::call_site(),
+                "`#[when_fn]` can only be applied to functions",
+            ))
+        }
+    };
+
+    let arms = arms(&body)?;
+    let fallback = fallback(signature(&tokens)?);
+
+    // The crate is named `kiam` in the crate using the attribute, since proc-macros can't use
+    // `$crate`
+    let call = match fallback {
+        Fallback::None => "::kiam::when_opt! { ARMS }",
+        Fallback::Err => {
+            "::kiam::__when_wrap!(
+                [::core::result::Result::Ok]
+                [::core::result::Result::Err(::core::convert::From::from(::kiam::NoMatch))]
+                [] []
+                ARMS
+            )"
+        }
+        Fallback::Default => {
+            "::kiam::__when_wrap!([] [::core::default::Default::default()] [] [] ARMS)"
+        }
+        Fallback::Unit => "::kiam::when! { ARMS }",
+    };
+    let call = TokenStream::from_str(call)
+        .unwrap()
+        .into_iter()
+        .flat_map(|token| replace(token, &arms))
+        .collect();
+
+    let mut output: TokenStream = tokens.into_iter().collect();
+    let mut new = Group::new(Delimiter::Brace, call);
+    new.set_span(body.span());
+    output.extend(Some(TokenTree::Group(new)));
+    Ok(output)
+}
+
+/// Returns the arms in `{ when! { <arms> } }` (the path to `when!` doesn't matter).
+fn arms(body: &Group) -> Result<TokenStream, Error> {
+    let tokens: Vec<TokenTree> = body.stream().into_iter().collect();
+    let message = "the body of a `#[when_fn]` function must be a `when! { ... }` invocation";
+
+    match &*tokens {
+        [path @ .., TokenTree::Ident(name), TokenTree::Punct(bang), TokenTree::Group(arms)]
+            if name.to_string() == "when"
+                && bang.as_char() == '!'
+                && path.iter().all(|token| match token {
+                    TokenTree::Ident(_) => true,
+                    TokenTree::Punct(punct) => punct.as_char() == ':',
+                    _ => false,
+                }) =>
+        {
+            Ok(arms.stream())
+        }
+        [] => Err(Error::at(body.span(), message)),
+        _ => Err(Error::spanning(&tokens, message)),
+    }
+}
+
+/// Returns the return type of the function with the signature `tokens`, if any.
+fn signature(tokens: &[TokenTree]) -> Result<Option<&[TokenTree]>, Error> {
+    let message = "`#[when_fn]` can only be applied to functions";
+    let name = tokens
+        .iter()
+        .position(|token| matches!(token, TokenTree::Ident(ident) if ident.to_string() == "fn"))
+        .map(|fn_| fn_ + 1)
+        .filter(|&name| name < tokens.len())
+        .ok_or_else(|| Error::at(Span::call_site(), message))?;
+
+    // Skip the generic parameters, which can contain parenthesis (`F: Fn(u8) -> u8`)
+    let mut i = name + 1;
+    let mut depth = 0_usize;
+    loop {
+        match tokens.get(i) {
+            Some(TokenTree::Punct(_)) if is_arrow(tokens, i) => i += 1,
+            Some(TokenTree::Punct(punct)) if punct.as_char() == '<' => depth += 1,
+            Some(TokenTree::Punct(punct)) if punct.as_char() == '>' => {
+                depth = depth.saturating_sub(1)
+            }
+            Some(TokenTree::Group(group))
+                if depth == 0 && group.delimiter() == Delimiter::Parenthesis =>
+            {
+                break
+            }
+            Some(_) => {}
+            None => return Err(Error::spanning(tokens, message)),
+        }
+        i += 1;
+    }
+
+    // `(<parameters>) -> <return type> where ...`
+    if !is_arrow(tokens, i + 1) {
+        return Ok(None);
+    }
+    let ret = &tokens[i + 3..];
+    let end = ret
+        .iter()
+        .position(|token| matches!(token, TokenTree::Ident(ident) if ident.to_string() == "where"))
+        .unwrap_or(ret.len());
+    Ok(Some(&ret[..end]))
+}
+
+/// Infers the default from the path of the return type, `Option`, `std::option::Option` and so on
+/// are all treated as `Option`.
+fn fallback(ret: Option<&[TokenTree]>) -> Fallback {
+    let ret = match ret {
+        Some(ret) => ret,
+        None => return Fallback::Unit,
+    };
+
+    let generics = ret
+        .iter()
+        .position(|token| matches!(token, TokenTree::Punct(punct) if punct.as_char() == '<'));
+    let path = &ret[..generics.unwrap_or(ret.len())];
+    let is_path = path.iter().all(|token| match token {
+        TokenTree::Ident(_) => true,
+        TokenTree::Punct(punct) => punct.as_char() == ':',
+        _ => false,
+    });
+
+    match path.last() {
+        Some(TokenTree::Ident(name)) if is_path && generics.is_some() => match &*name.to_string() {
+            "Option" => Fallback::None,
+            "Result" => Fallback::Err,
+            _ => Fallback::Default,
+        },
+        _ => Fallback::Default,
+    }
+}
+
+/// Returns `true` if `tokens[i..]` starts with `->`.
+fn is_arrow(tokens: &[TokenTree], i: usize) -> bool {
+    match (tokens.get(i), tokens.get(i + 1)) {
+        (Some(TokenTree::Punct(a)), Some(TokenTree::Punct(b))) => {
+            a.as_char() == '-' && a.spacing() == Spacing::Joint && b.as_char() == '>'
+        }
+        _ => false,
+    }
+}
+
+/// Replaces `ARMS` in `token` with `arms`.
+fn replace(token: TokenTree, arms: &TokenStream) -> TokenStream {
+    match &token {
+        TokenTree::Ident(ident) if ident.to_string() == "ARMS" => arms.clone(),
+        TokenTree::Group(old) => {
+            let stream = old
+                .stream()
+                .into_iter()
+                .flat_map(|token| replace(token, arms))
+                .collect();
+            let mut new = Group::new(old.delimiter(), stream);
+            new.set_span(old.span());
+            TokenTree::Group(new).into()
+        }
+        _ => token.into(),
+    }
+}
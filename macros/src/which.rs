@@ -0,0 +1,146 @@
+//! `when_which!`: `when!` which also evaluates to the arm which fired.
+
+use std::str::FromStr;
+
+use proc_macro::{Delimiter, Group, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+use crate::parse::{Arm, Error, When};
+
+/// Expands to `$crate::when!` with the branches replaced by `(<branch>, <Enum>::<variant>)`, where
+/// `<Enum>` is `enum`, the path to an enum defined by the caller, if any.
+///
+/// Otherwise the block defines `enum Which` with a variant per arm. To not shadow a type named
+/// `Which` in the arms, the branches are replaced by `(<branch>, <index>)` and the index is converted
+/// to `Which` after `when!`.
+pub(crate) fn expand(
+    krate: TokenStream,
+    enum_: Option<&[TokenTree]>,
+    when: &When<'_>,
+) -> Result<TokenStream, Error> {
+    let mut variants = Vec::new();
+    let mut arms = TokenStream::new();
+
+    for (index, arm) in when.arms.iter().enumerate() {
+        let (attrs, label, condition, branch) = match arm {
+            Arm::Line {
+                attrs,
+                label,
+                condition,
+                branch,
+                ..
+            } => (*attrs, *label, *condition, *branch),
+            Arm::Macro(tokens) => {
+                return Err(Error::spanning(
+                    tokens,
+                    "arms generated by macros are not supported by `when_which!`",
+                ))
+            }
+        };
+
+        let variant = match label {
+            Some(TokenTree::Ident(ident)) => ident.to_string(),
+            _ if arm.is_default() => "Default".to_owned(),
+            _ => format!("Arm{}", index),
+        };
+        let name = match label {
+            Some(TokenTree::Literal(literal)) => literal.to_string(),
+            Some(TokenTree::Ident(ident)) => Literal::string(&ident.to_string()).to_string(),
+            _ => {
+                let condition: TokenStream = condition.iter().cloned().collect();
+                Literal::string(&condition.to_string()).to_string()
+            }
+        };
+
+        // `<attributes> <label>: <condition> => (<branch>, <Enum>::<variant> or <index>),`
+        let head = &arm.tokens()[..attrs.len() + label.map_or(0, |_| 2) + condition.len()];
+        let mut value: TokenStream = branch.iter().cloned().collect();
+        value.extend(Some(TokenTree::Punct(Punct::new(',', Spacing::Alone))));
+        match enum_ {
+            Some(enum_) => {
+                value.extend(enum_.iter().cloned());
+                value.extend(TokenStream::from_str(&format!("::{}", variant)).unwrap());
+            }
+            None => value.extend(Some(TokenTree::Literal(Literal::usize_suffixed(index)))),
+        }
+
+        arms.extend(head.iter().cloned());
+        arms.extend(vec![
+            TokenTree::Punct(Punct::new('=', Spacing::Joint)),
+            TokenTree::Punct(Punct::new('>', Spacing::Alone)),
+            TokenTree::Group(Group::new(Delimiter::Parenthesis, value)),
+            TokenTree::Punct(Punct::new(',', Spacing::Alone)),
+        ]);
+
+        variants.push((variant, name));
+    }
+
+    let message = "`when_which!` requires a default arm (`_ =>` or `else =>`)";
+    match when.arms.last() {
+        Some(arm) if arm.is_default() => {}
+        Some(arm) => return Err(Error::spanning(arm.tokens(), message)),
+        None => return Err(Error::at(Span::call_site(), message)),
+    }
+
+    // `$crate::when! { <preamble> <subject>; <arms> }`
+    let mut input: TokenStream = when.preamble.iter().cloned().collect();
+    if let Some(subject) = when.subject {
+        input.extend(subject.iter().cloned());
+        input.extend(Some(TokenTree::Punct(Punct::new(';', Spacing::Alone))));
+    }
+    input.extend(arms);
+
+    let mut call = krate;
+    call.extend(TokenStream::from_str("::when!").unwrap());
+    call.extend(Some(TokenTree::Group(Group::new(Delimiter::Brace, input))));
+
+    if enum_.is_some() {
+        return Ok(call);
+    }
+
+    // The last arm is the default one, so it is used for the indices which can't be produced
+    let (last, _) = variants.last().unwrap();
+    let mut output = TokenStream::from_str("let (value, index) =").unwrap();
+    output.extend(call);
+    output.extend(
+        TokenStream::from_str(&format!(
+            "; {{
+            #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+            #[allow(dead_code)]
+            enum Which {{ {variants} }}
+
+            #[allow(dead_code)]
+            impl Which {{
+                /// The label of the arm, or its condition, if there is no label.
+                fn name(self) -> &'static str {{
+                    match self {{ {names} }}
+                }}
+
+                /// The index of the arm.
+                fn index(self) -> usize {{
+                    self as usize
+                }}
+            }}
+
+            let which = match index {{ {indices} _ => Which::{last} }};
+            (value, which)
+        }}",
+            variants = variants
+                .iter()
+                .map(|(variant, _)| format!("{},", variant))
+                .collect::<String>(),
+            names = variants
+                .iter()
+                .map(|(variant, name)| format!("Which::{} => {},", variant, name))
+                .collect::<String>(),
+            indices = variants[..variants.len() - 1]
+                .iter()
+                .enumerate()
+                .map(|(index, (variant, _))| format!("{} => Which::{},", index, variant))
+                .collect::<String>(),
+            last = last,
+        ))
+        .unwrap(),
+    );
+
+    Ok(TokenTree::Group(Group::new(Delimiter::Brace, output)).into())
+}
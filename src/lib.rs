@@ -9,6 +9,58 @@
 #![no_std]
 #![forbid(unsafe_code)]
 #![deny(missing_docs, broken_intra_doc_links)]
+#![cfg_attr(feature = "nightly", feature(likely_unlikely))]
+#![cfg_attr(all(test, feature = "nightly"), feature(yeet_expr))]
+
+// Some tests need `std`, e.g. the tests of `dbg_when!`
+#[cfg(test)]
+extern crate std;
+
+// Used by the macros which need `alloc`, e.g. `collect_when!`
+#[cfg(feature = "alloc")]
+#[doc(hidden)]
+pub extern crate alloc as __alloc;
+
+// Boxes the branches of `when_boxed!`, a function (unlike `Box::pin(...) as ...`) lets the compiler
+// infer the output and the lifetime of the future
+#[cfg(feature = "alloc")]
+#[doc(hidden)]
+pub fn __box_future<'a, F: core::future::Future + 'a>(
+    future: F,
+) -> rules::BoxFuture<'a, F::Output> {
+    __alloc::boxed::Box::pin(future)
+}
+
+// Optional dependencies used by the expansions of the macros, so that the crates using the macros
+// don't need to depend on them directly
+#[doc(hidden)]
+pub mod __private {
+    #[cfg(feature = "defmt")]
+    pub use defmt;
+    #[cfg(feature = "either")]
+    pub use either;
+    #[cfg(feature = "futures")]
+    pub use futures;
+    #[cfg(feature = "log")]
+    pub use log;
+    #[cfg(feature = "rayon")]
+    pub use rayon;
+    #[cfg(feature = "tracing")]
+    pub use tracing;
+
+    // Picks the arm of `rand_when!` without a closure
+    #[cfg(feature = "rand")]
+    pub fn pick_uniform(n: usize) -> usize {
+        rand::Rng::gen_range(&mut rand::thread_rng(), 0..n)
+    }
+
+    // The branches of `when_enum!` are wrapped into nested `Either`s before they are converted into
+    // the generated enum
+    pub enum Either<L, R> {
+        Left(L),
+        Right(R),
+    }
+}
 
 /// Better syntax for `if`/`else if`/`else` similar to `match` syntax
 ///
@@ -35,6 +87,64 @@
 /// assert_eq!(x, 1);
 /// ```
 ///
+/// Both the default branch and the other branches are optional, so `when! {}` and `when! { _ => x }`
+/// are also valid (this is mostly useful for macro-generated code):
+///
+/// ```rust
+/// kiam::when! {}
+///
+/// let x = kiam::when! {
+///     _ => 1,
+/// };
+///
+/// assert_eq!(x, 1);
+/// ```
+///
+/// Arms can also be generated by other macros. A macro invocation marked with `..` is expanded with
+/// the rest of the arms, so the macro can emit its own arms before them:
+///
+/// ```rust
+/// // Receives the arguments in parenthesis and the following arms
+/// macro_rules! sign_arms {
+///     (($x:expr) $($rest:tt)*) => {
+///         kiam::when! {
+///             $x > 0 => "positive",
+///             $x < 0 => "negative",
+///             $($rest)*
+///         }
+///     };
+/// }
+///
+/// let x = 0;
+///
+/// let sign = kiam::when! {
+///     ..sign_arms!(x),
+///     _ => "zero",
+/// };
+///
+/// assert_eq!(sign, "zero");
+/// ```
+///
+/// The default branch must be the last one, since it makes all the following branches unreachable:
+///
+/// ```compile_fail
+/// let x = kiam::when! {
+///     _ => 1,
+///     false => 0, // error: default arm must be last; following arms are unreachable
+/// };
+/// ```
+///
+/// Kotlin-style `else` can be used instead of `_` as well:
+///
+/// ```rust
+/// let x = kiam::when! {
+///     false => 0,
+///     else => 1,
+/// };
+///
+/// assert_eq!(x, 1);
+/// ```
+///
 /// You can also use `let <pat> =` to match a pattern, but in difference with `match` you'll have to provide an expression for every pattern:
 ///
 /// ```rust
@@ -52,6 +162,174 @@
 /// assert_eq!(x, 18);
 /// ```
 ///
+/// Patterns can also contain top-level alternatives (`let A | B = <expr>`).
+///
+/// Similarly to `match`, pattern branches can be additionally guarded by `if <cond>`:
+///
+/// ```rust
+/// let a = Some(2);
+///
+/// let x = kiam::when! {
+///     let Some(x) = a if x > 3 => x,
+///     let Some(x) = a => x * 10,
+///     _ => 0,
+/// };
+///
+/// assert_eq!(x, 20);
+/// ```
+///
+/// `not let <pat> = <expr>` is the opposite of `let <pat> = <expr>`: it fires if the pattern does
+/// *not* match (and so it can't bind anything):
+///
+/// ```rust
+/// let cached: Option<u32> = None;
+///
+/// let x = kiam::when! {
+///     not let Some(_) = cached => "recompute",
+///     _ => "use cache",
+/// };
+///
+/// assert_eq!(x, "recompute");
+/// ```
+///
+/// `<expr> matches <pat>` can be used as a shorthand for `matches!(<expr>, <pat>)` (everything
+/// before `matches` is considered to be the expression):
+///
+/// ```rust
+/// let c = 'x';
+///
+/// let x = kiam::when! {
+///     c matches '0'..='9' => "digit",
+///     c matches 'a'..='z' | 'A'..='Z' => "letter",
+///     _ => "other",
+/// };
+///
+/// assert_eq!(x, "letter");
+/// ```
+///
+/// Several conditions can be chained in a single arm, separated by `,`. The arm only fires if all
+/// of the patterns match and all of the boolean conditions are `true`, bindings of a pattern can be
+/// used in the following conditions:
+///
+/// ```rust
+/// let input = Some("17");
+///
+/// let x = kiam::when! {
+///     let Some(s) = input, !s.is_empty(), let Ok(n) = s.parse::<u32>() => n,
+///     _ => 0,
+/// };
+///
+/// assert_eq!(x, 17);
+/// ```
+///
+/// Alternatives can be combined in a single arm with `or`, the arm fires if any of them holds. `,`
+/// binds tighter than `or`, so `a, b or c` means "`a` and `b`, or `c`". Bindings can't be used in
+/// the branch, but they can be used in the conditions of the same alternative:
+///
+/// ```rust
+/// let input = "+7";
+///
+/// let x = kiam::when! {
+///     input.is_empty() or input == "0" => 0,
+///     let Ok(n) = input.parse::<i32>(), n > 0 or input matches "max" | "inf" => 1,
+///     _ => -1,
+/// };
+///
+/// assert_eq!(x, 1);
+/// ```
+///
+/// Arms can be conditionally compiled with `#[cfg(...)]`:
+///
+/// ```rust
+/// let os = kiam::when! {
+///     #[cfg(unix)]
+///     true => "unix",
+///     #[cfg(windows)]
+///     true => "windows",
+///     _ => "unknown",
+/// };
+/// # let _ = os;
+/// ```
+///
+/// Other attributes, like `#[allow(...)]`, are applied to the code generated for the arm (both the
+/// conditions and the branch), so lints can be controlled for a single arm:
+///
+/// ```rust
+/// # let opt = Some(1);
+/// kiam::when! {
+///     #[allow(unused_variables)]
+///     let Some(x) = opt => (),
+///     _ => (),
+/// }
+/// ```
+///
+/// Arms can be labelled with a string literal followed by `:`. Labels don't change what `when!`
+/// does, but they are shown instead of the condition by the macros which report the arm which
+/// fired, like [`dbg_when!`], [`exclusive_when!`], [`when_label!`] and `when_which!`:
+///
+/// ```rust
+/// # let (requests, banned) = (120, false);
+/// let response = kiam::when! {
+///     "banned": banned => 403,
+///     "rate-limited": requests > 100 => 429,
+///     _ => 200,
+/// };
+///
+/// assert_eq!(response, 429);
+/// ```
+///
+/// `#[likely]` and `#[unlikely]` can be used as hints for branch prediction, when one arm fires much
+/// more (or less) often than the others:
+///
+/// ```rust
+/// # let (opcode, args) = (0, [1, 2]);
+/// let result = kiam::when! {
+///     #[unlikely]
+///     args.is_empty() => None,
+///     #[likely]
+///     opcode == 0 => Some(args[0] + args[1]),
+///     _ => Some(0),
+/// };
+/// # let _ = result;
+/// ```
+///
+/// On stable the hints are expressed by calling a `#[cold]` function on the unlikely path, with the
+/// `nightly` feature `core::hint::likely` and `core::hint::unlikely` are used instead. The hints are
+/// ignored on arms with several conditions and on arms with other attributes.
+///
+/// Like in `match`, the comma after a branch can be omitted if the branch is a block:
+///
+/// ```rust
+/// let mut x = 0;
+///
+/// kiam::when! {
+///     false => {
+///         x = 1;
+///     }
+///     true => {
+///         x = 2;
+///     }
+///     _ => {}
+/// }
+///
+/// assert_eq!(x, 2);
+/// ```
+///
+/// The arms can be preceded by `let` statements, which are evaluated once, before any condition.
+/// The bindings are visible in all the conditions and branches, but not outside of `when!`:
+///
+/// ```rust
+/// let x = kiam::when! {
+///     let s = "17".trim();
+///     let n: Result<u32, _> = s.parse();
+///     s.is_empty() => 0,
+///     let Ok(n) = n => n,
+///     _ => 1,
+/// };
+///
+/// assert_eq!(x, 17);
+/// ```
+///
 /// Last notes:
 /// - You can also compare structure literals without brackets (you can't do this with `if`/`else if`/`else` chain)
 /// - You can mixup boolean-branches with pattern matching
@@ -92,108 +370,9020 @@
 /// }
 /// ```
 ///
-/// ## Grammar
+/// ## Subject form
 ///
-/// ```text
-/// grammar:
-///                   ╭───────────────>────────────────╮  ╭────>────╮
-///                   │                                │  │         │
-/// │├──╭── line ──╮──╯── "," ── "_" ── "=>" ── expr ──╰──╯── "," ──╰──┤│
-///     │          │
-///     ╰── "," ───╯
+/// Similarly to kotlin, `when!` can also accept a subject, separated from the arms by `;`. In this
+/// form every arm is a value which is compared with the subject using `==`:
 ///
-/// line:
-///     ╭─────────────>─────────────╮
-///     │                           │
-/// │├──╯── "let"/i ── pat ── "=" ──╰── expr ── "=>" ── expr ──┤│
+/// ```rust
+/// let status = 404;
+///
+/// let x = kiam::when! {
+///     status;
+///     200 => "ok",
+///     404 => "not found",
+///     _ => "unknown",
+/// };
+///
+/// assert_eq!(x, "not found");
 /// ```
-#[macro_export]
-macro_rules! when {
-    (
-        $(
-            $(let $pat:pat = )? $cond:expr => $branch:expr
-        ),+
-        $(, _ => $def_branch:expr)?
-        $(,)?
-    ) => {
-        $(
-            if $(let $pat = )? $cond {
-                $branch
-            } else
-        )+
-        {
-            $(
-                $def_branch
-            )?
-        }
-    };
-}
-
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        let r = when! {
-            false => 0,
-            true => 1,
-            true => 2,
-            _ => 42,
-        };
-
-        assert_eq!(r, 1);
-    }
-
-    #[test]
-    fn pattern() {
-        let r = when! {
-            let Some(x) = None => x,
-            let Some(y) = Some(12) => y + 1,
-            _ => 0,
-        };
-
-        assert_eq!(r, 13);
-    }
-
-    #[test]
-    fn mixed() {
-        let r = when! {
-            false => 0,
-            let Some(_) = None::<bool> => 0,
-            let Some(y) = Some(12) => y + 1,
-            true => 1,
-            _ => 0,
-        };
-
-        assert_eq!(r, 13);
-    }
-
-    #[test]
-    fn r#struct() {
-        #[derive(PartialEq)]
-        struct Struct {
-            x: i32,
-        }
-
-        // won't work with if/elseif/else
-        #[allow(clippy::eq_op)]
-        let r = when! {
-            Struct { x: 0 } == Struct { x: 1 } => 0,
-            Struct { x: 22 } == Struct { x: 22 } => 1,
-            _ => 2,
-        };
-
-        assert_eq!(r, 1);
-    }
-
-    #[test]
-    fn no_def() {
-        let mut x = 0;
-
-        when! {
-            false => x = 18,
-            true => x += 1,
-        }
-
-        assert_eq!(x, 1);
+///
+/// The subject is evaluated only once, before any of the arms. It can also be bound to a name with
+/// `<expr> as <name>`, which can then be used in the arms and branches (to use a cast as the subject,
+/// wrap it in parenthesis):
+///
+/// ```rust
+/// let x = kiam::when! {
+///     "hello".len() as len;
+///     0 => "empty".to_owned(),
+///     _ => format!("{} bytes", len),
+/// };
+///
+/// assert_eq!(x, "5 bytes");
+/// ```
+///
+/// An arm can compare the subject with several values, separated by `,`. Values which are a single
+/// token (e.g. a literal) or a path can also be separated by `|`, like in patterns:
+///
+/// ```rust
+/// let c = 'e';
+///
+/// let x = kiam::when! {
+///     c;
+///     'a' | 'e' | 'i' | 'o' | 'u' => "vowel",
+///     char::MAX, char::REPLACEMENT_CHARACTER => "special",
+///     _ => "other",
+/// };
+///
+/// assert_eq!(x, "vowel");
+/// ```
+///
+/// Arms can also check if the subject is contained in a range using `in`:
+///
+/// ```rust
+/// let x = 42;
+///
+/// let size = kiam::when! {
+///     x;
+///     0 => "zero",
+///     in 1..10 => "small",
+///     in 10..=100 => "medium",
+///     _ => "large",
+/// };
+///
+/// assert_eq!(size, "medium");
+/// ```
+///
+/// `in` (and its negated counterpart, `!in`) works with anything that has a `contains` method
+/// accepting a reference to the subject, e.g. slices or `HashSet`s:
+///
+/// ```rust
+/// use std::collections::HashSet;
+///
+/// let banned: HashSet<_> = ["root", "admin"].iter().copied().collect();
+/// let name = "guest";
+///
+/// let x = kiam::when! {
+///     name;
+///     in banned => "banned",
+///     !in ["guest", "user"] => "unknown",
+///     _ => "welcome",
+/// };
+///
+/// assert_eq!(x, "welcome");
+/// ```
+///
+/// `let <pat>` arms match the subject against a pattern, like `match` arms do (including bindings and
+/// `if` guards), and can be mixed with the other arms. The subject is matched by reference, so it's
+/// not moved and the bindings are references:
+///
+/// ```rust
+/// let x = Some(7);
+///
+/// let x = kiam::when! {
+///     x;
+///     None => "none",
+///     let Some(n) if *n < 0 => "negative",
+///     Some(0) => "zero",
+///     let Some(1..=9) => "digit",
+///     _ => "number",
+/// };
+///
+/// assert_eq!(x, "digit");
+/// ```
+///
+/// [`when_exhaustive!`] is a subject form which only accepts `let` arms and checks them for
+/// exhaustiveness, like `match`.
+///
+/// `_ if <cond>` is an arm with a guard, but without a pattern, so it fires whenever `<cond>` is
+/// `true`. This can be used to express a conditional fallback before the default arm:
+///
+/// ```rust
+/// let strict = false;
+///
+/// let x = kiam::when! {
+///     "maybe";
+///     "yes" => Some(true),
+///     "no" => Some(false),
+///     _ if strict => None,
+///     _ => Some(false),
+/// };
+///
+/// assert_eq!(x, Some(false));
+/// ```
+///
+/// If the subject is a [`dyn Any`] (behind a reference or a smart pointer, like `&dyn Any` or
+/// `Box<dyn Any>`), `is Type` arms can be used to check its type:
+///
+/// ```rust
+/// use std::any::Any;
+///
+/// let value: Box<dyn Any> = Box::new(String::from("hi"));
+///
+/// let x = kiam::when! {
+///     value;
+///     is i32 => "integer",
+///     is String => "string",
+///     _ => "something else",
+/// };
+///
+/// assert_eq!(x, "string");
+/// ```
+///
+/// To use the value as the concrete type, bind it to a name with `as`:
+///
+/// ```rust
+/// use std::any::Any;
+///
+/// let value: &dyn Any = &String::from("hello");
+///
+/// let len = kiam::when! {
+///     value;
+///     is &str as s => s.len(),
+///     is String as s => s.len(),
+///     _ => 0,
+/// };
+///
+/// assert_eq!(len, 5);
+/// ```
+///
+/// [`dyn Any`]: core::any::Any
+///
+/// ## Diagnostics
+///
+/// A missing `=>` (or `->` written instead of it) is reported with the arm which lacks it:
+///
+/// ```compile_fail
+/// let x = 1;
+///
+/// kiam::when! {
+///     x > 0 -> "positive", // error: expected `=>` after `x > 0`, found `->`
+///     _ => "not positive",
+/// };
+/// ```
+///
+/// `else if` and `else { ... }`, which are easy to paste from an `if` chain, are reported as well:
+///
+/// ```compile_fail
+/// let x = 1;
+///
+/// kiam::when! {
+///     x > 0 => "positive",
+///     else if x < 0 => "negative", // error: unexpected `else if`
+///     _ => "zero",
+/// };
+/// ```
+///
+/// By default `when!` is a declarative macro, so when an arm is malformed the error usually points
+/// at the whole invocation. With the `macros` feature enabled, the arms are first checked by a
+/// proc-macro, which points at the exact arm instead:
+///
+/// ```compile_fail
+/// let x = 1;
+///
+/// kiam::when! {
+///     x > 0 => "positive"
+///     x < 0 => "negative", // error: missing `,` after the branch
+///     _ => "zero",
+/// };
+/// ```
+///
+/// The declarative macro is still used to expand the arms, so the feature doesn't change which
+/// inputs are accepted.
+///
+/// The proc-macro also warns about arms which are accepted, but are most likely mistakes. For example,
+/// arms after an arm with the condition `true` can never fire:
+///
+/// ```rust
+/// let x = 1;
+///
+/// let sign = kiam::when! {
+///     x > 0 => "positive",
+///     true => "not positive",
+///     x < 0 => "negative", // warning: this arm is unreachable
+///     _ => "zero",         // warning: this arm is unreachable
+/// };
+/// ```
+///
+/// So are arms with the same condition as a previous arm, which are usually a copy-paste mistake
+/// (unless the condition has side effects):
+///
+/// ```rust
+/// let (x, y) = (1, 2);
+///
+/// let quadrant = kiam::when! {
+///     x > 0 && y > 0 => 1,
+///     x < 0 && y > 0 => 2,
+///     x > 0 && y > 0 => 4, // warning: a previous arm has the same condition
+///     _ => 0,
+/// };
+/// ```
+///
+/// Arms with a condition which is always `false`, like `false` or `0 > 1`, are reported too, since they
+/// are usually unfinished.
+///
+/// In the subject form, integer values and ranges are checked as well: an arm whose values are all
+/// handled by the previous arms is reported as unreachable and, if all the arms compare the subject
+/// with integers and there is no default arm, so are the values between the ranges:
+///
+/// ```rust
+/// let age = 30;
+///
+/// kiam::when! {
+///     age;
+///     in 0..13 => println!("child"),
+///     in 13..20 => println!("teenager"),
+///     in 20..65 => println!("adult"),
+///     in 30..40 => println!("thirties"), // warning: this arm is unreachable
+///     in 65.. => println!("senior"),
+/// }
+///
+/// kiam::when! {
+///     age;
+///     in 0..13 => println!("child"),
+///     in 14..20 => println!("teenager"), // warning: `13` is not handled by any arm
+///     in 20.. => println!("adult"),
+/// }
+/// ```
+///
+/// Proc-macros can't emit warnings on stable, so the warnings are reported as uses of deprecated
+/// constants and can be silenced with `#[allow(deprecated)]`.
+///
+/// ## Jump tables
+///
+/// Chains of comparisons of the same variable with integer or string literals can be lowered to a
+/// `match`, see [`match_when!`].
+///
+/// ## Decision graphs
+///
+/// With the `macros` feature, `when!` can also draw its arms: if the `KIAM_GRAPH_DIR` environment
+/// variable is set during the build, a Graphviz (`.dot`) and a Mermaid (`.mmd`) flowchart is written to
+/// that directory for every `when!`, named after its crate and location (like
+/// `my_app-src_main_rs-12-5.dot`):
+///
+/// ```sh
+/// KIAM_GRAPH_DIR=target/graphs cargo build
+/// dot -Tsvg target/graphs/my_app-src_main_rs-12-5.dot > routing.svg
+/// ```
+///
+/// Each condition is a node with a `true` edge to its branch and a `false` edge to the next condition
+/// (or to the default branch). Changing the variable doesn't cause a rebuild, so crates which were
+/// already built need to be rebuilt (e.g. with `cargo clean -p <crate>`) to get their graphs.
+///
+/// ## Grammar
+///
+/// ```text
+/// grammar:
+///                   ╭─────────────────>──────────────────╮  ╭────>────╮
+///                   │                                    │  │         │
+/// │├──╭── line ──╮──╯── "," ── default ── "=>" ── expr ──╰──╯── "," ──╰──┤│
+///     │          │
+///     ╰── "," ───╯
+///
+/// (every part is optional, an empty invocation is valid; a `line` can also be replaced by `..`
+/// followed by a macro invocation generating arms; both this and the subject grammar can be preceded by any number of
+/// `preamble`s)
+///
+/// preamble:
+///                     ╭────────>────────╮
+///                     │                 │
+/// │├── "let" ── pat ──╯── ":" ── type ──╰── "=" ── expr ── ";" ──┤│
+///
+/// (the type can only be specified if the pattern is a single identifier)
+///
+/// default:
+/// │├──╮─── "_" ───╭──┤│
+///     │           │
+///     ╰── "else" ─╯
+///
+/// line:
+///     ╭────────>────────╮                         ╭─────────>──────────╮
+///     │                 │                         │                    │
+/// │├──╯─╭─ attribute ─╮─╰──╭──── condition ────╮──╯── "if"/i ── expr ──╰── "=>" ── expr ──┤│
+///       │             │    │                   │
+///       ╰─────<───────╯    ╰─── "," / "or"/i ──╯
+///
+/// condition:
+///     ╭─────────────────────>─────────────────────╮
+///     │                                           │
+///     │     ╭────>────╮                           │
+///     │     │         │                           │
+/// │├──╯─────╯─ "not" ─╰── "let"/i ── pat ── "=" ──╰── expr ──╮─────────────────────────╭──┤│
+///                                                            │                         │
+///                                                            ╰── "matches"/i ── pat ───╯
+///
+/// (the `if` guard is only allowed after a `let` condition; `,` binds tighter than `or`; the
+/// condition, as well as the lines of the subject grammar, can be preceded by a label, `"label" ":"`)
+///
+/// subject grammar:
+///             ╭──────────>──────────╮                               ╭─────────────────>──────────────────╮  ╭────>────╮
+///             │                     │                               │                                    │  │         │
+/// │├── expr ──╯── "as"/i ── ident ──╰── ";" ──╭── subject_line ──╮──╯── "," ── default ── "=>" ── expr ──╰──╯── "," ──╰──┤│
+///                                             │                  │
+///                                             ├───── is_line ────┤
+///                                             │                  │
+///                                             ├─── pattern_line ─┤
+///                                             │                  │
+///                                             ├──── guard_line ──┤
+///                                             │                  │
+///                                             ╰────── "," ───────╯
+///
+/// subject_line:
+///     ╭──────>──────╮
+///     │             │
+/// │├──╯──── "in" ───╰── expr ── "=>" ── expr ──┤│
+///     │             │
+///     ╰─── "!in" ───╯
+///
+/// (without `in`/`!in`, several values can be separated by `,`, or by `|` if they are literals or
+/// paths)
+///
+/// pattern_line:
+///                       ╭─────────>──────────╮
+///                       │                    │
+/// │├── "let"/i ── pat ──╯── "if"/i ── expr ──╰── "=>" ── expr ──┤│
+///
+/// guard_line:
+/// │├── "_" ── "if"/i ── expr ── "=>" ── expr ──┤│
+///
+/// is_line:
+///                    ╭──────────>──────────╮
+///                    │                     │
+/// │├── "is"/i ── type ──╯── "as"/i ── ident ──╰── "=>" ── expr ──┤│
+/// ```
+#[macro_export]
+macro_rules! when {
+    ($($tokens:tt)*) => {
+        $crate::__when_check!([$crate] $($tokens)*)
+    };
+}
+
+// Without the `macros` feature the arms are not checked up front, malformed arms are reported by
+// `__when!` itself
+#[cfg(not(feature = "macros"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __when_check {
+    ([$krate:tt] $($tokens:tt)*) => {
+        $crate::__when!($($tokens)*)
+    };
+}
+
+// `kiam_macros::when!` checks the arms, reporting errors with precise spans, and expands to
+// `$crate::__when!` (the crate path is passed as the first token, since proc-macros can't use
+// `$crate`)
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub use kiam_macros::when as __when_check;
+
+/// Same as [`when!`], but with the `macros` feature, chains of comparisons of the same variable (or
+/// field) with integer literals, like an opcode dispatcher, are lowered to a `match`, so that LLVM
+/// can build a jump table instead of comparing the value with every literal in turn:
+///
+/// ```rust
+/// let opcode = 2_u8;
+///
+/// let name = kiam::match_when! {
+///     opcode == 0x00 => "nop",
+///     opcode == 0x01 => "push",
+///     opcode == 0x02 => "pop",
+///     _ => "unknown",
+/// };
+///
+/// assert_eq!(name, "pop");
+/// ```
+///
+/// Chains of comparisons with string literals, like in a tokenizer, are lowered to a `match` on the
+/// length of the string, so that the string is only compared with the literals of the same length:
+///
+/// ```rust
+/// let keyword = |token: &str| kiam::match_when! {
+///     token == "fn" => 0,
+///     token == "let" => 1,
+///     token == "if" => 2,
+///     _ => -1,
+/// };
+///
+/// assert_eq!(keyword("let"), 1);
+/// ```
+///
+/// The strings are converted with `AsRef<str>`, so `&str`, `String` and such are supported. Literals
+/// with escapes are not lowered.
+///
+/// Since the literals become patterns, the variable must be of an integer type, or implement
+/// `AsRef<str>` for string literals. Types which only implement `PartialEq` for the literals are
+/// accepted by `when!`, but not by `match_when!` with the `macros` feature. Without the feature,
+/// this is the same as `when!`.
+#[macro_export]
+macro_rules! match_when {
+    ($($tokens:tt)*) => {
+        $crate::__match_when!([$crate] $($tokens)*)
+    };
+}
+
+#[cfg(not(feature = "macros"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __match_when {
+    ([$krate:tt] $($tokens:tt)*) => {
+        $crate::__when!($($tokens)*)
+    };
+}
+
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub use kiam_macros::match_when as __match_when;
+
+// The implementation of `when!`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __when {
+    // An attribute can't start the subject form, so there is no need to look for `;`
+    (#[$($attr:tt)*] $($rest:tt)*) => {
+        $crate::__when!(@arms #[$($attr)*] $($rest)*)
+    };
+    (let $name:ident: $ty:ty = $init:expr; $($rest:tt)*) => {{
+        let $name: $ty = $init;
+        $crate::__when!($($rest)*)
+    }};
+    (let $pat:pat = $init:expr; $($rest:tt)*) => {{
+        let $pat = $init;
+        $crate::__when!($($rest)*)
+    }};
+    (@subject $subject:ident [$($arms:tt)*] $label:tt : $($rest:tt)*) => {
+        $crate::__when!(@subject $subject [$($arms)*] $($rest)*)
+    };
+    (@subject $subject:ident [$($arms:tt)*] in $range:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { ($range).contains($subject) } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] in $range:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] in $range => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] !in $collection:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { !($collection).contains($subject) } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] !in $collection:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] !in $collection => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] is $ty:ty as $name:ident => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { let ::core::option::Option::Some($name) = $subject.downcast_ref::<$ty>() } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] is $ty:ty as $name:ident => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] is $ty as $name => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] is $ty:ty => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { $subject.is::<$ty>() } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] is $ty:ty => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] is $ty => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] let $($pat:pat)|+ if $guard:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        // Guards can't be expressed with `if`, so the rest of the arms are nested in a `match`
+        $crate::__when!(
+            @subject_emit [$($arms)*] {
+                match $subject {
+                    $($pat)|+ if $guard => $branch,
+                    _ => $crate::__when!(@subject $subject [] $($($rest)*)?),
+                }
+            }
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] let $($pat:pat)|+ if $guard:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] let $($pat)|+ if $guard => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] let $($pat:pat)|+ => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { let $($pat)|+ = $subject } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] let $($pat:pat)|+ => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] let $($pat)|+ => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] _ if $guard:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(@subject $subject [$($arms)* { $guard } => { $branch }] $($($rest)*)?)
+    };
+    (@subject $subject:ident [$($arms:tt)*] _ if $guard:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] _ if $guard => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] else if $($rest:tt)*) => {
+        ::core::compile_error!("unexpected `else if`: arms of `when!` are already checked in order, remove `else`")
+    };
+    (@subject $subject:ident [$($arms:tt)*] else { $($branch:tt)* } $($rest:tt)*) => {
+        ::core::compile_error!("unexpected `else { ... }`: use `_ => { ... }` for the default arm")
+    };
+    (@subject $subject:ident [$($arms:tt)*] _ => $def_branch:expr $(,)?) => {
+        $crate::__when!(@subject_emit [$($arms)*] { $def_branch })
+    };
+    (@subject $subject:ident [$($arms:tt)*] else => $def_branch:expr $(,)?) => {
+        $crate::__when!(@subject_emit [$($arms)*] { $def_branch })
+    };
+    (@subject $subject:ident [$($arms:tt)*] _ => $def_branch:expr, $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    (@subject $subject:ident [$($arms:tt)*] _ => { $($def_branch:tt)* } $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    (@subject $subject:ident [$($arms:tt)*] else => $def_branch:expr, $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    (@subject $subject:ident [$($arms:tt)*] else => { $($def_branch:tt)* } $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    // `expr` can't be followed by `|` (and `a | b` would be parsed as a single expression), so
+    // alternatives are only supported for single token values (like literals) and simple paths
+    (@subject $subject:ident [$($arms:tt)*] $($value:tt)|+ => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { $(*$subject == $value)||+ } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] $($value:tt)|+ => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] $($value)|+ => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] $($($value:ident)::+)|+ => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { $(*$subject == $($value)::+)||+ } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] $($($value:ident)::+)|+ => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] $($($value)::+)|+ => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*] $($value:expr),+ => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when!(
+            @subject $subject [$($arms)* { $(*$subject == $value)||+ } => { $branch }] $($($rest)*)?
+        )
+    };
+    (@subject $subject:ident [$($arms:tt)*] $($value:expr),+ => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@subject $subject [$($arms)*] $($value),+ => { $($branch)* }, $($rest)+)
+    };
+    (@subject $subject:ident [$($arms:tt)*]) => {
+        $crate::__when!(@subject_emit [$($arms)*] {})
+    };
+    (@subject $subject:ident [$($arms:tt)*] $($rest:tt)+) => {
+        $crate::__when!(@missing_arrow [] $($rest)+)
+    };
+    (@subject_emit [$({ $($cond:tt)* } => $branch:tt)*] $def_branch:tt) => {
+        $(
+            if $($cond)* $branch else
+        )*
+        $def_branch
+    };
+    (@arms) => {
+        ()
+    };
+    // Costs are only used by `reorder_when!`
+    (@arms #[cost(cheap)] $($rest:tt)*) => {
+        $crate::__when!(@arms $($rest)*)
+    };
+    (@arms #[cost(expensive)] $($rest:tt)*) => {
+        $crate::__when!(@arms $($rest)*)
+    };
+    // Branch prediction hints, see `__likely`
+    (@arms #[likely] $label:tt : $($rest:tt)*) => {
+        $crate::__when!(@arms #[likely] $($rest)*)
+    };
+    (@arms #[unlikely] $label:tt : $($rest:tt)*) => {
+        $crate::__when!(@arms #[unlikely] $($rest)*)
+    };
+    (@arms #[likely] let $($pat:pat)|+ = $cond:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        if let $($pat)|+ = $cond {
+            $branch
+        } else {
+            $crate::__cold();
+            $crate::__when!(@arms $($($rest)*)?)
+        }
+    };
+    (@arms #[unlikely] let $($pat:pat)|+ = $cond:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        if let $($pat)|+ = $cond {
+            $crate::__cold();
+            $branch
+        } else {
+            $crate::__when!(@arms $($($rest)*)?)
+        }
+    };
+    (@arms #[likely] $cond:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        if $crate::__likely($cond) {
+            $branch
+        } else {
+            $crate::__when!(@arms $($($rest)*)?)
+        }
+    };
+    (@arms #[unlikely] $cond:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        if $crate::__unlikely($cond) {
+            $branch
+        } else {
+            $crate::__when!(@arms $($($rest)*)?)
+        }
+    };
+    (@arms #[likely] let $($pat:pat)|+ = $cond:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@arms #[likely] let $($pat)|+ = $cond => { $($branch)* }, $($rest)+)
+    };
+    (@arms #[likely] $cond:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@arms #[likely] $cond => { $($branch)* }, $($rest)+)
+    };
+    (@arms #[unlikely] let $($pat:pat)|+ = $cond:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@arms #[unlikely] let $($pat)|+ = $cond => { $($branch)* }, $($rest)+)
+    };
+    (@arms #[unlikely] $cond:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@arms #[unlikely] $cond => { $($branch)* }, $($rest)+)
+    };
+    // Hints are only supported on arms with a single condition
+    (@arms #[likely] $($rest:tt)+) => {
+        $crate::__when!(@arms $($rest)+)
+    };
+    (@arms #[unlikely] $($rest:tt)+) => {
+        $crate::__when!(@arms $($rest)+)
+    };
+    (@arms #[$($attr:tt)*] $($rest:tt)*) => {
+        $crate::__when!(@split [#[$($attr)*]] $($rest)*)
+    };
+    // Labels are only used by other macros, like `dbg_when!`. `$label:literal` can't be used, since
+    // it fails hard on conditions starting with `-`
+    (@arms $label:tt : $($rest:tt)*) => {
+        $crate::__when!(@arms $($rest)*)
+    };
+    // `if` chains pasted into `when!`
+    (@arms else if $($rest:tt)*) => {
+        ::core::compile_error!("unexpected `else if`: arms of `when!` are already checked in order, remove `else`")
+    };
+    (@arms else { $($branch:tt)* } $($rest:tt)*) => {
+        ::core::compile_error!("unexpected `else { ... }`: use `_ => { ... }` for the default arm")
+    };
+    (@arms _ => $def_branch:expr $(,)?) => {
+        $def_branch
+    };
+    (@arms else => $def_branch:expr $(,)?) => {
+        $def_branch
+    };
+    (@arms _ => $def_branch:expr, $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    (@arms _ => { $($def_branch:tt)* } $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    (@arms else => $def_branch:expr, $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    (@arms else => { $($def_branch:tt)* } $($rest:tt)+) => {
+        ::core::compile_error!("default arm must be last; following arms are unreachable")
+    };
+    (@arms .. $($path:ident)::+ ! ($($args:tt)*) $(, $($rest:tt)*)?) => {
+        $($path)::+! { ($($args)*) $($($rest)*)? }
+    };
+    (@arms .. $($path:ident)::+ ! [$($args:tt)*] $(, $($rest:tt)*)?) => {
+        $($path)::+! { ($($args)*) $($($rest)*)? }
+    };
+    // Runs of simple arms are expanded several at a time, so that long `when!`s don't hit the
+    // recursion limit. An arm with attributes would be parsed as an attributed expression,
+    // so a run stops before it.
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        # $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else {
+            $crate::__when!(@arms # $($rest)*)
+        }
+    };
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        $(let $($p1:pat)|+ =)? $c1:expr => $b1:expr,
+        # $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else
+        if $(let $($p1)|+ =)? $c1 { $b1 } else {
+            $crate::__when!(@arms # $($rest)*)
+        }
+    };
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        $(let $($p1:pat)|+ =)? $c1:expr => $b1:expr,
+        $(let $($p2:pat)|+ =)? $c2:expr => $b2:expr,
+        # $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else
+        if $(let $($p1)|+ =)? $c1 { $b1 } else
+        if $(let $($p2)|+ =)? $c2 { $b2 } else {
+            $crate::__when!(@arms # $($rest)*)
+        }
+    };
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        $(let $($p1:pat)|+ =)? $c1:expr => $b1:expr,
+        $(let $($p2:pat)|+ =)? $c2:expr => $b2:expr,
+        $(let $($p3:pat)|+ =)? $c3:expr => $b3:expr,
+        # $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else
+        if $(let $($p1)|+ =)? $c1 { $b1 } else
+        if $(let $($p2)|+ =)? $c2 { $b2 } else
+        if $(let $($p3)|+ =)? $c3 { $b3 } else {
+            $crate::__when!(@arms # $($rest)*)
+        }
+    };
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        $(let $($p1:pat)|+ =)? $c1:expr => $b1:expr,
+        $(let $($p2:pat)|+ =)? $c2:expr => $b2:expr,
+        $(let $($p3:pat)|+ =)? $c3:expr => $b3:expr,
+        $(let $($p4:pat)|+ =)? $c4:expr => $b4:expr,
+        # $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else
+        if $(let $($p1)|+ =)? $c1 { $b1 } else
+        if $(let $($p2)|+ =)? $c2 { $b2 } else
+        if $(let $($p3)|+ =)? $c3 { $b3 } else
+        if $(let $($p4)|+ =)? $c4 { $b4 } else {
+            $crate::__when!(@arms # $($rest)*)
+        }
+    };
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        $(let $($p1:pat)|+ =)? $c1:expr => $b1:expr,
+        $(let $($p2:pat)|+ =)? $c2:expr => $b2:expr,
+        $(let $($p3:pat)|+ =)? $c3:expr => $b3:expr,
+        $(let $($p4:pat)|+ =)? $c4:expr => $b4:expr,
+        $(let $($p5:pat)|+ =)? $c5:expr => $b5:expr,
+        # $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else
+        if $(let $($p1)|+ =)? $c1 { $b1 } else
+        if $(let $($p2)|+ =)? $c2 { $b2 } else
+        if $(let $($p3)|+ =)? $c3 { $b3 } else
+        if $(let $($p4)|+ =)? $c4 { $b4 } else
+        if $(let $($p5)|+ =)? $c5 { $b5 } else {
+            $crate::__when!(@arms # $($rest)*)
+        }
+    };
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        $(let $($p1:pat)|+ =)? $c1:expr => $b1:expr,
+        $(let $($p2:pat)|+ =)? $c2:expr => $b2:expr,
+        $(let $($p3:pat)|+ =)? $c3:expr => $b3:expr,
+        $(let $($p4:pat)|+ =)? $c4:expr => $b4:expr,
+        $(let $($p5:pat)|+ =)? $c5:expr => $b5:expr,
+        $(let $($p6:pat)|+ =)? $c6:expr => $b6:expr,
+        # $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else
+        if $(let $($p1)|+ =)? $c1 { $b1 } else
+        if $(let $($p2)|+ =)? $c2 { $b2 } else
+        if $(let $($p3)|+ =)? $c3 { $b3 } else
+        if $(let $($p4)|+ =)? $c4 { $b4 } else
+        if $(let $($p5)|+ =)? $c5 { $b5 } else
+        if $(let $($p6)|+ =)? $c6 { $b6 } else {
+            $crate::__when!(@arms # $($rest)*)
+        }
+    };
+    (
+        @arms
+        $(let $($p0:pat)|+ =)? $c0:expr => $b0:expr,
+        $(let $($p1:pat)|+ =)? $c1:expr => $b1:expr,
+        $(let $($p2:pat)|+ =)? $c2:expr => $b2:expr,
+        $(let $($p3:pat)|+ =)? $c3:expr => $b3:expr,
+        $(let $($p4:pat)|+ =)? $c4:expr => $b4:expr,
+        $(let $($p5:pat)|+ =)? $c5:expr => $b5:expr,
+        $(let $($p6:pat)|+ =)? $c6:expr => $b6:expr,
+        $(let $($p7:pat)|+ =)? $c7:expr => $b7:expr,
+        $($rest:tt)*
+    ) => {
+        if $(let $($p0)|+ =)? $c0 { $b0 } else
+        if $(let $($p1)|+ =)? $c1 { $b1 } else
+        if $(let $($p2)|+ =)? $c2 { $b2 } else
+        if $(let $($p3)|+ =)? $c3 { $b3 } else
+        if $(let $($p4)|+ =)? $c4 { $b4 } else
+        if $(let $($p5)|+ =)? $c5 { $b5 } else
+        if $(let $($p6)|+ =)? $c6 { $b6 } else
+        if $(let $($p7)|+ =)? $c7 { $b7 } else {
+            $crate::__when!(@arms $($rest)*)
+        }
+    };
+    (@arms let $($pat:pat)|+ = $cond:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        if let $($pat)|+ = $cond {
+            $branch
+        } else {
+            $crate::__when!(@arms $($($rest)*)?)
+        }
+    };
+    (@arms let $($pat:pat)|+ = $cond:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        if let $($pat)|+ = $cond {
+            $($branch)*
+        } else {
+            $crate::__when!(@arms $($rest)+)
+        }
+    };
+    (@arms $cond:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        if $cond {
+            $branch
+        } else {
+            $crate::__when!(@arms $($($rest)*)?)
+        }
+    };
+    (@arms $cond:expr => { $($branch:tt)* } $($rest:tt)+) => {
+        if $cond {
+            $($branch)*
+        } else {
+            $crate::__when!(@arms $($rest)+)
+        }
+    };
+    // Arms with several conditions are expanded to an `Option` (see `@slot`), runs of them are
+    // expanded several at a time too
+    (
+        @arms
+        $($(let $($p0:pat)|+ =)? $c0:expr),+ => $b0:expr,
+        $($(let $($p1:pat)|+ =)? $c1:expr),+ => $b1:expr,
+        $($(let $($p2:pat)|+ =)? $c2:expr),+ => $b2:expr,
+        $($(let $($p3:pat)|+ =)? $c3:expr),+ => $b3:expr,
+        $($(let $($p4:pat)|+ =)? $c4:expr),+ => $b4:expr,
+        $($(let $($p5:pat)|+ =)? $c5:expr),+ => $b5:expr,
+        $($(let $($p6:pat)|+ =)? $c6:expr),+ => $b6:expr,
+        $($(let $($p7:pat)|+ =)? $c7:expr),+ => $b7:expr,
+        $($rest:tt)*
+    ) => {
+        if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p0)|+ =)? $c0 })+]] { $b0 })
+        {
+            value
+        } else if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p1)|+ =)? $c1 })+]] { $b1 })
+        {
+            value
+        } else if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p2)|+ =)? $c2 })+]] { $b2 })
+        {
+            value
+        } else if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p3)|+ =)? $c3 })+]] { $b3 })
+        {
+            value
+        } else if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p4)|+ =)? $c4 })+]] { $b4 })
+        {
+            value
+        } else if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p5)|+ =)? $c5 })+]] { $b5 })
+        {
+            value
+        } else if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p6)|+ =)? $c6 })+]] { $b6 })
+        {
+            value
+        } else if let ::core::option::Option::Some(value) =
+            $crate::__when!(@slot [[$({ $(let $($p7)|+ =)? $c7 })+]] { $b7 })
+        {
+            value
+        } else {
+            $crate::__when!(@arms $($rest)*)
+        }
+    };
+    (@arms $($(let $($pat:pat)|+ =)? $cond:expr),+ => $branch:expr $(, $($rest:tt)*)?) => {
+        match $crate::__when!(@slot [[$({ $(let $($pat)|+ =)? $cond })+]] { $branch }) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($($rest)*)?),
+        }
+    };
+    (@arms $($(let $($pat:pat)|+ =)? $cond:expr),+ => { $($branch:tt)* } $($rest:tt)+) => {
+        match $crate::__when!(@slot [[$({ $(let $($pat)|+ =)? $cond })+]] { $($branch)* }) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    // Other arms (guards, `or`, `matches`, ...) can't be matched with `expr`, so they are split off
+    // at the first `=>` and their conditions are scanned separately (see `@attrs`), without nesting
+    // the rest of the arms in the scanning. Arms with up to ten tokens before `=>` are split off
+    // in a single step, longer ones (and ones with a block branch not followed by `,`) go through
+    // `@split`
+    (@arms $c0:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 $c3 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2 $c3] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 $c3 $c4 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2 $c3 $c4] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 $c3 $c4 $c5 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2 $c3 $c4 $c5] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 $c3 $c4 $c5 $c6 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2 $c3 $c4 $c5 $c6] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt $c7:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 $c3 $c4 $c5 $c6 $c7 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt $c7:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2 $c3 $c4 $c5 $c6 $c7] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt $c7:tt $c8:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 $c3 $c4 $c5 $c6 $c7 $c8 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt $c7:tt $c8:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2 $c3 $c4 $c5 $c6 $c7 $c8] => $($rest)*)
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt $c7:tt $c8:tt $c9:tt => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $c0 $c1 $c2 $c3 $c4 $c5 $c6 $c7 $c8 $c9 => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    (@arms $c0:tt $c1:tt $c2:tt $c3:tt $c4:tt $c5:tt $c6:tt $c7:tt $c8:tt $c9:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$c0 $c1 $c2 $c3 $c4 $c5 $c6 $c7 $c8 $c9] => $($rest)*)
+    };
+    (@arms $($rest:tt)+) => {
+        $crate::__when!(@split [] $($rest)+)
+    };
+    // `@split [<tokens before `=>`>] ...`, the tokens are moved eight at a time
+    (@split [$($cond:tt)*] => $branch:expr, $($rest:tt)+) => {
+        match $crate::__when!(@attrs [] $($cond)* => $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => $crate::__when!(@arms $($rest)+),
+        }
+    };
+    // The last arm is expanded completely by `@attrs`, so that if it's malformed (and possibly
+    // includes the default arm, like `x -> 1, _ => 0`) the expansion is only the error
+    (@split [$($cond:tt)*] => $branch:expr $(,)?) => {
+        $crate::__when!(@attrs [@last] $($cond)* => $branch)
+    };
+    (@split [$($cond:tt)*] => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when!(@split [$($cond)*] => { $($branch)* }, $($rest)+)
+    };
+    (@split [$($cond:tt)*] $t0:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0] => $($rest)*)
+    };
+    (@split [$($cond:tt)*] $t0:tt $t1:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0 $t1] => $($rest)*)
+    };
+    (@split [$($cond:tt)*] $t0:tt $t1:tt $t2:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0 $t1 $t2] => $($rest)*)
+    };
+    (@split [$($cond:tt)*] $t0:tt $t1:tt $t2:tt $t3:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0 $t1 $t2 $t3] => $($rest)*)
+    };
+    (@split [$($cond:tt)*] $t0:tt $t1:tt $t2:tt $t3:tt $t4:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0 $t1 $t2 $t3 $t4] => $($rest)*)
+    };
+    (@split [$($cond:tt)*] $t0:tt $t1:tt $t2:tt $t3:tt $t4:tt $t5:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0 $t1 $t2 $t3 $t4 $t5] => $($rest)*)
+    };
+    (@split [$($cond:tt)*] $t0:tt $t1:tt $t2:tt $t3:tt $t4:tt $t5:tt $t6:tt => $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0 $t1 $t2 $t3 $t4 $t5 $t6] => $($rest)*)
+    };
+    (@split [$($cond:tt)*] $t0:tt $t1:tt $t2:tt $t3:tt $t4:tt $t5:tt $t6:tt $t7:tt $($rest:tt)*) => {
+        $crate::__when!(@split [$($cond)* $t0 $t1 $t2 $t3 $t4 $t5 $t6 $t7] $($rest)*)
+    };
+    // There is no `=>` or the branch can't be parsed, which is reported by `@chain` and `@scan`
+    (@split [$($cond:tt)*] $($rest:tt)*) => {
+        $crate::__when!(@attrs [] $($cond)* $($rest)*)
+    };
+    (@attrs [$($attrs:tt)*] #[cost(cheap)] $($rest:tt)*) => {
+        $crate::__when!(@attrs [$($attrs)*] $($rest)*)
+    };
+    (@attrs [$($attrs:tt)*] #[cost(expensive)] $($rest:tt)*) => {
+        $crate::__when!(@attrs [$($attrs)*] $($rest)*)
+    };
+    // Hints are only supported on arms with a single condition and without other attributes
+    (@attrs [$($attrs:tt)*] #[likely] $($rest:tt)*) => {
+        $crate::__when!(@attrs [$($attrs)*] $($rest)*)
+    };
+    (@attrs [$($attrs:tt)*] #[unlikely] $($rest:tt)*) => {
+        $crate::__when!(@attrs [$($attrs)*] $($rest)*)
+    };
+    (@attrs [$($attrs:tt)*] #[$($attr:tt)*] $($rest:tt)*) => {
+        $crate::__when!(@attrs [$($attrs)* #[$($attr)*]] $($rest)*)
+    };
+    (@attrs [$($attrs:tt)*] $label:tt : $($rest:tt)*) => {
+        $crate::__when!(@attrs [$($attrs)*] $($rest)*)
+    };
+    (@attrs [$($attrs:tt)*] $($rest:tt)*) => {
+        $crate::__when!(@chain [$($attrs)*] [] $($rest)*)
+    };
+    // `@chain [<attributes> <finished alternatives>] [<conditions of the current alternative>] ...`
+    (@chain [$($alts:tt)*] [$($conds:tt)*] not let $($pat:pat)|+ = $cond:expr, $($rest:tt)*) => {
+        $crate::__when!(@chain [$($alts)*] [$($conds)* { !::core::matches!($cond, $($pat)|+) }] $($rest)*)
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] not let $($pat:pat)|+ = $cond:expr => $branch:expr) => {
+        $crate::__when!(@slot [$($alts)* [$($conds)* { !::core::matches!($cond, $($pat)|+) }]] { $branch })
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] not let $($pat:pat)|+ = $($rest:tt)*) => {
+        $crate::__when!(@scan [$($alts)*] [$($conds)*] [not let $($pat)|+ =] [] $($rest)*)
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] let $($pat:pat)|+ = $cond:expr, $($rest:tt)*) => {
+        $crate::__when!(@chain [$($alts)*] [$($conds)* { let $($pat)|+ = $cond }] $($rest)*)
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] let $($pat:pat)|+ = $cond:expr => $branch:expr) => {
+        $crate::__when!(@slot [$($alts)* [$($conds)* { let $($pat)|+ = $cond }]] { $branch })
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] let $($pat:pat)|+ = $($rest:tt)*) => {
+        $crate::__when!(@scan [$($alts)*] [$($conds)*] [let $($pat)|+ =] [] $($rest)*)
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] $cond:expr, $($rest:tt)*) => {
+        $crate::__when!(@chain [$($alts)*] [$($conds)* { $cond }] $($rest)*)
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] $cond:expr => $branch:expr) => {
+        $crate::__when!(@slot [$($alts)* [$($conds)* { $cond }]] { $branch })
+    };
+    (@chain [$($alts:tt)*] [$($conds:tt)*] $($rest:tt)+) => {
+        $crate::__when!(@scan [$($alts)*] [$($conds)*] [] [] $($rest)+)
+    };
+    // Conditions which can't be parsed as `expr` followed by `,` or `=>` are scanned token by token:
+    // `@scan [<alternatives>] [<conditions>] [<condition kind>] [<scanned tokens>] ...`
+    (@scan [] [] [let $($pat:pat)|+ =] [$($cond:tt)+] if $guard:expr => $branch:expr) => {
+        match ($($cond)+) {
+            // The branch may diverge (e.g. `=> return`), which is fine
+            #[allow(unreachable_code, clippy::diverging_sub_expression)]
+            $($pat)|+ if $guard => ::core::option::Option::Some($branch),
+            _ => ::core::option::Option::None,
+        }
+    };
+    (@scan [@last] [] [let $($pat:pat)|+ =] [$($cond:tt)+] if $guard:expr => $branch:expr) => {
+        match ($($cond)+) {
+            $($pat)|+ if $guard => $branch,
+            _ => {}
+        }
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [let $($pat:pat)|+ =] [$($cond:tt)+] if $guard:expr => $branch:expr) => {
+        $crate::__when!(@slot [$($alts)* [$($conds)* { let $($pat)|+ = ($($cond)+) } { $guard }]] { $branch })
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [let $($pat:pat)|+ =] [$($cond:tt)+] or $($rest:tt)+) => {
+        $crate::__when!(@chain [$($alts)* [$($conds)* { let $($pat)|+ = ($($cond)+) }]] [] $($rest)+)
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [not let $($pat:pat)|+ =] [$($cond:tt)+] or $($rest:tt)+) => {
+        $crate::__when!(
+            @chain [$($alts)* [$($conds)* { !::core::matches!(($($cond)+), $($pat)|+) }]] [] $($rest)+
+        )
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [($($cond:tt)+) matches] [$($pat:tt)+] or $($rest:tt)+) => {
+        $crate::__when!(
+            @chain [$($alts)* [$($conds)* { ::core::matches!(($($cond)+), $($pat)+) }]] [] $($rest)+
+        )
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [] [$($cond:tt)+] or $($rest:tt)+) => {
+        $crate::__when!(@chain [$($alts)* [$($conds)* { ($($cond)+) }]] [] $($rest)+)
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [] [$($cond:tt)+] matches $($pat:pat)|+ $(if $guard:expr)?, $($rest:tt)*) => {
+        $crate::__when!(
+            @chain [$($alts)*] [$($conds)* { ::core::matches!(($($cond)+), $($pat)|+ $(if $guard)?) }] $($rest)*
+        )
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [] [$($cond:tt)+] matches $($pat:pat)|+ $(if $guard:expr)? => $branch:expr) => {
+        $crate::__when!(
+            @slot [$($alts)* [$($conds)* { ::core::matches!(($($cond)+), $($pat)|+ $(if $guard)?) }]] { $branch }
+        )
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [] [$($cond:tt)+] matches $($rest:tt)+) => {
+        $crate::__when!(@scan [$($alts)*] [$($conds)*] [($($cond)+) matches] [] $($rest)+)
+    };
+    // `.or(...)` and `::or` are method/function calls, not alternatives
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*] . or $($rest:tt)*) => {
+        $crate::__when!(@scan [$($alts)*] [$($conds)*] [$($kind)*] [$($cond)* . or] $($rest)*)
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*] :: or $($rest:tt)*) => {
+        $crate::__when!(@scan [$($alts)*] [$($conds)*] [$($kind)*] [$($cond)* :: or] $($rest)*)
+    };
+    // A condition which is still scanned when `->`, `=>` or the end of the arms is reached is
+    // missing `=>` (possibly, in a previous arm)
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*] -> $($rest:tt)*) => {
+        $crate::__when!(@missing_arrow [$($kind)* $($cond)*] -> $($rest)*)
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*] , _ => $($rest:tt)*) => {
+        $crate::__when!(@missing_arrow [$($kind)* $($cond)*] , _ => $($rest)*)
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*] , else => $($rest:tt)*) => {
+        $crate::__when!(@missing_arrow [$($kind)* $($cond)*] , else => $($rest)*)
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*] => $($rest:tt)*) => {
+        $crate::__when!(@missing_arrow [$($kind)* $($cond)*] => $($rest)*)
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*]) => {
+        $crate::__when!(@missing_arrow [$($kind)* $($cond)*])
+    };
+    (@scan [$($alts:tt)*] [$($conds:tt)*] [$($kind:tt)*] [$($cond:tt)*] $token:tt $($rest:tt)*) => {
+        $crate::__when!(@scan [$($alts)*] [$($conds)*] [$($kind)*] [$($cond)* $token] $($rest)*)
+    };
+    // Arms which can't be parsed are scanned token by token to report a missing `=>`:
+    // `@missing_arrow [<scanned tokens>] ...`
+    (@missing_arrow [$($cond:tt)+] -> $($rest:tt)*) => {
+        ::core::compile_error!(::core::concat!(
+            "expected `=>` after `", ::core::stringify!($($cond)+), "`, found `->`"
+        ))
+    };
+    (@missing_arrow [$($cond:tt)+] , _ => $($rest:tt)*) => {
+        ::core::compile_error!(::core::concat!("missing `=>` after `", ::core::stringify!($($cond)+), "`"))
+    };
+    (@missing_arrow [$($cond:tt)+] , else => $($rest:tt)*) => {
+        ::core::compile_error!(::core::concat!("missing `=>` after `", ::core::stringify!($($cond)+), "`"))
+    };
+    (@missing_arrow [$($cond:tt)+] => $($rest:tt)*) => {
+        ::core::compile_error!(::core::concat!(
+            "can't parse `", ::core::stringify!($($cond)+), "`; is `=>` missing?"
+        ))
+    };
+    (@missing_arrow [$($cond:tt)+]) => {
+        ::core::compile_error!(::core::concat!("missing `=>` after `", ::core::stringify!($($cond)+), "`"))
+    };
+    (@missing_arrow [$($cond:tt)*] $token:tt $($rest:tt)*) => {
+        $crate::__when!(@missing_arrow [$($cond)* $token] $($rest)*)
+    };
+    // Arms which can't be expressed as a single `if` are expanded to an `Option`, which is `Some`
+    // if the arm matched: the conditions are nested and the result of the branch is stored in a
+    // "slot", which is returned afterwards.
+    //
+    // This is also used for arms with attributes, which are applied to the nested conditions, and
+    // arms with alternatives (`or`), each of which is only checked if the previous didn't match.
+    (@slot [@last $($alts:tt)*] $branch:tt) => {
+        match $crate::__when!(@slot [$($alts)*] $branch) {
+            ::core::option::Option::Some(value) => value,
+            ::core::option::Option::None => {}
+        }
+    };
+    (@slot [$(#[$attr:meta])* [$({ $($cond:tt)* })*]] $branch:tt) => {{
+        #[allow(unused_mut)]
+        let mut slot = ::core::option::Option::None;
+        $(#[$attr])*
+        {
+            $crate::__when!(@nest slot [$({ $($cond)* })*] $branch);
+        }
+        slot
+    }};
+    (@slot [$(#[$attr:meta])* $([$({ $($cond:tt)* })*])*] $branch:tt) => {{
+        #[allow(unused_mut)]
+        let mut slot = ::core::option::Option::None;
+        $(#[$attr])*
+        {
+            $(
+                if slot.is_none() {
+                    $crate::__when!(@nest slot [$({ $($cond)* })*] $branch);
+                }
+            )*
+        }
+        slot
+    }};
+    (@nest $slot:ident [{ $($cond:tt)* } $($conds:tt)*] $branch:tt) => {
+        if $($cond)* {
+            $crate::__when!(@nest $slot [$($conds)*] $branch);
+        }
+    };
+    (@nest $slot:ident [] $branch:tt) => {
+        // The branch may diverge (e.g. `=> return`), which is fine
+        #[allow(unreachable_code, clippy::diverging_sub_expression)]
+        {
+            $slot = ::core::option::Option::Some($branch);
+        }
+    };
+    // The first `;` or `=>` decides if this is the subject form. The tokens before `;` can't be
+    // matched as `expr` because `<expr> as <name>` would be parsed as a cast.
+    (@entry [$($subject:tt)+] as $name:ident; $($arms:tt)*) => {
+        match ($($subject)+) {
+            $name => {
+                // The subject is unused if there are no arms other than the default one
+                #[allow(unused_variables)]
+                let subject = &$name;
+                $crate::__when!(@subject subject [] $($arms)*)
+            }
+        }
+    };
+    (@entry [$($subject:tt)+]; $($arms:tt)*) => {
+        match ($($subject)+) {
+            // The subject is unused if there are no arms other than the default one
+            #[allow(unused_variables)]
+            ref subject => $crate::__when!(@subject subject [] $($arms)*),
+        }
+    };
+    (@entry [$($head:tt)*] => $($rest:tt)*) => {
+        $crate::__when!(@arms $($head)* => $($rest)*)
+    };
+    (@entry [$($head:tt)*] $token:tt $($rest:tt)*) => {
+        $crate::__when!(@entry [$($head)* $token] $($rest)*)
+    };
+    (@entry [$($arms:tt)*]) => {
+        $crate::__when!(@arms $($arms)*)
+    };
+    // The subject can't start with `let` or be followed by `=>` or `,`, so in these cases the first
+    // arm isn't scanned for `;` (which would hit the recursion limit if the arm is long)
+    (let $($rest:tt)*) => {
+        $crate::__when!(@arms let $($rest)*)
+    };
+    (not let $($rest:tt)*) => {
+        $crate::__when!(@arms not let $($rest)*)
+    };
+    (.. $($path:ident)::+ ! $args:tt $($rest:tt)*) => {
+        $crate::__when!(@arms .. $($path)::+ ! $args $($rest)*)
+    };
+    ($cond:expr => $($rest:tt)*) => {
+        $crate::__when!(@arms $cond => $($rest)*)
+    };
+    ($cond:expr, $($rest:tt)*) => {
+        $crate::__when!(@arms $cond, $($rest)*)
+    };
+    ($($tokens:tt)*) => {
+        $crate::__when!(@entry [] $($tokens)*)
+    };
+}
+
+/// Same as the subject form of [`when!`], but the arms are checked for exhaustiveness, like `match`
+/// arms.
+///
+/// Only `let` arms (with optional labels and `if` guards) are accepted and there is no default arm.
+/// This way forgetting to handle a variant (for example, one which was added to an enum later) is a
+/// compile-time error:
+///
+/// ```rust
+/// enum Shape {
+///     Circle(f32),
+///     Square(f32),
+/// }
+///
+/// let shape = Shape::Square(2.0);
+///
+/// let area = kiam::when_exhaustive! {
+///     shape;
+///     let Shape::Circle(r) => 3.14 * r * r,
+///     let Shape::Square(a) => a * a,
+/// };
+///
+/// assert_eq!(area, 4.0);
+/// ```
+///
+/// ```compile_fail
+/// enum Shape {
+///     Circle(f32),
+///     Square(f32),
+/// }
+///
+/// let shape = Shape::Square(2.0);
+///
+/// let area = kiam::when_exhaustive! {
+///     shape;
+///     let Shape::Circle(r) => 3.14 * r * r,
+/// };
+/// ```
+#[macro_export]
+macro_rules! when_exhaustive {
+    ($($tokens:tt)*) => {
+        $crate::__when_exhaustive!(@entry [] $($tokens)*)
+    };
+}
+
+// The implementation of `when_exhaustive!`: the arms are collected into a `match` without a
+// fallback, `@let <subject> [<match arms>] ...`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __when_exhaustive {
+    (@let $subject:ident [$($acc:tt)*] $label:tt : let $($rest:tt)*) => {
+        $crate::__when_exhaustive!(@let $subject [$($acc)*] let $($rest)*)
+    };
+    (@let $subject:ident [$($acc:tt)*] let $($pat:pat)|+ $(if $guard:expr)? => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when_exhaustive!(@let $subject [$($acc)* $($pat)|+ $(if $guard)? => $branch,] $($($rest)*)?)
+    };
+    (@let $subject:ident [$($acc:tt)*] let $($pat:pat)|+ $(if $guard:expr)? => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when_exhaustive!(@let $subject [$($acc)*] let $($pat)|+ $(if $guard)? => { $($branch)* }, $($rest)+)
+    };
+    (@let $subject:ident [$($acc:tt)*]) => {
+        match $subject {
+            $($acc)*
+        }
+    };
+    (@let $subject:ident [$($acc:tt)*] $($rest:tt)+) => {
+        ::core::compile_error!(::core::concat!(
+            "`when_exhaustive!` only accepts `let` arms, found `",
+            ::core::stringify!($($rest)+),
+            "`"
+        ))
+    };
+    (@entry [$($subject:tt)+] as $name:ident; $($arms:tt)*) => {
+        match ($($subject)+) {
+            $name => {
+                let subject = &$name;
+                $crate::__when_exhaustive!(@let subject [] $($arms)*)
+            }
+        }
+    };
+    (@entry [$($subject:tt)+]; $($arms:tt)*) => {
+        match ($($subject)+) {
+            ref subject => $crate::__when_exhaustive!(@let subject [] $($arms)*),
+        }
+    };
+    (@entry [$($subject:tt)*] $token:tt $($rest:tt)*) => {
+        $crate::__when_exhaustive!(@entry [$($subject)* $token] $($rest)*)
+    };
+    (@entry [$($subject:tt)*]) => {
+        ::core::compile_error!("expected `;` after the subject of `when_exhaustive!`")
+    };
+}
+
+/// Same as [`when!`], but evaluates to `Some(branch)` if an arm fired and to `None` otherwise.
+///
+/// ```rust
+/// let x = 12;
+///
+/// let parity = kiam::when_opt! {
+///     x < 0 => "negative",
+///     x % 2 == 0 => "even",
+/// };
+///
+/// assert_eq!(parity, Some("even"));
+///
+/// let error = kiam::when_opt! {
+///     let Err(e) = "17".parse::<u8>() => e,
+/// };
+///
+/// assert_eq!(error, None);
+/// ```
+///
+/// Since `None` is used as the default branch, a default arm (`_ =>` or `else =>`) is not allowed.
+/// Note that arms generated by other macros are not wrapped in `Some`.
+#[macro_export]
+macro_rules! when_opt {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!(
+            [::core::option::Option::Some] [::core::option::Option::None] [] [] $($arms)*
+        )
+    };
+}
+
+/// Same as [`when!`], but evaluates to `Ok(branch)` if an arm fired and to `Err(NoMatch)` otherwise.
+///
+/// This allows to propagate the "no arm fired" case with `?`:
+///
+/// ```rust
+/// use kiam::NoMatch;
+///
+/// fn unit_multiplier(unit: &str) -> Result<u64, NoMatch> {
+///     let m = kiam::try_when! {
+///         unit;
+///         "s" => 1,
+///         "m" => 60,
+///         "h" => 60 * 60,
+///     }?;
+///
+///     Ok(m * 1000)
+/// }
+///
+/// assert_eq!(unit_multiplier("m"), Ok(60_000));
+/// assert_eq!(unit_multiplier("y"), Err(NoMatch));
+/// ```
+///
+/// Since `Err(NoMatch)` is used as the default branch, a default arm (`_ =>` or `else =>`) is not
+/// allowed. Note that arms generated by other macros are not wrapped in `Ok`.
+#[macro_export]
+macro_rules! try_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!(
+            [::core::result::Result::Ok] [::core::result::Result::Err($crate::NoMatch)] [] []
+            $($arms)*
+        )
+    };
+}
+
+/// Same as [`when!`], but panics if none of the arms fired.
+///
+/// The panic message contains the location of the macro invocation and all the conditions which
+/// were checked, so this is useful for dispatch where falling through is a bug:
+///
+/// ```rust,should_panic
+/// let x = 7;
+///
+/// // panics with:
+/// // none of the arms matched (src/main.rs:5), checked conditions:
+/// //     x < 5
+/// //     x % 2 == 0
+/// kiam::assert_when! {
+///     x < 5 => (),
+///     x % 2 == 0 => (),
+/// }
+/// ```
+///
+/// Since the panic is used as the default branch, a default arm (`_ =>` or `else =>`) is not
+/// allowed.
+#[macro_export]
+macro_rules! assert_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@panic] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but the default branch is `unreachable!()` instead of `()`.
+///
+/// This makes the intent explicit when one of the arms always fires:
+///
+/// ```rust
+/// let x: u8 = 200;
+///
+/// let size = kiam::when_unreachable! {
+///     x < 10 => "small",
+///     x < 100 => "medium",
+///     x <= u8::MAX => "large",
+/// };
+///
+/// assert_eq!(size, "large");
+/// ```
+///
+/// Since `unreachable!()` is used as the default branch, a default arm (`_ =>` or `else =>`) is not
+/// allowed.
+#[macro_export]
+macro_rules! when_unreachable {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [::core::unreachable!("no when! arm matched")] [] [] $($arms)*)
+    };
+}
+
+/// Evaluates the expressions left to right and returns the first `Some`, or `None` if all of them
+/// are `None`.
+///
+/// The expressions after the first `Some` are not evaluated:
+///
+/// ```rust
+/// let cache = |k: u32| if k == 1 { Some("cached") } else { None };
+/// let db = |k: u32| if k < 10 { Some("stored") } else { None };
+///
+/// assert_eq!(kiam::first_some!(cache(1), db(1), unreachable!()), Some("cached"));
+/// assert_eq!(kiam::first_some!(cache(2), db(2)), Some("stored"));
+/// assert_eq!(kiam::first_some!(cache(20), db(20)), None);
+/// ```
+#[macro_export]
+macro_rules! first_some {
+    () => {
+        ::core::option::Option::None
+    };
+    ($first:expr $(, $rest:expr)* $(,)?) => {
+        match $first {
+            ::core::option::Option::Some(value) => ::core::option::Option::Some(value),
+            ::core::option::Option::None => $crate::first_some!($($rest),*),
+        }
+    };
+}
+
+/// Evaluates the expressions left to right and returns the first `Ok`, or the last `Err` if all of
+/// them are `Err`.
+///
+/// The expressions after the first `Ok` are not evaluated:
+///
+/// ```rust
+/// let parse = |s: &str| s.parse::<u8>();
+///
+/// assert_eq!(kiam::first_ok!(parse("x"), parse("7"), parse(unreachable!())), Ok(7));
+/// assert!(kiam::first_ok!(parse("x"), parse("300")).is_err());
+/// ```
+///
+/// Only the last error is kept, so the errors of the other expressions may be of different types.
+#[macro_export]
+macro_rules! first_ok {
+    ($last:expr $(,)?) => {
+        $last
+    };
+    ($first:expr, $($rest:expr),+ $(,)?) => {
+        match $first {
+            ::core::result::Result::Ok(value) => ::core::result::Result::Ok(value),
+            ::core::result::Result::Err(_) => $crate::first_ok!($($rest),+),
+        }
+    };
+}
+
+/// Evaluates the expressions left to right and returns the first which is not empty, or the last
+/// one if all of them are empty.
+///
+/// The expressions after the first non-empty one are not evaluated. Emptiness is checked with
+/// [`MaybeEmpty`], which is implemented for strings, slices, arrays and references to them (and
+/// for collections from `alloc` with the `alloc` feature):
+///
+/// ```rust
+/// let arg = "";
+/// let env = || "from env";
+///
+/// let config = kiam::first_non_empty!(arg, env(), unreachable!());
+///
+/// assert_eq!(config, "from env");
+/// assert_eq!(kiam::first_non_empty!("", ""), "");
+/// ```
+#[macro_export]
+macro_rules! first_non_empty {
+    ($last:expr $(,)?) => {
+        $last
+    };
+    ($first:expr, $($rest:expr),+ $(,)?) => {
+        match $first {
+            value => {
+                if $crate::MaybeEmpty::is_empty(&value) {
+                    $crate::first_non_empty!($($rest),+)
+                } else {
+                    value
+                }
+            }
+        }
+    };
+}
+
+/// Compact two-way expression, `choose!(cond => a, _ => b)` is the same as
+/// `if cond { a } else { b }`.
+///
+/// ```rust
+/// let n = 3;
+///
+/// println!("{} item{}", n, kiam::choose!(n == 1 => "", _ => "s"));
+/// assert_eq!(kiam::choose!(n > 2 => "many", else => "few"), "many");
+/// ```
+#[macro_export]
+macro_rules! choose {
+    ($cond:expr => $then:expr, _ => $otherwise:expr $(,)?) => {
+        if $cond {
+            $then
+        } else {
+            $otherwise
+        }
+    };
+    ($cond:expr => $then:expr, else => $otherwise:expr $(,)?) => {
+        if $cond {
+            $then
+        } else {
+            $otherwise
+        }
+    };
+}
+
+/// Inverted condition, `unless!(cond => a, _ => b)` is the same as `if !cond { a } else { b }`.
+///
+/// ```rust
+/// let ready = false;
+///
+/// let status = kiam::unless!(ready => "waiting", _ => "running");
+/// assert_eq!(status, "waiting");
+///
+/// // The default branch is optional
+/// kiam::unless!(ready => println!("still waiting"));
+/// ```
+#[macro_export]
+macro_rules! unless {
+    ($cond:expr => $branch:expr $(,)?) => {
+        if !$cond {
+            $branch
+        }
+    };
+    ($cond:expr => $branch:expr, _ => $otherwise:expr $(,)?) => {
+        if !$cond {
+            $branch
+        } else {
+            $otherwise
+        }
+    };
+    ($cond:expr => $branch:expr, else => $otherwise:expr $(,)?) => {
+        if !$cond {
+            $branch
+        } else {
+            $otherwise
+        }
+    };
+}
+
+/// Guard clause, binds the names from a pattern in the enclosing scope or diverges if the pattern
+/// doesn't match.
+///
+/// `guard!(let <pat> = <expr> else <diverge>)` is the same as
+/// `let <pat> = <expr> else { <diverge> };`:
+///
+/// ```rust
+/// #[derive(Debug, PartialEq)]
+/// struct Missing;
+///
+/// fn first_even(xs: &[u32]) -> Result<u32, Missing> {
+///     kiam::guard!(let Some(&x) = xs.iter().find(|x| *x % 2 == 0) else return Err(Missing));
+///     Ok(x)
+/// }
+///
+/// assert_eq!(first_even(&[1, 4, 5]), Ok(4));
+/// assert_eq!(first_even(&[1, 3]), Err(Missing));
+/// ```
+#[macro_export]
+macro_rules! guard {
+    (let $pat:pat = $($rest:tt)+) => {
+        $crate::guard!(@expr [$pat] [] $($rest)+)
+    };
+    (let $($pat:pat)|+ = $($rest:tt)+) => {
+        $crate::guard!(@expr [($($pat)|+)] [] $($rest)+)
+    };
+    (@expr [$($pat:tt)*] [$($expr:tt)+] else { $($diverge:tt)* }) => {
+        let $($pat)* = ($($expr)+) else { $($diverge)* };
+    };
+    (@expr [$($pat:tt)*] [$($expr:tt)+] else $($diverge:tt)+) => {
+        let $($pat)* = ($($expr)+) else { $($diverge)+ };
+    };
+    (@expr [$($pat:tt)*] [$($expr:tt)*] $token:tt $($rest:tt)*) => {
+        $crate::guard!(@expr [$($pat)*] [$($expr)* $token] $($rest)*)
+    };
+}
+
+/// Same as [`when!`], but the arms are re-evaluated in a loop, until a branch `break`s out of it.
+///
+/// `when_loop! { ... }` is the same as `loop { when! { ... } }`, so the value passed to `break` is
+/// the value of the whole expression:
+///
+/// ```rust
+/// let mut attempts = 0;
+/// let connect = |attempt: u32| if attempt < 3 { Err("timeout") } else { Ok("connected") };
+///
+/// let status = kiam::when_loop! {
+///     attempts >= 5 => break "failed",
+///     let Ok(s) = connect(attempts) => break s,
+///     _ => attempts += 1,
+/// };
+///
+/// assert_eq!(status, "connected");
+/// assert_eq!(attempts, 3);
+/// ```
+#[macro_export]
+macro_rules! when_loop {
+    ($($arms:tt)*) => {
+        loop {
+            $crate::when! { $($arms)* }
+        }
+    };
+}
+
+/// Same as [`when!`], but executes the branches of *all* the arms which fired, in order.
+///
+/// ```rust
+/// let (x, mut log) = (15, Vec::new());
+///
+/// kiam::when_all! {
+///     x % 3 == 0 => log.push("fizz"),
+///     x % 5 == 0 => log.push("buzz"),
+///     x > 100 => log.push("big"),
+/// }
+///
+/// assert_eq!(log, ["fizz", "buzz"]);
+/// ```
+///
+/// Since every arm is checked separately, the subject form, `let` statements before the arms and
+/// a default arm are not supported.
+#[macro_export]
+macro_rules! when_all {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@all] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when_all!`], but collects the values of the branches of all the arms which fired into a
+/// `Vec`, in order.
+///
+/// ```rust
+/// let (name, age) = ("", 200);
+///
+/// let errors = kiam::collect_when! {
+///     name.is_empty() => "name is empty",
+///     name.len() > 32 => "name is too long",
+///     age > 150 => "age is too big",
+/// };
+///
+/// assert_eq!(errors, ["name is empty", "age is too big"]);
+/// ```
+///
+/// Requires the `alloc` feature of this crate. The subject form, `let` statements before the arms
+/// and a default arm are not supported.
+#[cfg(feature = "alloc")]
+#[macro_export]
+macro_rules! collect_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@collect] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but evaluates to `Some((index, branch))`, where `index` is the index of the
+/// arm which fired (starting from `0`), or to `None` if none of the arms fired.
+///
+/// ```rust
+/// let classify = |x: i32| kiam::when_index! {
+///     x < 0 => "negative",
+///     x == 0 => "zero",
+///     x < 10 => "small",
+/// };
+///
+/// assert_eq!(classify(-5), Some((0, "negative")));
+/// assert_eq!(classify(7), Some((2, "small")));
+/// assert_eq!(classify(42), None);
+/// ```
+///
+/// Since `None` is used as the default branch, a default arm (`_ =>` or `else =>`) is not allowed.
+#[macro_export]
+macro_rules! when_index {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@index] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but evaluates to the label of the arm which fired and its branch, or to
+/// `None` if none of the arms fired.
+///
+/// The label of an arm without one is its condition, as written.
+///
+/// ```rust
+/// let respond = |status: u16| {
+///     kiam::when_label! {
+///         "ok": status < 300 => 0,
+///         "redirect": status < 400 => 1,
+///         status < 500 => 2,
+///     }
+/// };
+///
+/// assert_eq!(respond(200), Some(("ok", 0)));
+/// assert_eq!(respond(301), Some(("redirect", 1)));
+/// assert_eq!(respond(404), Some(("status < 500", 2)));
+/// assert_eq!(respond(503), None);
+/// ```
+///
+/// Since `None` is used as the default branch, a default arm (`_ =>` or `else =>`) is not allowed.
+#[macro_export]
+macro_rules! when_label {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@labelled] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but also evaluates to the arm which fired.
+///
+/// Each invocation defines an enum `Which` with a variant per arm: arms labelled with an identifier
+/// (which, unlike string labels, is only supported by `when_which!`) are named after it, the
+/// default arm is named `Default` and the other arms are named `Arm0`, `Arm1` and so on, after their
+/// index. `Which::name` returns the label of the arm (or its
+/// condition, as written) and `Which::index` returns its index.
+///
+/// ```rust
+/// let respond = |status: u16| {
+///     kiam::when_which! {
+///         ok: status < 300 => 0,
+///         "redirect": status < 400 => 1,
+///         status < 500 => 2,
+///         _ => 3,
+///     }
+/// };
+///
+/// let (code, which) = respond(200);
+/// assert_eq!((code, which.name(), which.index()), (0, "ok", 0));
+///
+/// let (code, which) = respond(301);
+/// assert_eq!((code, which.name(), which.index()), (1, "redirect", 1));
+///
+/// let (code, which) = respond(404);
+/// assert_eq!((code, which.name(), which.index()), (2, "status < 500", 2));
+///
+/// let (code, which) = respond(503);
+/// assert_eq!((code, which.name(), which.index()), (3, "_", 3));
+/// ```
+///
+/// The enum is local to the expansion, so it can't be named outside of it, but its values can be
+/// compared with each other, stored and printed with `{:?}`. To name the enum (e.g. in the signature
+/// of a function or to `match` on it), define it at the item level and pass its name before the arms
+/// with `enum <name>;`, the arms then evaluate to its variants instead:
+///
+/// ```rust
+/// #[derive(Debug, PartialEq)]
+/// enum Decision {
+///     Ok,
+///     Redirect,
+///     Arm2,
+///     Default,
+/// }
+///
+/// fn respond(status: u16) -> (u8, Decision) {
+///     kiam::when_which! {
+///         enum Decision;
+///         Ok: status < 300 => 0,
+///         Redirect: status < 400 => 1,
+///         status < 500 => 2,
+///         _ => 3,
+///     }
+/// }
+///
+/// assert_eq!(respond(301), (1, Decision::Redirect));
+/// assert_eq!(respond(404), (2, Decision::Arm2));
+/// ```
+///
+/// A default arm is required and arms generated by macros are not supported.
+///
+/// This macro is only available with the `macros` feature.
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! when_which {
+    ($($tokens:tt)*) => {
+        $crate::__when_which!([$crate] $($tokens)*)
+    };
+}
+
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub use kiam_macros::when_which as __when_which;
+
+/// Same as [`when!`], but checks the arms marked with `#[cost(cheap)]` first and the arms marked with
+/// `#[cost(expensive)]` last. The order of the arms with the same cost is preserved.
+///
+/// ```rust
+/// # fn lookup_in_database(_: u32) -> bool { true }
+/// let user_id = 7;
+/// let is_admin = false;
+///
+/// let access = kiam::reorder_when! {
+///     #[cost(expensive)]
+///     lookup_in_database(user_id) => "granted by the database",
+///     #[cost(cheap)]
+///     is_admin => "granted to an admin",
+///     _ => "denied",
+/// };
+///
+/// assert_eq!(access, "granted by the database");
+/// ```
+///
+/// This changes which arm fires if the conditions of several arms hold, so the conditions should be
+/// mutually exclusive and side effect free. `#[cost(...)]` is also accepted (and ignored) by
+/// [`when!`], so the costs can be written down before opting in to the reordering. The subject form
+/// and `let` statements before the arms are not supported.
+#[macro_export]
+macro_rules! reorder_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@reorder] [] [] $($arms)*)
+    };
+}
+
+/// Defines a function whose body is arms of [`when!`], with the default branch inferred from the
+/// return type: functions returning `Option<T>` wrap the branches in `Some` and return `None` if
+/// none of the arms fired (like [`when_opt!`]), functions returning `Result<T, E>` wrap the branches
+/// in `Ok` and return `Err(E::from(NoMatch))` (like [`try_when!`]), other functions return
+/// `Default::default()`.
+///
+/// ```rust
+/// use kiam::when_fn;
+///
+/// /// Classifies a HTTP status code.
+/// #[when_fn]
+/// pub fn class(status: u16) -> std::option::Option<&'static str> {
+///     when! {
+///         status < 200 => "informational",
+///         status < 300 => "success",
+///         status < 400 => "redirection",
+///         status < 600 => "error",
+///     }
+/// }
+///
+/// #[when_fn]
+/// fn sign<T: Into<i64> + Copy>(x: T) -> i32 {
+///     when! {
+///         x.into() < 0 => -1,
+///         x.into() > 0 => 1,
+///     }
+/// }
+///
+/// assert_eq!(class(204), Some("success"));
+/// assert_eq!(class(999), None);
+/// assert_eq!(sign(-7), -1);
+/// assert_eq!(sign(0_u8), 0);
+/// ```
+///
+/// The body of a function must be valid Rust before an attribute is applied to it, so the arms are
+/// written in a `when! { ... }` invocation, which is replaced by the attribute (so `when!` doesn't
+/// need to be imported). `Option` and `Result` can be written with or without a path. Since the
+/// default branch is inferred, a default arm (`_ =>` or `else =>`) is not allowed, except for
+/// functions without a return type.
+///
+/// This attribute is only available with the `macros` feature, the crate using it must depend on
+/// this crate under the name `kiam`.
+#[cfg(feature = "macros")]
+#[doc(inline)]
+pub use kiam_macros::when_fn;
+
+/// Same as [`when!`], but only accepts the forms which expand to code valid in const contexts
+/// (`const fn`, `const` and `static` items).
+///
+/// ```rust
+/// const fn class(b: u8) -> u8 {
+///     kiam::const_when! {
+///         b;
+///         let b'0'..=b'9' => 1,
+///         let b'a'..=b'z' | b'A'..=b'Z' => 2,
+///         b' ' | b'\t' | b'\n' => 3,
+///         _ => 0,
+///     }
+/// }
+///
+/// const TABLE: [u8; 4] = [class(b'7'), class(b'q'), class(b'\t'), class(b'-')];
+///
+/// assert_eq!(TABLE, [1, 2, 3, 0]);
+/// ```
+///
+/// Boolean conditions, `let` conditions, `matches`, guards, `#[likely]`/`#[unlikely]` hints and
+/// the subject form are supported (conditions themselves still need to be const-compatible, e.g.
+/// calls must be to `const fn`s).
+/// `in`/`!in` arms (which call `contains`) and `is` arms (which use [`Any`]) are rejected with a
+/// compile error. Note that this also rejects variables named `is` outside of blocks.
+///
+/// [`Any`]: core::any::Any
+#[macro_export]
+macro_rules! const_when {
+    (@check [$($all:tt)*] in $($rest:tt)*) => {
+        $crate::const_when!(@in)
+    };
+    (@check [$($all:tt)*] $a:tt in $($rest:tt)*) => {
+        $crate::const_when!(@in)
+    };
+    (@check [$($all:tt)*] $a:tt $b:tt in $($rest:tt)*) => {
+        $crate::const_when!(@in)
+    };
+    (@check [$($all:tt)*] $a:tt $b:tt $c:tt in $($rest:tt)*) => {
+        $crate::const_when!(@in)
+    };
+    (@check [$($all:tt)*] is $($rest:tt)*) => {
+        $crate::const_when!(@is)
+    };
+    (@check [$($all:tt)*] $a:tt is $($rest:tt)*) => {
+        $crate::const_when!(@is)
+    };
+    (@check [$($all:tt)*] $a:tt $b:tt is $($rest:tt)*) => {
+        $crate::const_when!(@is)
+    };
+    (@check [$($all:tt)*] $a:tt $b:tt $c:tt is $($rest:tt)*) => {
+        $crate::const_when!(@is)
+    };
+    (@check [$($all:tt)*] $a:tt $b:tt $c:tt $d:tt $($rest:tt)*) => {
+        $crate::const_when!(@check [$($all)*] $($rest)*)
+    };
+    (@check [$($all:tt)*] $($rest:tt)*) => {
+        $crate::when! { $($all)* }
+    };
+    (@in) => {
+        ::core::compile_error!("`in` arms are not supported in const contexts, since they call `contains`")
+    };
+    (@is) => {
+        ::core::compile_error!("`is` arms are not supported in const contexts, since they use `Any`")
+    };
+    ($($tokens:tt)*) => {
+        $crate::const_when!(@check [$($tokens)*] $($tokens)*)
+    };
+}
+
+/// Selects an expression based on `#[cfg(...)]` predicates, like `cfg-if`, but in expression
+/// position.
+///
+/// Each arm consists of attributes only, the branch of the first enabled arm is used. If none of
+/// the arms is enabled, the default arm (`_ =>` or `else =>`) is used.
+///
+/// ```rust
+/// let separator = kiam::cfg_when! {
+///     #[cfg(unix)] => ':',
+///     #[cfg(windows)] => ';',
+///     _ => ',',
+/// };
+/// # let _ = separator;
+///
+/// let n = kiam::cfg_when! {
+///     #[cfg(any())] => does_not_exist(),
+///     #[cfg(all())] => 1,
+///     _ => 0,
+/// };
+///
+/// assert_eq!(n, 1);
+/// ```
+///
+/// Branches of disabled arms are removed, so they may use items which only exist with the
+/// corresponding configuration.
+#[macro_export]
+macro_rules! cfg_when {
+    (@arms [$($arms:tt)*] $(#[$attr:meta])+ => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::cfg_when!(@arms [$($arms)* $(#[$attr])+ true => $branch,] $($($rest)*)?)
+    };
+    (@arms [$($arms:tt)*] $(#[$attr:meta])+ => $branch:block $($rest:tt)*) => {
+        $crate::cfg_when!(@arms [$($arms)* $(#[$attr])+ true => $branch,] $($rest)*)
+    };
+    // `__when!` is used directly, since all the conditions are `true` (with different attributes),
+    // which `kiam_macros::when!` would report
+    (@arms [$($arms:tt)*] _ => $default:expr $(,)?) => {
+        $crate::__when! { $($arms)* _ => $default }
+    };
+    (@arms [$($arms:tt)*] else => $default:expr $(,)?) => {
+        $crate::__when! { $($arms)* _ => $default }
+    };
+    (@arms [$($arms:tt)*]) => {
+        $crate::__when! { $($arms)* }
+    };
+    (@arms [$($arms:tt)*] $($tokens:tt)+) => {
+        ::core::compile_error!("expected `#[cfg(...)] => <expr>` arms, optionally followed by a default arm")
+    };
+    ($($tokens:tt)*) => {
+        $crate::cfg_when!(@arms [] $($tokens)*)
+    };
+}
+
+/// C-style `switch`: the first arm which fires and all the following arms are executed, until a
+/// branch uses `break`.
+///
+/// The conditions of the following arms are not checked, so the execution "falls through" to them.
+/// The default arm (`_ =>` or `else =>`) is always executed, unless some branch used `break`.
+///
+/// ```rust
+/// let mut steps = Vec::new();
+/// let version = 2;
+///
+/// kiam::switch! {
+///     version == 1 => steps.push("migrate v1"),
+///     version == 2 => steps.push("migrate v2"),
+///     version == 3 => {
+///         steps.push("migrate v3");
+///         break;
+///     }
+///     // Only reached if `version` is not 1, 2 or 3
+///     _ => steps.push("unknown version"),
+/// }
+///
+/// assert_eq!(steps, ["migrate v2", "migrate v3"]);
+/// ```
+///
+/// `continue` leaves the switch too (it doesn't start it over), `break` with a value is an error.
+/// Enclosing loops can still be targeted with `break 'label` and `continue 'label`.
+///
+/// Bindings from `let` conditions can't be used in the branches, since a branch may also be
+/// reached by falling through.
+#[macro_export]
+macro_rules! switch {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@switch] [] [] $($arms)*)
+    };
+}
+
+/// Loops as long as at least one of the arms fires, i.e. the arms are re-evaluated until none of
+/// them matches.
+///
+/// ```rust
+/// let mut queue = vec![1, 12, 3];
+/// let mut done = Vec::new();
+///
+/// kiam::while_when! {
+///     let Some(&x) = queue.last(), x > 10 => {
+///         queue.pop();
+///         queue.extend([x / 10, x % 10]);
+///     }
+///     let Some(x) = queue.pop() => done.push(x),
+/// }
+///
+/// assert_eq!(done, [3, 2, 1, 1]);
+/// ```
+///
+/// Since the loop is exited when no arm fires, a default arm (`_ =>` or `else =>`) is not allowed.
+#[macro_export]
+macro_rules! while_when {
+    ($($arms:tt)*) => {
+        loop {
+            $crate::__when_wrap!([] [break] [] [] $($arms)*)
+        }
+    };
+}
+
+/// Returns whether the value matches any of the patterns, like [`matches!`], but the patterns are
+/// independent and each of them may have its own guard.
+///
+/// ```rust
+/// let interesting = |x: Option<i32>| kiam::matches_any!(x, None, Some(0), Some(n) if n > 100);
+///
+/// assert!(interesting(None));
+/// assert!(interesting(Some(0)));
+/// assert!(interesting(Some(101)));
+/// assert!(!interesting(Some(5)));
+/// ```
+///
+/// Unlike `|`-patterns, the patterns don't need to bind the same variables.
+#[macro_export]
+macro_rules! matches_any {
+    ($value:expr, $($pat:pat $(if $guard:expr)?),+ $(,)?) => {
+        match $value {
+            $(
+                #[allow(unreachable_patterns)]
+                $pat $(if $guard)? => true,
+            )+
+            _ => false,
+        }
+    };
+}
+
+/// Dispatches on bit flags: an arm fires if all the bits of the flag are set in the value.
+/// Flags separated by `|` are alternatives, i.e. an arm fires if any of them is set.
+///
+/// ```rust
+/// const READ: u8 = 1 << 0;
+/// const WRITE: u8 = 1 << 1;
+/// const APPEND: u8 = 1 << 2;
+///
+/// let mode = |flags: u8| kiam::when_flags! {
+///     flags;
+///     READ => "read",
+///     WRITE | APPEND => "write",
+///     _ => "none",
+/// };
+///
+/// assert_eq!(mode(READ | WRITE), "read");
+/// assert_eq!(mode(APPEND), "write");
+/// assert_eq!(mode(0), "none");
+/// ```
+///
+/// The value is evaluated once and must be `Copy`. Flags must be paths (e.g. constants like
+/// `Flags::READ`) and, like the value, support `&` and `==`, so both integers and types generated
+/// by crates like `bitflags` can be used.
+#[macro_export]
+macro_rules! when_flags {
+    (@arms $flags:ident [$($arms:tt)*] _ => $default:expr $(,)?) => {
+        $crate::when! { $($arms)* _ => $default }
+    };
+    (@arms $flags:ident [$($arms:tt)*] else => $default:expr $(,)?) => {
+        $crate::when! { $($arms)* _ => $default }
+    };
+    (@arms $flags:ident [$($arms:tt)*]) => {
+        $crate::when! { $($arms)* }
+    };
+    (@arms $flags:ident [$($arms:tt)*] $($flag:path)|+ => $branch:block $($rest:tt)*) => {
+        $crate::when_flags!(
+            @arms $flags [$($arms)* $(($flags & $flag) == $flag)||+ => $branch,] $($rest)*
+        )
+    };
+    (@arms $flags:ident [$($arms:tt)*] $($flag:path)|+ => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::when_flags!(
+            @arms $flags [$($arms)* $(($flags & $flag) == $flag)||+ => $branch,] $($($rest)*)?
+        )
+    };
+    ($value:expr; $($arms:tt)*) => {
+        match $value {
+            flags => $crate::when_flags!(@arms flags [] $($arms)*),
+        }
+    };
+}
+
+/// Dispatches on a string, lowering to a real `match` on `&str`, which is usually faster than a
+/// chain of `==` comparisons when there are many alternatives.
+///
+/// The value can be anything implementing `AsRef<str>` (`&str`, `String`, ...), the arms are
+/// ordinary `match` arms, so patterns can be combined with `|` and have guards:
+///
+/// ```rust
+/// let command = String::from("rm");
+/// let force = true;
+///
+/// let action = kiam::when_str! {
+///     command;
+///     "ls" | "dir" => "list",
+///     "rm" | "del" if force => "remove",
+///     "rm" | "del" => "ask",
+///     _ => "unknown",
+/// };
+///
+/// assert_eq!(action, "remove");
+/// ```
+///
+/// The arms are passed to `match` as is, so, like in `match`, they must be exhaustive, i.e. end
+/// with a `_` arm. The `else =>` default of [`when!`] is not accepted.
+#[macro_export]
+macro_rules! when_str {
+    ($value:expr; $($arms:tt)*) => {
+        match ::core::convert::AsRef::<::core::primitive::str>::as_ref(&$value) {
+            $($arms)*
+        }
+    };
+}
+
+/// Same as [`when!`], but, like [`dbg!`], prints the location, the condition (or the label) of the
+/// arm which fired and the resulting value to stderr, then returns the value.
+///
+/// ```rust
+/// let x = 7;
+///
+/// let size = kiam::dbg_when! {
+///     x < 5 => "small",
+///     x < 10 => "medium",
+///     _ => "large",
+/// };
+/// // Prints `[src/main.rs:3] x < 10 => "medium"`
+///
+/// assert_eq!(size, "medium");
+/// ```
+///
+/// If there is no default arm (`_ =>` or `else =>`) and none of the arms fired, this is printed
+/// too. The values returned by the branches must implement `Debug`.
+///
+/// This macro requires `std`. The subject form and `let` statements before the arms are not
+/// supported.
+///
+/// [`dbg!`]: https://doc.rust-lang.org/std/macro.dbg.html
+#[macro_export]
+macro_rules! dbg_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@dbg] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but emits a [`tracing`] event when an arm fires, with the label (or the
+/// condition) of the arm and its index as fields. The location of the invocation is recorded in the
+/// metadata of the event.
+///
+/// Requires the `tracing` feature of this crate.
+///
+/// ```rust
+/// let x = 7;
+///
+/// let size = kiam::traced_when! {
+///     "small": x < 5 => "small",
+///     "medium": x < 10 => "medium",
+///     _ => "large",
+/// };
+/// // Emits `TRACE example: `when!` arm taken arm="medium" index=1`
+///
+/// assert_eq!(size, "medium");
+/// ```
+///
+/// The events are emitted at the `TRACE` level, another level can be specified before the arms,
+/// e.g. `traced_when! { DEBUG; ... }`. The subject form and `let` statements before the arms are
+/// not supported.
+///
+/// [`tracing`]: https://docs.rs/tracing
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! traced_when {
+    ($level:ident; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@traced tracing $level] [] [] $($arms)*)
+    };
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@traced tracing TRACE] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but logs a message with the [`log`] facade when an arm fires, with the
+/// location of the invocation, the label (or the condition) of the arm and its index.
+///
+/// Requires the `log` feature of this crate.
+///
+/// ```rust
+/// let x = 7;
+///
+/// let size = kiam::log_when! {
+///     "small": x < 5 => "small",
+///     "medium": x < 10 => "medium",
+///     _ => "large",
+/// };
+/// // Logs `[src/main.rs:3] `when!` arm taken: medium (#1)`
+///
+/// assert_eq!(size, "medium");
+/// ```
+///
+/// The messages are logged at the `Trace` level, another level can be specified before the arms,
+/// e.g. `log_when! { Debug; ... }`. The subject form and `let` statements before the arms are not
+/// supported.
+///
+/// [`log`]: https://docs.rs/log
+#[cfg(feature = "log")]
+#[macro_export]
+macro_rules! log_when {
+    ($level:ident; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@traced log $level] [] [] $($arms)*)
+    };
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@traced log Trace] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but logs a message with [`defmt`] when an arm fires, with the label (or the
+/// condition) of the arm and its index. For `no_std` firmware, where `log_when!` and
+/// `core::fmt` are too expensive.
+///
+/// Requires the `defmt` feature of this crate. Like with all `defmt` macros, a global logger must be
+/// defined in the final binary.
+///
+/// ```rust,ignore
+/// let size = kiam::defmt_when! {
+///     "small": x < 5 => Size::Small,
+///     "medium": x < 10 => Size::Medium,
+///     _ => Size::Large,
+/// };
+/// // Logs `when!` arm taken: medium (#1)
+/// ```
+///
+/// The format string is interned, like with all `defmt` macros, the label is sent as a `str`. The
+/// location is recorded by `defmt` and is shown by the host tools. The messages are logged with
+/// `defmt::trace!`, another level can be specified before the arms, e.g.
+/// `defmt_when! { debug; ... }`. The subject form and `let` statements before the arms are not
+/// supported.
+///
+/// [`defmt`]: https://docs.rs/defmt
+#[cfg(feature = "defmt")]
+#[macro_export]
+macro_rules! defmt_when {
+    ($level:ident; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@traced defmt $level] [] [] $($arms)*)
+    };
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@traced defmt trace] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but counts how many times each arm fired, see [`coverage`].
+///
+/// ```rust
+/// let sign = |x: i32| kiam::covered_when! {
+///     "negative": x < 0 => -1,
+///     "positive": x > 0 => 1,
+///     _ => 0,
+/// };
+///
+/// assert_eq!(sign(-3), -1);
+/// ```
+///
+/// Requires the `coverage` feature of this crate and `std`. The subject form and `let` statements
+/// before the arms are not supported.
+#[cfg(feature = "coverage")]
+#[macro_export]
+macro_rules! covered_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@covered] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but also evaluates to an [`Explanation`] of the decision: which conditions
+/// were checked, what they evaluated to and which arm fired.
+///
+/// ```rust
+/// let (is_vip, total) = (false, 250);
+///
+/// let (fee, why) = kiam::explain_when! {
+///     "vip": is_vip => 0,
+///     "large order": total > 100 => 5,
+///     _ => 10,
+/// };
+///
+/// assert_eq!(fee, 5);
+/// assert_eq!(why.fired(), Some("large order"));
+/// assert_eq!(why.to_string(), "vip: false\nlarge order: true\n");
+/// ```
+///
+/// The conditions are shown as their labels or as written, an arm with several conditions
+/// (separated by `,` or `or`) is reported as a whole. The subject form and `let` statements before
+/// the arms are not supported.
+#[macro_export]
+macro_rules! explain_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@explain] [] [] $($arms)*)
+    };
+}
+
+/// Builds a [`StaticRuleSet`](rules::StaticRuleSet) from arms with the syntax of [`when!`], so the
+/// rules can be stored in a `static` or a `const` without a heap.
+///
+/// The arms are preceded by the signature of the rules, `|<name>: &<context>| -> <output>;`, the
+/// context can be used in the conditions and the branches under `<name>`:
+///
+/// ```rust
+/// use kiam::rules::StaticRuleSet;
+///
+/// static STATUSES: StaticRuleSet<u16, &str> = kiam::static_rules! {
+///     |status: &u16| -> &'static str;
+///     *status < 300 => "ok",
+///     let 301 | 308 = *status => "moved permanently",
+///     *status < 400 => "redirect",
+///     _ => "error",
+/// };
+///
+/// assert_eq!(STATUSES.evaluate(&204), Some("ok"));
+/// assert_eq!(STATUSES.evaluate(&308), Some("moved permanently"));
+/// assert_eq!(STATUSES.evaluate(&404), Some("error"));
+/// ```
+///
+/// Every arm becomes a rule with a predicate and an action, the default arm becomes the default
+/// action. Since the predicate and the action are separate functions, the bindings of `let`
+/// conditions can't be used in the branches. The subject form and `let` statements before the arms
+/// are not supported.
+#[macro_export]
+macro_rules! static_rules {
+    (|$ctx:ident: &$ty:ty| -> $out:ty; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@static [$ctx] [$ty] [$out]] [] [] $($arms)*)
+    };
+}
+
+/// Builds a [`RuleSet`](rules::RuleSet) from arms with the syntax of [`when!`], whose branches are
+/// [`Handler`](rules::Handler)s (e.g. `Box<dyn Handler<_, _>>`), so that more handlers can be
+/// registered at runtime, e.g. by plugins.
+///
+/// The arms are preceded by the signature of the handlers, `|<name>: &<context>| -> <output>;`, the
+/// context can be used in the conditions under `<name>`:
+///
+/// ```rust
+/// use kiam::rules::Handler;
+///
+/// struct Api;
+/// struct NotFound;
+///
+/// impl Handler<str, u16> for Api {
+///     fn handle(&self, _: &str) -> u16 {
+///         200
+///     }
+/// }
+///
+/// impl Handler<str, u16> for NotFound {
+///     fn handle(&self, _: &str) -> u16 {
+///         404
+///     }
+/// }
+///
+/// let mut registry = kiam::handlers! {
+///     |path: &str| -> u16;
+///     path.starts_with("/api/") => Api,
+///     _ => NotFound,
+/// };
+///
+/// // A plugin contributes a handler at runtime
+/// struct Health;
+///
+/// impl Handler<str, u16> for Health {
+///     fn handle(&self, _: &str) -> u16 {
+///         204
+///     }
+/// }
+///
+/// let plugin: Box<dyn Handler<str, u16>> = Box::new(Health);
+/// registry.push_handler(|path| path == "/health", plugin);
+///
+/// assert_eq!(registry.evaluate("/api/users"), Some(200));
+/// assert_eq!(registry.evaluate("/health"), Some(204));
+/// assert_eq!(registry.evaluate("/"), Some(404));
+/// ```
+///
+/// The handlers are evaluated once, when the rule set is built, and the first arm whose condition
+/// holds decides which handler is used. The conditions are `move` closures, so they can only borrow
+/// data which lives for `'static`. The bindings of `let` conditions can't be used in the handlers.
+/// Requires the `alloc` feature of this crate. The subject form and `let` statements before the
+/// arms are not supported.
+#[cfg(feature = "alloc")]
+#[macro_export]
+macro_rules! handlers {
+    (|$ctx:ident: &$ty:ty| -> $out:ty; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@handlers [$ctx] [$ty] [$out]] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but wraps the results of the branches into [`Either`], so the branches may
+/// have different types.
+///
+/// The branch of the first arm is wrapped in `Left`, the branch of the second arm in
+/// `Right(Left(...))` and so on, the default branch is wrapped only in `Right`s. E.g. with a single
+/// arm and a default arm the type is `Either<A, B>`, with two arms it's `Either<A, Either<B, C>>`.
+/// The default arm (`_ =>` or `else =>`) is required.
+///
+/// ```rust
+/// let v = [1, 2, 3];
+/// let reverse = true;
+///
+/// // `Either` implements `Iterator` if both sides do
+/// let iter = kiam::when_either! {
+///     reverse => v.iter().rev(),
+///     _ => v.iter(),
+/// };
+///
+/// assert_eq!(iter.copied().collect::<Vec<_>>(), [3, 2, 1]);
+/// ```
+///
+/// Requires the `either` feature, which enables the [`either`] dependency. The subject form and
+/// `let` statements before the arms are not supported.
+///
+/// [`Either`]: https://docs.rs/either/1/either/enum.Either.html
+/// [`either`]: https://docs.rs/either
+#[cfg(feature = "either")]
+#[macro_export]
+macro_rules! when_either {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@either $crate::__private::either::Either] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but wraps the results of the branches into variants of an anonymous enum,
+/// which implements the listed traits, so the branches may have different types.
+///
+/// ```rust
+/// use std::fmt::Display;
+///
+/// fn numbers(desc: bool, limit: Option<u32>) -> impl Iterator<Item = u32> {
+///     kiam::when_enum! {
+///         impl Iterator;
+///         desc => (0..5).rev(),
+///         let Some(n) = limit => (0..5).take(n as usize),
+///         _ => 0..5,
+///     }
+/// }
+///
+/// fn describe(x: i32) -> impl Display {
+///     kiam::when_enum! {
+///         impl Display, Debug;
+///         x < 0 => "negative",
+///         _ => x,
+///     }
+/// }
+///
+/// assert_eq!(numbers(true, None).collect::<Vec<_>>(), [4, 3, 2, 1, 0]);
+/// assert_eq!(numbers(false, Some(2)).collect::<Vec<_>>(), [0, 1]);
+/// assert_eq!(describe(-1).to_string(), "negative");
+/// assert_eq!(describe(3).to_string(), "3");
+/// ```
+///
+/// Supported traits are `Iterator` (all the branches must have the same `Item`), `Future` (all the
+/// branches must have the same `Output` and be [`Unpin`], e.g. boxed with `Box::pin`), `Display`
+/// and `Debug`. The default arm (`_ =>` or `else =>`) is required. Since `macro_rules!` can't
+/// create new identifiers, the variants are taken from a fixed list, so there can be at most 16
+/// arms, including the default arm. The subject form and `let` statements before the arms are not
+/// supported.
+#[macro_export]
+macro_rules! when_enum {
+    (impl $($traits:ident),+; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@enum $($traits),+] [] [] $($arms)*)
+    };
+}
+
+// Builds the anonymous enum for `when_enum!`. Variants and their type parameters are taken from the
+// fixed list below, since `macro_rules!` can't create new identifiers.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __when_enum {
+    (@zip [$($traits:tt)*] [$($zipped:tt)*] [$($arms:tt)*] $value:tt) => {
+        $crate::__when_enum!(
+            @zip [$($traits)*]
+            [
+                V0 T0 V1 T1 V2 T2 V3 T3 V4 T4 V5 T5 V6 T6 V7 T7
+                V8 T8 V9 T9 V10 T10 V11 T11 V12 T12 V13 T13 V14 T14 V15 T15
+            ]
+            [$($zipped)*] [$($arms)*] $value
+        )
+    };
+    (@zip [$($traits:tt)*] [$v:ident $t:ident $($pool:tt)*] [$($zipped:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] $value:tt) => {
+        $crate::__when_enum!(@zip [$($traits)*] [$($pool)*] [$($zipped)* [$v $t]] [$($arms)*] $value)
+    };
+    (@zip [$($traits:tt)*] [] [$($zipped:tt)*] [$($arms:tt)+] $value:tt) => {
+        ::core::compile_error!("`when_enum!` supports at most 16 arms, including the default arm")
+    };
+    // The branches are wrapped into nested `Either`s and evaluated before the enum is defined, so
+    // that its name doesn't shadow anything the branches use
+    (@zip [$($traits:tt)*] [$($pool:tt)*] [$([$v:ident $t:ident])*] [] [$($value:tt)*]) => {{
+        let value = $($value)*;
+
+        {
+            enum WhenEnum<$($t),*> {
+                $($v($t),)*
+            }
+
+            $crate::__when_enum!(@impls [$($traits)*] [$($v $t)*]);
+
+            $crate::__when_enum!(@convert value [$($v)*])
+        }
+    }};
+    (@convert $value:ident [$v:ident]) => {
+        WhenEnum::$v($value)
+    };
+    (@convert $value:ident [$v:ident $($vs:ident)+]) => {
+        match $value {
+            $crate::__private::Either::Left(value) => WhenEnum::$v(value),
+            $crate::__private::Either::Right(value) => $crate::__when_enum!(@convert value [$($vs)+]),
+        }
+    };
+    (@impls [$trait:ident $(, $traits:ident)*] $variants:tt) => {
+        $crate::__when_enum!(@impl $trait $variants);
+        $crate::__when_enum!(@impls [$($traits),*] $variants);
+    };
+    (@impls [] $variants:tt) => {};
+    (@impl Iterator [$($v:ident $t:ident)*]) => {
+        impl<Item, $($t: ::core::iter::Iterator<Item = Item>),*> ::core::iter::Iterator for WhenEnum<$($t),*> {
+            type Item = Item;
+
+            fn next(&mut self) -> ::core::option::Option<Item> {
+                match self {
+                    $(WhenEnum::$v(inner) => inner.next(),)*
+                }
+            }
+
+            fn size_hint(&self) -> (usize, ::core::option::Option<usize>) {
+                match self {
+                    $(WhenEnum::$v(inner) => inner.size_hint(),)*
+                }
+            }
+        }
+    };
+    (@impl Display [$($v:ident $t:ident)*]) => {
+        impl<$($t: ::core::fmt::Display),*> ::core::fmt::Display for WhenEnum<$($t),*> {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                match self {
+                    $(WhenEnum::$v(inner) => ::core::fmt::Display::fmt(inner, f),)*
+                }
+            }
+        }
+    };
+    (@impl Debug [$($v:ident $t:ident)*]) => {
+        impl<$($t: ::core::fmt::Debug),*> ::core::fmt::Debug for WhenEnum<$($t),*> {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                match self {
+                    $(WhenEnum::$v(inner) => ::core::fmt::Debug::fmt(inner, f),)*
+                }
+            }
+        }
+    };
+    (@impl Future [$($v:ident $t:ident)*]) => {
+        impl<Output, $($t: ::core::future::Future<Output = Output> + ::core::marker::Unpin),*>
+            ::core::future::Future for WhenEnum<$($t),*>
+        {
+            type Output = Output;
+
+            fn poll(
+                self: ::core::pin::Pin<&mut Self>,
+                cx: &mut ::core::task::Context<'_>,
+            ) -> ::core::task::Poll<Output> {
+                match self.get_mut() {
+                    $(WhenEnum::$v(inner) => ::core::pin::Pin::new(inner).poll(cx),)*
+                }
+            }
+        }
+    };
+    (@impl $trait:ident $variants:tt) => {
+        ::core::compile_error!(::core::concat!(
+            "`when_enum!` can't implement `",
+            ::core::stringify!($trait),
+            "`, supported traits are `Iterator`, `Future`, `Display` and `Debug`",
+        ));
+    };
+}
+
+/// Same as [`when!`], but boxes and pins the futures returned by the branches, so the branches may
+/// return futures of different types (e.g. different `async` blocks or `async fn`s) with the same
+/// output.
+///
+/// ```rust
+/// # async fn example() {
+/// async fn from_cache(key: u32) -> Option<String> {
+///     None
+/// }
+///
+/// async fn from_db(key: u32) -> Option<String> {
+///     Some(key.to_string())
+/// }
+///
+/// let (key, cached) = (7, false);
+/// let value = kiam::when_boxed! {
+///     key == 0 => async { None },
+///     cached => from_cache(key),
+///     _ => from_db(key),
+/// }
+/// .await;
+///
+/// assert_eq!(value.as_deref(), Some("7"));
+/// # }
+/// ```
+///
+/// Evaluates to a [`BoxFuture`](rules::BoxFuture), i.e. `Pin<Box<dyn Future<Output = T> + '_>>`,
+/// which isn't `Send`. Requires the `alloc` feature of this crate. The default arm (`_ =>` or
+/// `else =>`) is required. The subject form and `let` statements before the arms are not
+/// supported.
+#[cfg(feature = "alloc")]
+#[macro_export]
+macro_rules! when_boxed {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@boxed] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but wraps the result of the branch in [`ControlFlow::Break`] and returns
+/// [`ControlFlow::Continue(())`] if none of the arms matched. Useful in `try_fold` and visitor
+/// callbacks.
+///
+/// ```rust
+/// use core::ops::ControlFlow;
+///
+/// let first_big = [1, 5, 12, 3, 20].iter().try_for_each(|&x| kiam::when_cf! {
+///     x > 10 => x,
+/// });
+///
+/// assert_eq!(first_big, ControlFlow::Break(12));
+/// ```
+///
+/// Since `ControlFlow::Continue(())` is used as the default branch, a default arm (`_ =>` or
+/// `else =>`) is not allowed.
+///
+/// [`ControlFlow::Break`]: core::ops::ControlFlow::Break
+/// [`ControlFlow::Continue(())`]: core::ops::ControlFlow::Continue
+#[macro_export]
+macro_rules! when_cf {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!(
+            [::core::ops::ControlFlow::Break] [::core::ops::ControlFlow::Continue(())] [] []
+            $($arms)*
+        )
+    };
+}
+
+/// Evaluates the arms up to `attempts` times (but at least once), until one of them fires. If none
+/// of the arms fired during the last attempt, the default branch (`_ =>` or `else =>`) is used.
+///
+/// Optionally, a backoff closure can be passed after the number of attempts, it's called between
+/// the attempts with the number of failed attempts so far (starting from `1`):
+///
+/// ```rust
+/// let mut polls = 0;
+/// let mut poll = || {
+///     polls += 1;
+///     if polls >= 3 { Some("ready") } else { None }
+/// };
+///
+/// let mut waited = Vec::new();
+/// let status = kiam::retry_when! {
+///     5, |attempt| waited.push(attempt * 10);
+///     let Some(s) = poll() => s,
+///     _ => "timed out",
+/// };
+///
+/// assert_eq!(status, "ready");
+/// assert_eq!(waited, [10, 20]);
+///
+/// let status = kiam::retry_when! {
+///     2;
+///     false => "never",
+///     _ => "timed out",
+/// };
+///
+/// assert_eq!(status, "timed out");
+/// ```
+///
+/// Without a default arm all the branches must evaluate to `()`. The subject form and `let`
+/// statements before the arms are not supported.
+#[macro_export]
+macro_rules! retry_when {
+    ($attempts:expr, $backoff:expr; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@retry [$attempts] [$backoff]] [] [] $($arms)*)
+    };
+    ($attempts:expr; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@retry [$attempts] [|_: usize| ()]] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but returns [`Poll::Pending`] from the enclosing function if none of the arms
+/// matched. Useful in hand-written `poll` functions.
+///
+/// ```rust
+/// use core::{future::Future, pin::Pin, task::{Context, Poll}};
+///
+/// struct Both<A, B>(A, Option<u32>, B);
+///
+/// impl<A: Future<Output = u32> + Unpin, B: Future<Output = u32> + Unpin> Future for Both<A, B> {
+///     type Output = u32;
+///
+///     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+///         let a = kiam::poll_when! {
+///             let Some(a) = self.1 => a,
+///             let Poll::Ready(a) = Pin::new(&mut self.0).poll(cx) => a,
+///         };
+///         self.1 = Some(a);
+///
+///         let b = kiam::poll_when! {
+///             let Poll::Ready(b) = Pin::new(&mut self.2).poll(cx) => b,
+///         };
+///
+///         Poll::Ready(a + b)
+///     }
+/// }
+/// ```
+///
+/// Since returning `Poll::Pending` is used as the default branch, a default arm (`_ =>` or
+/// `else =>`) is not allowed.
+///
+/// [`Poll::Pending`]: core::task::Poll::Pending
+#[macro_export]
+macro_rules! poll_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [return ::core::task::Poll::Pending] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but the conditions are futures, which are awaited lazily, in order, until
+/// one of them resolves to `true` (or its output matches the pattern, for `let` arms).
+///
+/// ```rust
+/// # async fn example() {
+/// async fn cached(key: u32) -> Option<&'static str> { None }
+/// async fn is_known(key: u32) -> bool { key < 10 }
+///
+/// let key = 5;
+/// let value = kiam::when_async! {
+///     let Some(value) = cached(key) => value,
+///     is_known(key) => "known",
+///     _ => "unknown",
+/// };
+///
+/// assert_eq!(value, "known");
+/// # }
+/// ```
+///
+/// Each arm consists of a single future (`<future> =>` or `let <pat> = <future> =>`), the
+/// `,`/`or` combinations and guards of [`when!`] are not supported. Those can still be used in
+/// [`when!`] inside `async` code by writing the `.await`s explicitly.
+#[macro_export]
+macro_rules! when_async {
+    (@arms [$($arms:tt)*] _ => $default:expr $(,)?) => {
+        $crate::when! { $($arms)* _ => $default }
+    };
+    (@arms [$($arms:tt)*] else => $default:expr $(,)?) => {
+        $crate::when! { $($arms)* _ => $default }
+    };
+    (@arms [$($arms:tt)*]) => {
+        $crate::when! { $($arms)* }
+    };
+    (@arms [$($arms:tt)*] let $($pat:pat)|+ = $future:expr => $branch:block $($rest:tt)*) => {
+        $crate::when_async!(@arms [$($arms)* let $($pat)|+ = $future.await => $branch,] $($rest)*)
+    };
+    (@arms [$($arms:tt)*] let $($pat:pat)|+ = $future:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::when_async!(@arms [$($arms)* let $($pat)|+ = $future.await => $branch,] $($($rest)*)?)
+    };
+    (@arms [$($arms:tt)*] $future:expr => $branch:block $($rest:tt)*) => {
+        $crate::when_async!(@arms [$($arms)* $future.await => $branch,] $($rest)*)
+    };
+    (@arms [$($arms:tt)*] $future:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::when_async!(@arms [$($arms)* $future.await => $branch,] $($($rest)*)?)
+    };
+    ($($arms:tt)*) => {
+        $crate::when_async!(@arms [] $($arms)*)
+    };
+}
+
+/// Races futures: polls all of them and executes the branch of the first one which completes with
+/// an output matching its pattern. Runtime-agnostic, must be used in `async` code.
+///
+/// ```rust
+/// # async fn example() {
+/// # async fn fetch() -> Result<&'static str, ()> { Ok("data") }
+/// # async fn timeout() {}
+/// let result = kiam::select_when! {
+///     let Ok(data) = fetch() => data,
+///     let () = timeout() => "timed out",
+/// };
+/// # let _ = result;
+/// # }
+/// ```
+///
+/// If an output doesn't match the pattern, the remaining futures continue the race. If there is a
+/// default arm (`_ =>` or `else =>`), its branch is used when none of the futures completed (with
+/// a matching output) immediately, so the `select_when!` never waits. Without a default arm, a
+/// panic is raised if all the futures completed and none of the outputs matched.
+///
+/// `after <duration> =>` arms fire if none of the other arms did before the `duration` (a
+/// [`core::time::Duration`]) elapsed. Since there is no timer in `core`, the arms must be preceded
+/// by `timer = <timer>;`, where `<timer>` implements [`Timer`]:
+///
+/// ```rust
+/// use core::time::Duration;
+///
+/// # struct Never;
+/// # impl kiam::Timer for Never {
+/// #     type Sleep = core::future::Pending<()>;
+/// #     fn sleep(&self, _: Duration) -> Self::Sleep { core::future::pending() }
+/// # }
+/// # async fn example() {
+/// # async fn fetch() -> Result<&'static str, ()> { Ok("data") }
+/// # let my_timer = Never;
+/// let result = kiam::select_when! {
+///     timer = my_timer;
+///     let Ok(data) = fetch() => data,
+///     after Duration::from_secs(5) => "timed out",
+/// };
+/// # let _ = result;
+/// # }
+/// ```
+///
+/// By default the futures are polled fairly: every time the `select_when!` is polled, the polling
+/// starts with the next arm, so that an arm whose future is always ready can't starve the others.
+/// The rotation also continues across evaluations, each evaluation starts with the arm after the
+/// one the previous evaluation of the same `select_when!` started with.
+/// With `biased;` before the arms (and before or after `timer = <timer>;`), the futures are always
+/// polled in the order of the arms, which gives earlier arms priority:
+///
+/// ```rust
+/// # async fn example() {
+/// # async fn shutdown() {}
+/// # async fn next_job() -> Option<u32> { None }
+/// let job = kiam::select_when! {
+///     biased;
+///     let () = shutdown() => None,
+///     let Some(job) = next_job() => Some(job),
+/// };
+/// # let _ = job;
+/// # }
+/// ```
+///
+/// Branches are executed after the race, like with `futures::select!`, so they can use `.await`,
+/// `return` and `?`. `break` and `continue` in the branches need a label (`break 'outer`).
+#[macro_export]
+macro_rules! select_when {
+    (@arms [$($opts:tt)*] [$($arms:tt)*] _ => $default:expr $(,)?) => {
+        $crate::select_when!(@pin [$($opts)*] [0] [] [$($arms)*] [$default])
+    };
+    (@arms [$($opts:tt)*] [$($arms:tt)*] else => $default:expr $(,)?) => {
+        $crate::select_when!(@pin [$($opts)*] [0] [] [$($arms)*] [$default])
+    };
+    (@arms [$($opts:tt)*] [$($arms:tt)*]) => {
+        $crate::select_when!(@pin [$($opts)*] [0] [] [$($arms)*] [])
+    };
+    (@arms [$($opts:tt)*] [$($arms:tt)*] let $($pat:pat)|+ = $future:expr => $branch:block $($rest:tt)*) => {
+        $crate::select_when!(@arms [$($opts)*] [$($arms)* [$($pat)|+] [$future] [$branch]] $($rest)*)
+    };
+    (@arms [$($opts:tt)*] [$($arms:tt)*] let $($pat:pat)|+ = $future:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::select_when!(@arms [$($opts)*] [$($arms)* [$($pat)|+] [$future] [$branch]] $($($rest)*)?)
+    };
+    (@arms [$($opts:tt)*] [$($arms:tt)*] after $duration:expr => $branch:block $($rest:tt)*) => {
+        $crate::select_when!(
+            @arms [$($opts)*]
+            [$($arms)* [()] [$crate::select_when!(@sleep [$($opts)*] $duration)] [$branch]]
+            $($rest)*
+        )
+    };
+    (@arms [$($opts:tt)*] [$($arms:tt)*] after $duration:expr => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::select_when!(
+            @arms [$($opts)*]
+            [$($arms)* [()] [$crate::select_when!(@sleep [$($opts)*] $duration)] [$branch]]
+            $($($rest)*)?
+        )
+    };
+    (@sleep [$order:ident $timer:ident] $duration:expr) => {
+        $crate::Timer::sleep(&$timer, $duration)
+    };
+    (@sleep [$order:ident] $duration:expr) => {
+        ::core::compile_error!("`after` arms of `select_when!` need a timer, add `timer = <timer>;` before the arms")
+    };
+    // Every level introduces its own `future`, `done` and `output` variables, hygiene keeps them
+    // apart
+    (@pin $opts:tt [$($n:tt)*] [$($pinned:tt)*] [[$($pat:tt)*] [$future:expr] [$branch:expr] $($arms:tt)*] [$($default:tt)*]) => {{
+        let mut future = ::core::pin::pin!($future);
+        #[allow(unused_mut, unused_assignments)]
+        let mut done = false;
+        let mut output = ::core::option::Option::None;
+        $crate::select_when!(
+            @pin $opts [$($n)* + 1] [$($pinned)* [future done output [$($n)*] [$($pat)*] [$branch]]] [$($arms)*]
+            [$($default)*]
+        )
+    }};
+    (@pin [$order:ident $($timer:ident)?] [$($count:tt)*] [$([$future:ident $done:ident $output:ident [$($n:tt)*] [$($pat:tt)*] [$branch:expr]])*] [] [$($default:tt)*]) => {{
+        // The arm which is polled first by the next poll, only used in the fair mode
+        #[allow(unused_mut, unused_variables)]
+        let mut next: ::core::primitive::usize = $crate::select_when!(@first $order [$($count)*]);
+
+        // The branches are executed outside of the closure passed to `poll_fn`, so they can move
+        // captured values and use `.await`, `?` and `return`. They are wrapped in labeled blocks,
+        // so that `break` and `continue` without a label don't silently target the `'race` loop
+        #[allow(unused_labels)]
+        let result = 'race: loop {
+            // The index of the arm whose future completed (its output is put in `output`), or
+            // `None` if the default arm should be used
+            let winner: ::core::option::Option<::core::primitive::usize> =
+                ::core::future::poll_fn(|cx| {
+                    let start: ::core::primitive::usize =
+                        $crate::select_when!(@start $order next [$($count)*]);
+
+                    // The arms from `start` to the end are polled in the first pass, the rest in
+                    // the second
+                    for pass in 0..2 {
+                        $(
+                            if !$done && (($($n)*) >= start) == (pass == 0) {
+                                if let ::core::task::Poll::Ready(value) = ::core::future::Future::poll($future.as_mut(), cx) {
+                                    $done = true;
+                                    $output = ::core::option::Option::Some(value);
+                                    return ::core::task::Poll::Ready(::core::option::Option::Some($($n)*));
+                                }
+                            }
+                        )*
+                    }
+
+                    $crate::select_when!(@fallback [$($done)*] [$($default)*])
+                })
+                .await;
+
+            $(
+                if winner == ::core::option::Option::Some($($n)*) {
+                    // An output which doesn't match the pattern is dropped and the race continues
+                    #[allow(unreachable_patterns)]
+                    match $output.take() {
+                        ::core::option::Option::Some($($pat)*) => break 'race 'arm: { $branch },
+                        _ => {}
+                    }
+                }
+            )*
+
+            $crate::select_when!(@default 'race winner [$($default)*]);
+        };
+
+        result
+    }};
+    // The index of the arm which is polled first by an evaluation, in the fair mode it's the one
+    // after the arm the previous evaluation at the same call site started with
+    (@first biased [$($count:tt)*]) => {
+        0
+    };
+    (@first fair [$($count:tt)*]) => {{
+        static START: ::core::sync::atomic::AtomicUsize = ::core::sync::atomic::AtomicUsize::new(0);
+
+        // `load` and `store` rather than `fetch_add`, which isn't available on all targets.
+        // Concurrent evaluations may start with the same arm, which is fine
+        let start = START.load(::core::sync::atomic::Ordering::Relaxed);
+        let start = if start < $($count)* { start } else { 0 };
+        START.store(start + 1, ::core::sync::atomic::Ordering::Relaxed);
+        start
+    }};
+    // The index of the arm which is polled first by a poll
+    (@start biased $next:ident [$($count:tt)*]) => {
+        0
+    };
+    (@start fair $next:ident [$($count:tt)*]) => {{
+        let start = $next;
+        $next = if start + 1 < $($count)* { start + 1 } else { 0 };
+        start
+    }};
+    (@fallback [$($done:ident)*] [$default:expr]) => {
+        ::core::task::Poll::Ready(::core::option::Option::None)
+    };
+    (@fallback [$($done:ident)*] []) => {{
+        if true $(&& $done)* {
+            ::core::panic!("all the futures of `select_when!` completed, but none of the outputs matched");
+        }
+
+        ::core::task::Poll::Pending
+    }};
+    (@default $race:lifetime $winner:ident [$default:expr]) => {
+        if $winner.is_none() {
+            break $race 'arm: { $default };
+        }
+    };
+    (@default $race:lifetime $winner:ident []) => {};
+    (@options [$order:ident] timer = $timer:expr; $($arms:tt)*) => {{
+        #[allow(unused_variables)]
+        let timer = $timer;
+        $crate::select_when!(@arms [$order timer] [] $($arms)*)
+    }};
+    (@options [$order:ident] $($arms:tt)*) => {
+        $crate::select_when!(@arms [$order] [] $($arms)*)
+    };
+    (biased; $($arms:tt)*) => {
+        $crate::select_when!(@options [biased] $($arms)*)
+    };
+    (timer = $timer:expr; biased; $($arms:tt)*) => {
+        $crate::select_when!(@options [biased] timer = $timer; $($arms)*)
+    };
+    ($($arms:tt)*) => {
+        $crate::select_when!(@options [fair] $($arms)*)
+    };
+}
+
+/// Applies arms with the syntax of [`when!`] to each item of a [`Stream`], producing a stream of
+/// the results of the branches. Items for which no arm fired are dropped, like with
+/// [`StreamExt::filter_map`].
+///
+/// Requires the `futures` feature of this crate.
+///
+/// ```rust
+/// use futures::{
+///     executor::block_on,
+///     stream::{self, StreamExt},
+/// };
+///
+/// let lines = stream::iter(vec!["# comment", "", "answer=42", "name"]);
+///
+/// let entries = kiam::stream_when! {
+///     for line in lines;
+///     line.starts_with('#') => continue,
+///     let Some((key, value)) = line.split_once('=') => (key, Some(value)),
+///     !line.is_empty() => (line, None),
+/// };
+///
+/// let entries = block_on(entries.collect::<Vec<_>>());
+/// assert_eq!(entries, [("answer", Some("42")), ("name", None)]);
+/// ```
+///
+/// The arms are preceded by `for <pattern> in <stream>;`, the pattern is bound to each item. An arm
+/// with `continue` as the branch drops the items for which it fires, without checking the following
+/// arms. A default arm (`_ =>` or `else =>`) is used for the items for which no other arm fired.
+///
+/// The conditions and the branches are evaluated in a `move` closure, so they can't `.await`. The
+/// subject form and `let` statements before the arms are not supported.
+///
+/// [`Stream`]: https://docs.rs/futures/0.3/futures/stream/trait.Stream.html
+/// [`StreamExt::filter_map`]: https://docs.rs/futures/0.3/futures/stream/trait.StreamExt.html#method.filter_map
+#[cfg(feature = "futures")]
+#[macro_export]
+macro_rules! stream_when {
+    (for $item:pat in $stream:expr; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@stream [$item] [$stream]] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but evaluates all the conditions in parallel, on the [`rayon`] thread pool,
+/// and then executes the branch of the first arm whose condition was `true`. Useful when the
+/// conditions are expensive.
+///
+/// ```rust
+/// fn is_prime(n: u64) -> bool {
+///     n > 1 && (2..n).take_while(|d| d * d <= n).all(|d| n % d != 0)
+/// }
+///
+/// let (a, b) = (1_000_000_007, 998_244_353);
+///
+/// let which = kiam::par_when! {
+///     is_prime(a) && is_prime(b) => "both",
+///     is_prime(a) => "a",
+///     is_prime(b) => "b",
+///     _ => "none",
+/// };
+///
+/// assert_eq!(which, "both");
+/// ```
+///
+/// Unlike [`when!`], *all* the conditions are evaluated, so they should be pure. The values used
+/// by the conditions must be `Sync`. Conditions may use `let`, `or` and so on, but their bindings
+/// can't be used in the branches, since the branch is executed after all the conditions are
+/// evaluated. If a condition panics, the panic is propagated.
+///
+/// The conditions are split with [`rayon::join`], which costs a few atomic operations per
+/// condition (or a context switch, if the condition is stolen by another thread), much less than
+/// spawning a thread, but still more than evaluating a cheap condition directly.
+///
+/// Requires the `rayon` feature, which enables the [`rayon`] dependency. The subject form and
+/// `let` statements before the arms are not supported.
+///
+/// [`rayon`]: https://docs.rs/rayon
+/// [`rayon::join`]: https://docs.rs/rayon/1/rayon/fn.join.html
+#[cfg(feature = "rayon")]
+#[macro_export]
+macro_rules! par_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@par] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but remembers which arm fired for a key, so the conditions are not
+/// re-evaluated when the same key is used again.
+///
+/// The cache is a map from the key to `usize` (the index of the arm which fired) supplied by the
+/// user, anything with `get(&key)` and `insert(key, index)` methods (like `HashMap` and
+/// `BTreeMap`) can be used:
+///
+/// ```rust
+/// use std::collections::HashMap;
+///
+/// fn is_prime(n: u64) -> bool {
+///     n > 1 && (2..n).take_while(|d| d * d <= n).all(|d| n % d != 0)
+/// }
+///
+/// let mut cache = HashMap::new();
+/// let mut classify = |n: u64| kiam::memo_when! {
+///     cache, n;
+///     is_prime(n) => "prime",
+///     n % 2 == 0 => "even",
+///     _ => "odd",
+/// };
+///
+/// let kinds: Vec<_> = [7, 8, 9, 7, 7, 8].iter().map(|&n| classify(n)).collect();
+///
+/// assert_eq!(kinds, ["prime", "even", "odd", "prime", "prime", "even"]);
+/// assert_eq!(cache.len(), 3);
+/// ```
+///
+/// Since the conditions are not evaluated when the result is cached, they should only depend on
+/// the key and can only be boolean expressions. The subject form and `let` statements before the
+/// arms are not supported.
+#[macro_export]
+macro_rules! memo_when {
+    ($cache:expr, $key:expr; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@memo [$cache] [$key]] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but in debug builds (with `debug_assertions`) first evaluates *all* the
+/// conditions and panics if more than one of them holds. Catches overlapping arms, which silently
+/// shadow each other with [`when!`].
+///
+/// ```rust,should_panic
+/// let discount = |age: u32, student: bool| kiam::exclusive_when! {
+///     age < 18 => 50,
+///     age >= 65 => 30,
+///     student => 20,
+///     _ => 0,
+/// };
+///
+/// assert_eq!(discount(70, false), 30);
+/// assert_eq!(discount(30, true), 20);
+///
+/// // Panics: "2 arms of `exclusive_when!` fired (src/main.rs:1), including `age < 18` and `student`"
+/// discount(16, true);
+/// ```
+///
+/// Labelled arms are reported by their labels. In debug builds each condition is evaluated once,
+/// except for the condition of the arm which fired if it binds variables (like `let` conditions),
+/// which is evaluated again to bind them, so the conditions should be pure. In release builds this
+/// is the same as [`when!`]. The default arm is not checked.
+/// The subject form and `let` statements before the arms are not supported.
+#[macro_export]
+macro_rules! exclusive_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@exclusive] [] [] $($arms)*)
+    };
+}
+
+/// Same as [`when!`], but evaluates *all* the conditions and executes the branch of a random arm
+/// among the ones whose conditions hold. If none of them hold, the default branch (`_ =>` or
+/// `else =>`) is executed.
+///
+/// The randomness is supplied by the user: a closure which receives the number of arms whose
+/// conditions hold (`n`) and returns a number in `0..n`, e.g. `|n| rng.gen_range(0..n)` with
+/// `rand`:
+///
+/// ```rust
+/// // A tiny xorshift generator, use a real one in real code
+/// let mut state = 0x2545_f491_u32;
+/// let mut random = |n: usize| {
+///     state ^= state << 13;
+///     state ^= state >> 17;
+///     state ^= state << 5;
+///     state as usize % n
+/// };
+///
+/// let (hp, has_potion, enemy_near) = (20, true, true);
+///
+/// for _ in 0..10 {
+///     let action = kiam::rand_when! {
+///         &mut random;
+///         hp < 30 && has_potion => "drink a potion",
+///         enemy_near => "attack",
+///         hp < 10 => "run away",
+///         _ => "wander",
+///     };
+///
+///     assert!(action == "drink a potion" || action == "attack");
+/// }
+/// ```
+///
+/// With the `rand` feature, which enables the [`rand`] dependency, the closure can be omitted. The
+/// arm is then picked uniformly with `rand::thread_rng`:
+///
+/// ```rust
+/// # #[cfg(feature = "rand")] {
+/// let (hp, has_potion, enemy_near) = (20, true, true);
+///
+/// let action = kiam::rand_when! {
+///     hp < 30 && has_potion => "drink a potion",
+///     enemy_near => "attack",
+///     hp < 10 => "run away",
+///     _ => "wander",
+/// };
+///
+/// assert!(action == "drink a potion" || action == "attack");
+/// # }
+/// ```
+///
+/// Conditions can only be boolean expressions. If the closure returns a number which is out of
+/// range, `rand_when!` panics. The subject form and `let` statements before the arms are not
+/// supported.
+///
+/// [`rand`]: https://docs.rs/rand
+#[macro_export]
+macro_rules! rand_when {
+    ($pick:expr; $($arms:tt)*) => {
+        $crate::__when_wrap!([] [@rand [$pick]] [] [] $($arms)*)
+    };
+    ($($arms:tt)*) => {
+        $crate::__rand_when_uniform!($($arms)*)
+    };
+}
+
+// `rand_when!` without a closure picks the arm with `rand`
+#[cfg(feature = "rand")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __rand_when_uniform {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@rand [$crate::__private::pick_uniform]] [] [] $($arms)*)
+    };
+}
+
+#[cfg(not(feature = "rand"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __rand_when_uniform {
+    ($($arms:tt)*) => {
+        ::core::compile_error!(
+            "`rand_when!` requires a closure picking the arm (`|n| ...;`) or the `rand` feature"
+        )
+    };
+}
+
+/// Checks a sequence of conditions which must *all* hold, bindings of `let` conditions can be used
+/// in the following conditions and in the `then` block. If any of the conditions doesn't hold, the
+/// `else` block is executed.
+///
+/// ```rust
+/// let input = Some("42");
+///
+/// let n = kiam::when_chain! {
+///     let Some(s) = input;
+///     !s.is_empty();
+///     let Ok(n) = s.parse::<i32>();
+///     n > 0;
+///     then { n * 2 }
+///     else { 0 }
+/// };
+///
+/// assert_eq!(n, 84);
+/// ```
+///
+/// This is the same as a single [`when!`] arm with the conditions separated by `,`. Without the
+/// `else` block the `then` block must evaluate to `()`. `or` can't be used in the conditions.
+#[macro_export]
+macro_rules! when_chain {
+    (@conds [$({ $($conds:tt)* })*] [] then $then:block $(else $else:block)?) => {
+        $crate::when! {
+            $($($conds)*),* => $then,
+            $(_ => $else,)?
+        }
+    };
+    (@conds [$($conds:tt)*] [$($cur:tt)*] ; $($rest:tt)*) => {
+        $crate::when_chain!(@conds [$($conds)* { $($cur)* }] [] $($rest)*)
+    };
+    (@conds [$($conds:tt)*] [$($cur:tt)*] $a:tt ; $($rest:tt)*) => {
+        $crate::when_chain!(@conds [$($conds)* { $($cur)* $a }] [] $($rest)*)
+    };
+    (@conds [$($conds:tt)*] [$($cur:tt)*] $a:tt $b:tt ; $($rest:tt)*) => {
+        $crate::when_chain!(@conds [$($conds)* { $($cur)* $a $b }] [] $($rest)*)
+    };
+    (@conds [$($conds:tt)*] [$($cur:tt)*] $a:tt $b:tt $c:tt ; $($rest:tt)*) => {
+        $crate::when_chain!(@conds [$($conds)* { $($cur)* $a $b $c }] [] $($rest)*)
+    };
+    (@conds [$($conds:tt)*] [$($cur:tt)*] $a:tt $b:tt $c:tt $d:tt $($rest:tt)*) => {
+        $crate::when_chain!(@conds [$($conds)*] [$($cur)* $a $b $c $d] $($rest)*)
+    };
+    (@conds [$($conds:tt)*] [$($cur:tt)*] $($rest:tt)*) => {
+        ::core::compile_error!("expected `;`-separated conditions followed by `then { ... }`")
+    };
+    ($($tokens:tt)*) => {
+        $crate::when_chain!(@conds [] [] $($tokens)*)
+    };
+}
+
+/// Returns `Err(...)` from the enclosing function if one of the arms fires, like a list of guard
+/// clauses. The error is converted with [`From`], like with `?`.
+///
+/// ```rust
+/// #[derive(Debug, PartialEq)]
+/// enum Error {
+///     Empty,
+///     TooLong(usize),
+///     Invalid(char),
+/// }
+///
+/// fn validate(name: &str) -> Result<&str, Error> {
+///     kiam::bail_when! {
+///         name.is_empty() => Error::Empty,
+///         name.len() > 16 => Error::TooLong(name.len()),
+///         let Some(c) = name.chars().find(|c| !c.is_alphanumeric()) => Error::Invalid(c),
+///     }
+///
+///     Ok(name)
+/// }
+///
+/// assert_eq!(validate("waffle"), Ok("waffle"));
+/// assert_eq!(validate(""), Err(Error::Empty));
+/// assert_eq!(validate("a b"), Err(Error::Invalid(' ')));
+/// ```
+///
+/// Since the fallthrough is used to continue, a default arm (`_ =>` or `else =>`) is not allowed.
+#[macro_export]
+macro_rules! bail_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@bail] [] [] $($arms)*)
+    };
+}
+
+/// Returns the result of the branch from the enclosing function if one of the arms fires,
+/// otherwise the execution continues. Like [`bail_when!`], but for arbitrary values.
+///
+/// ```rust
+/// fn describe(n: u32) -> &'static str {
+///     kiam::return_when! {
+///         n == 0 => "zero",
+///         n == 1 => "one",
+///         n < 10 => "a few",
+///     }
+///
+///     "many"
+/// }
+///
+/// assert_eq!(describe(1), "one");
+/// assert_eq!(describe(5), "a few");
+/// assert_eq!(describe(42), "many");
+/// ```
+///
+/// Since the fallthrough is used to continue, a default arm (`_ =>` or `else =>`) is not allowed.
+#[macro_export]
+macro_rules! return_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@return] [] [] $($arms)*)
+    };
+}
+
+/// Breaks out of the enclosing loop if the condition holds.
+///
+/// The condition uses the syntax of [`when!`] conditions, so `,` and `or` can be used to combine
+/// conditions. A label can be specified before the condition (`'label: <condition>`). Arms can be
+/// used to break with a value, the first arm which fires is used:
+///
+/// ```rust
+/// let mut stack = vec![1, 2, 3, 4];
+/// let mut sum = 0;
+///
+/// loop {
+///     kiam::break_when!(let None = stack.last() or sum > 5);
+///     sum += stack.pop().unwrap();
+/// }
+///
+/// assert_eq!(sum, 7);
+///
+/// let mut i = 0;
+/// let found = 'search: loop {
+///     loop {
+///         i += 1;
+///         kiam::break_when! {
+///             'search:
+///             i % 7 == 0 => Some(i),
+///             i > 100 => None,
+///         }
+///         kiam::break_when!(i % 3 == 0);
+///     }
+/// };
+///
+/// assert_eq!(found, Some(7));
+/// ```
+///
+/// A default arm (`_ =>` or `else =>`) is not allowed.
+#[macro_export]
+macro_rules! break_when {
+    ($label:lifetime: $($tokens:tt)+) => {
+        $crate::__when_wrap!([] [@break [$label]] [] [] $($tokens)+)
+    };
+    ($($tokens:tt)+) => {
+        $crate::__when_wrap!([] [@break []] [] [] $($tokens)+)
+    };
+}
+
+/// Continues the enclosing loop if the condition holds.
+///
+/// The condition uses the syntax of [`when!`] conditions, so `,` and `or` can be used to combine
+/// conditions. A label can be specified before the condition (`'label: <condition>`):
+///
+/// ```rust
+/// let mut picked = Vec::new();
+///
+/// 'outer: for line in ["a,1", "#comment", "", "b,2,x", "c,3"] {
+///     kiam::continue_when!(line.is_empty() or line.starts_with('#'));
+///
+///     for field in line.split(',') {
+///         kiam::continue_when!('outer: field == "x");
+///     }
+///
+///     picked.push(line);
+/// }
+///
+/// assert_eq!(picked, ["a,1", "c,3"]);
+/// ```
+#[macro_export]
+macro_rules! continue_when {
+    ($label:lifetime: $($cond:tt)+) => {
+        $crate::when! { $($cond)+ => continue $label }
+    };
+    ($($cond:tt)+) => {
+        $crate::when! { $($cond)+ => continue }
+    };
+}
+
+/// Same as [`bail_when!`], but uses `do yeet` instead of `return Err(...)`, so it can be used in
+/// `try` blocks and with types other than `Result`.
+///
+/// Requires the `nightly` feature of this crate and the `yeet_expr` feature of the compiler.
+///
+/// ```rust
+/// #![feature(try_blocks, yeet_expr)]
+///
+/// let check = |x: i32| -> Result<i32, &'static str> {
+///     try {
+///         kiam::yeet_when! {
+///             x < 0 => "negative",
+///             x > 100 => "too big",
+///         }
+///
+///         x * 2
+///     }
+/// };
+///
+/// assert_eq!(check(21), Ok(42));
+/// assert_eq!(check(-1), Err("negative"));
+/// ```
+///
+/// Since the fallthrough is used to continue, a default arm (`_ =>` or `else =>`) is not allowed.
+#[cfg(feature = "nightly")]
+#[macro_export]
+macro_rules! yeet_when {
+    ($($arms:tt)*) => {
+        $crate::__when_wrap!([] [@yeet] [] [] $($arms)*)
+    };
+}
+
+// Wraps all branches into `$wrap(...)` and adds `_ => $default` as the last arm:
+// `__when_wrap!([<wrap>] [<default>] [<{condition} [branch]>...] [<current arm>] ...)`.
+//
+// To not hit the recursion limit, tokens which are not `=>` are skipped in chunks.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __when_wrap {
+    ([$($wrap:tt)*] [@panic] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! {
+            $($($cond)* => $($branch)*,)* $($cur)*
+            _ => ::core::panic!(
+                "{}",
+                ::core::concat!(
+                    "none of the arms matched (",
+                    ::core::file!(),
+                    ":",
+                    ::core::line!(),
+                    "), checked conditions:",
+                    $("\n    ", ::core::stringify!($($cond)*),)*
+                )
+            ),
+        }
+    };
+    ([$($wrap:tt)*] [@all] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {{
+        $(
+            $crate::when! { $($cond)* => $($branch)* }
+        )*
+        $crate::when! { $($cur)* }
+    }};
+    ([$($wrap:tt)*] [@collect] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {{
+        let mut collected = $crate::__alloc::vec::Vec::new();
+        $(
+            $crate::when! { $($cond)* => collected.push($($branch)*) }
+        )*
+        $crate::when! { $($cur)* }
+        collected
+    }};
+    ([$($wrap:tt)*] [@switch] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {{
+        // The loop is only used as a target for `break`, it's a `while` rather than a `loop` so
+        // that `continue` doesn't run the arms again and `break <value>` is rejected
+        let mut first = true;
+        #[allow(unused_assignments)]
+        while ::core::mem::replace(&mut first, false) {
+            let mut matched = false;
+            $(
+                // `__when!` is used directly, since the condition of the default arm is `true`,
+                // which `kiam_macros::when!` would report as making the `_` arm unreachable
+                if matched || $crate::__when! { $($cond)* => true, _ => false } {
+                    matched = true;
+                    $($branch)*;
+                }
+            )*
+            $crate::when! { $($cur)* }
+        }
+    }};
+    ([$($wrap:tt)*] [@switch] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!([$($wrap)*] [@switch] [$($arms)* { true } [$branch]] [])
+    };
+    ([$($wrap:tt)*] [@switch] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!([$($wrap)*] [@switch] [$($arms)* { true } [$branch]] [])
+    };
+    ([$($wrap:tt)*] [@dbg] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@dbg [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@dbg] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@dbg [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@dbg] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(
+            @dbg [$($arms)*]
+            [$($cur)* _ => ::std::eprintln!("[{}:{}] none of the arms matched", ::core::file!(), ::core::line!())]
+        )
+    };
+    (@dbg [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! {
+            $(
+                $($cond)* => match $($branch)* {
+                    value => {
+                        ::std::eprintln!(
+                            "[{}:{}] {} => {:#?}",
+                            ::core::file!(),
+                            ::core::line!(),
+                            $crate::__when_wrap!(@label_of $($cond)*),
+                            &value,
+                        );
+                        value
+                    }
+                },
+            )*
+            $($cur)*
+        }
+    };
+    ([$($wrap:tt)*] [@boxed] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@boxed [$($arms)*] [$branch])
+    };
+    ([$($wrap:tt)*] [@boxed] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@boxed [$($arms)*] [$branch])
+    };
+    ([$($wrap:tt)*] [@boxed] [$($arms:tt)*] [$($cur:tt)*]) => {
+        ::core::compile_error!("`when_boxed!` requires a default arm")
+    };
+    (@boxed [$({ $($cond:tt)* } [$($branch:tt)*])*] [$default:expr]) => {
+        $crate::when! {
+            $($($cond)* => $crate::__box_future($($branch)*),)*
+            _ => $crate::__box_future($default),
+        }
+    };
+    ([$($wrap:tt)*] [@either $($either:tt)*] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@either [$($either)*] [$($arms)*] [($branch)])
+    };
+    ([$($wrap:tt)*] [@either $($either:tt)*] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@either [$($either)*] [$($arms)*] [($branch)])
+    };
+    ([$($wrap:tt)*] [@either $($either:tt)*] [$($arms:tt)*] [$($cur:tt)*]) => {
+        ::core::compile_error!("`when_either!` requires a default arm")
+    };
+    (@either [$($either:tt)*] [{ $($cond:tt)* } [$($branch:tt)*]] [$($default:tt)*]) => {
+        $crate::when! {
+            $($cond)* => $($either)*::Left($($branch)*),
+            _ => $($either)*::Right($($default)*),
+        }
+    };
+    (@either [$($either:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)+] [$($default:tt)*]) => {
+        $crate::when! {
+            $($cond)* => $($either)*::Left($($branch)*),
+            _ => $($either)*::Right($crate::__when_wrap!(@either [$($either)*] [$($arms)+] [$($default)*])),
+        }
+    };
+    (@either [$($either:tt)*] [] [$($default:tt)*]) => {
+        $($default)*
+    };
+    ([$($wrap:tt)*] [@enum $($traits:tt)*] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_enum!(
+            @zip [$($traits)*] [] [$($arms)* { _ } [($branch)]]
+            [$crate::__when_wrap!(@either [$crate::__private::Either] [$($arms)*] [($branch)])]
+        )
+    };
+    ([$($wrap:tt)*] [@enum $($traits:tt)*] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_enum!(
+            @zip [$($traits)*] [] [$($arms)* { _ } [($branch)]]
+            [$crate::__when_wrap!(@either [$crate::__private::Either] [$($arms)*] [($branch)])]
+        )
+    };
+    ([$($wrap:tt)*] [@enum $($traits:tt)*] [$($arms:tt)*] [$($cur:tt)*]) => {
+        ::core::compile_error!("`when_enum!` requires a default arm")
+    };
+    ([$($wrap:tt)*] [@retry $($retry:tt)*] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@retry $($retry)* [$($arms)*] [] [$branch])
+    };
+    ([$($wrap:tt)*] [@retry $($retry:tt)*] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@retry $($retry)* [$($arms)*] [] [$branch])
+    };
+    ([$($wrap:tt)*] [@retry $($retry:tt)*] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@retry $($retry)* [$($arms)*] [$($cur)*] [()])
+    };
+    (@retry [$attempts:expr] [$backoff:expr] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*] [$default:expr]) => {{
+        let attempts: usize = $attempts;
+        #[allow(unused_mut)]
+        let mut backoff = $backoff;
+        let mut attempt: usize = 0;
+
+        loop {
+            attempt += 1;
+
+            let value = $crate::when! {
+                $($($cond)* => ::core::option::Option::Some($($branch)*),)*
+                $($cur)*
+                _ => ::core::option::Option::None,
+            };
+
+            if let ::core::option::Option::Some(value) = value {
+                break value;
+            }
+
+            if attempt >= attempts {
+                break $default;
+            }
+
+            backoff(attempt);
+        }
+    }};
+    ([$($wrap:tt)*] [@par] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@par [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@par] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@par [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@par] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@par [$($arms)*] [$($cur)*])
+    };
+    (@par [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($default:tt)*]) => {
+        $crate::__when_wrap!(@par_index [] [0] [$({ $($cond)* } [$($branch)*])*] [$($default)*])
+    };
+    // Numbers the arms: `@par_index [<numbered arms>] [<index>] [<arms>] [<default>]`
+    (@par_index [$($arms:tt)*] [$($i:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($rest:tt)*] [$($default:tt)*]) => {
+        $crate::__when_wrap!(
+            @par_index [$($arms)* { $($cond)* } [$($i)*] [$($branch)*]] [$($i)* + 1] [$($rest)*] [$($default)*]
+        )
+    };
+    (@par_index [$({ $($cond:tt)* } [$($i:tt)*] [$($branch:tt)*])*] [$($n:tt)*] [] [$($default:tt)*]) => {{
+        let first: ::core::option::Option<usize> =
+            $crate::__when_wrap!(@par_join [$({ $($cond)* })*]);
+
+        // `__when!` is used directly, since all the conditions are similar
+        $crate::__when! {
+            $(first == ::core::option::Option::Some($($i)*) => $($branch)*,)*
+            $($default)*
+        }
+    }};
+    // Evaluates the conditions in parallel, returns the index of the first one which is `true`.
+    // The conditions are evaluated by `when!`, so they may have labels, hints, `let`s and so on
+    (@par_join []) => {
+        ::core::option::Option::None
+    };
+    (@par_join [{ $($cond:tt)* }]) => {
+        if $crate::when! { $($cond)* => true, _ => false } {
+            ::core::option::Option::Some(0)
+        } else {
+            ::core::option::Option::None
+        }
+    };
+    (@par_join [{ $($cond:tt)* } $($conds:tt)+]) => {{
+        let (head, rest) = $crate::__private::rayon::join(
+            || -> bool { $crate::when! { $($cond)* => true, _ => false } },
+            || -> ::core::option::Option<usize> { $crate::__when_wrap!(@par_join [$($conds)+]) },
+        );
+
+        if head {
+            ::core::option::Option::Some(0)
+        } else {
+            rest.map(|i| i + 1)
+        }
+    }};
+    ([$($wrap:tt)*] [@memo $($memo:tt)*] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@memo $($memo)* [0] [] [] [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@memo $($memo:tt)*] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@memo $($memo)* [0] [] [] [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@memo $($memo:tt)*] [$($arms:tt)*] []) => {
+        $crate::__when_wrap!(@memo $($memo)* [0] [] [] [$($arms)*] [])
+    };
+    (@memo [$cache:expr] [$key:expr] [$($n:tt)*] [$($conds:tt)*] [$($branches:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] [$($default:tt)*]) => {
+        $crate::__when_wrap!(
+            @memo [$cache] [$key] [$($n)* + 1]
+            [$($conds)* $($cond)* => $($n)*,]
+            [$($branches)* [$($n)*] [$($branch)*]]
+            [$($arms)*] [$($default)*]
+        )
+    };
+    (@memo [$cache:expr] [$key:expr] [$($n:tt)*] [$($conds:tt)*] [$([$($i:tt)*] [$($branch:tt)*])*] [] [$($default:tt)*]) => {{
+        let cache = &mut $cache;
+        let key = $key;
+        let index: usize = match cache.get(&key) {
+            ::core::option::Option::Some(&index) => index,
+            ::core::option::Option::None => {
+                let index = $crate::when! { $($conds)* _ => $($n)* };
+                cache.insert(key, index);
+                index
+            }
+        };
+
+        $crate::when! { $(index == $($i)* => $($branch)*,)* $($default)* }
+    }};
+    // `[@traced <backend> <level>]`, where the backend is `tracing`, `log` or `defmt`
+    ([$($wrap:tt)*] [@traced $backend:ident $level:ident] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@traced $backend $level [0] [] [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@traced $backend:ident $level:ident] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@traced $backend $level [0] [] [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@traced $backend:ident $level:ident] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@traced $backend $level [0] [] [$($arms)*] [$($cur)*])
+    };
+    (@traced $backend:ident $level:ident [$($n:tt)*] [$($acc:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(
+            @traced $backend $level [$($n)* + 1]
+            [
+                $($acc)*
+                $($cond)* => {
+                    $crate::__when_wrap!(@event $backend $level [$($cond)*] [$($n)*]);
+                    $($branch)*
+                },
+            ]
+            [$($arms)*] [$($cur)*]
+        )
+    };
+    (@traced $backend:ident $level:ident [$($n:tt)*] [$($acc:tt)*] [] [$($cur:tt)*]) => {
+        $crate::when! { $($acc)* $($cur)* }
+    };
+    (@event tracing $level:ident [$($cond:tt)*] [$($n:tt)*]) => {
+        $crate::__private::tracing::event!(
+            $crate::__private::tracing::Level::$level,
+            arm = $crate::__when_wrap!(@label_of $($cond)*),
+            index = ($($n)*) as u64,
+            "`when!` arm taken"
+        )
+    };
+    // The code generated by the macros of `defmt` refers to `defmt::...`, so the crate is imported
+    // under its own name
+    (@event defmt $level:ident [$($cond:tt)*] [$($n:tt)*]) => {{
+        use $crate::__private::defmt;
+        defmt::$level!(
+            "`when!` arm taken: {=str} (#{=u32})",
+            $crate::__when_wrap!(@label_of $($cond)*),
+            ($($n)*) as u32
+        )
+    }};
+    (@event log $level:ident [$($cond:tt)*] [$($n:tt)*]) => {
+        $crate::__private::log::log!(
+            $crate::__private::log::Level::$level,
+            "[{}:{}] `when!` arm taken: {} (#{})",
+            ::core::file!(),
+            ::core::line!(),
+            $crate::__when_wrap!(@label_of $($cond)*),
+            $($n)*
+        )
+    };
+    ([$($wrap:tt)*] [@covered] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@covered [0] [] [] [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@covered] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@covered [0] [] [] [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@covered] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@covered [0] [] [] [$($arms)*] [$($cur)*])
+    };
+    // `@covered [<index>] [<labels>] [<arms>] ...`
+    (@covered [$($n:tt)*] [$($labels:tt)*] [$($acc:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(
+            @covered [$($n)* + 1]
+            [$($labels)* $crate::coverage::Arm::new($crate::__when_wrap!(@label_of $($cond)*)),]
+            [$($acc)* $($cond)* => { __KIAM_COVERAGE.hit($($n)*); $($branch)* },]
+            [$($arms)*] [$($cur)*]
+        )
+    };
+    (@covered [$($n:tt)*] [$($labels:tt)*] [$($acc:tt)*] [] [$($cur:tt)*]) => {{
+        static __KIAM_ARMS: [$crate::coverage::Arm; $($n)*] = [$($labels)*];
+        static __KIAM_COVERAGE: $crate::coverage::Site = $crate::coverage::Site::new(
+            ::core::file!(),
+            ::core::line!(),
+            ::core::column!(),
+            &__KIAM_ARMS,
+        );
+        __KIAM_COVERAGE.enter();
+
+        $crate::when! { $($acc)* $($cur)* }
+    }};
+    ([$($wrap:tt)*] [@explain] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@explain [0] [] [] [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@explain] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@explain [0] [] [] [$($arms)* { _ } [($branch)]] [])
+    };
+    ([$($wrap:tt)*] [@explain] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(
+            @explain [0] [] [] [$($arms)*]
+            [$($cur)* _ => (::core::option::Option::None, ())]
+        )
+    };
+    // `@explain [<index>] [<conditions>] [<arms>] ...`
+    (@explain [$($n:tt)*] [$($conds:tt)*] [$($acc:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(
+            @explain [$($n)* + 1]
+            [$($conds)* $crate::__when_wrap!(@label_of $($cond)*),]
+            [$($acc)* $($cond)* => (::core::option::Option::Some($($n)*), $($branch)*),]
+            [$($arms)*] [$($cur)*]
+        )
+    };
+    (@explain [$($n:tt)*] [$($conds:tt)*] [$($acc:tt)*] [] [$($cur:tt)*]) => {{
+        const CONDITIONS: &[&::core::primitive::str] = &[$($conds)*];
+        let (fired, value) = $crate::when! { $($acc)* $($cur)* };
+        (value, $crate::Explanation::new(CONDITIONS, fired))
+    }};
+    ([$($wrap:tt)*] [@static $($sig:tt)*] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@static $($sig)* [$($arms)*] [$branch])
+    };
+    ([$($wrap:tt)*] [@static $($sig:tt)*] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@static $($sig)* [$($arms)*] [$branch])
+    };
+    ([$($wrap:tt)*] [@static $($sig:tt)*] [$($arms:tt)*] []) => {
+        $crate::__when_wrap!(@static $($sig)* [$($arms)*] [])
+    };
+    // `@static [<name>] [<context>] [<output>] [<arms>] [<default branch>]`
+    (@static [$ctx:ident] [$ty:ty] [$out:ty] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($default:expr)?]) => {{
+        let rules = $crate::rules::StaticRuleSet::new(&[$(
+            (
+                (|$ctx: &$ty| -> bool { $crate::when! { $($cond)* => true, _ => false } })
+                    as fn(&$ty) -> bool,
+                (|$ctx: &$ty| -> $out {
+                    let _ = $ctx;
+                    $($branch)*
+                }) as fn(&$ty) -> $out,
+            ),
+        )*]);
+        $(
+            let rules = rules.otherwise(|$ctx: &$ty| -> $out {
+                let _ = $ctx;
+                $default
+            });
+        )?
+        rules
+    }};
+    ([$($wrap:tt)*] [@handlers $($sig:tt)*] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@handlers $($sig)* [$($arms)*] [$branch])
+    };
+    ([$($wrap:tt)*] [@handlers $($sig:tt)*] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@handlers $($sig)* [$($arms)*] [$branch])
+    };
+    ([$($wrap:tt)*] [@handlers $($sig:tt)*] [$($arms:tt)*] []) => {
+        $crate::__when_wrap!(@handlers $($sig)* [$($arms)*] [])
+    };
+    // `@handlers [<name>] [<context>] [<output>] [<arms>] [<default handler>]`
+    (@handlers [$ctx:ident] [$ty:ty] [$out:ty] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($default:expr)?]) => {{
+        let mut rules = $crate::rules::RuleSet::<$ty, $out>::new();
+        $(
+            rules.push_handler(
+                move |$ctx: &$ty| -> bool { $crate::when! { $($cond)* => true, _ => false } },
+                $($branch)*,
+            );
+        )*
+        $(
+            rules = rules.otherwise_handler($default);
+        )?
+        rules
+    }};
+    ([$($wrap:tt)*] [@stream $($sig:tt)*] [$($arms:tt)*] [$($cur:tt)*] => continue $(, $($rest:tt)*)?) => {
+        $crate::__when_wrap!(
+            [$($wrap)*] [@stream $($sig)*] [$($arms)* { $($cur)* } [@skip]] [] $($($rest)*)?
+        )
+    };
+    ([$($wrap:tt)*] [@stream $($sig:tt)*] [$($arms:tt)*] [] _ => continue $(,)?) => {
+        $crate::__when_wrap!(@stream $($sig)* [] [$($arms)*] [] [])
+    };
+    ([$($wrap:tt)*] [@stream $($sig:tt)*] [$($arms:tt)*] [] else => continue $(,)?) => {
+        $crate::__when_wrap!(@stream $($sig)* [] [$($arms)*] [] [])
+    };
+    ([$($wrap:tt)*] [@stream $($sig:tt)*] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@stream $($sig)* [] [$($arms)*] [] [$branch])
+    };
+    ([$($wrap:tt)*] [@stream $($sig:tt)*] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@stream $($sig)* [] [$($arms)*] [] [$branch])
+    };
+    ([$($wrap:tt)*] [@stream $($sig:tt)*] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@stream $($sig)* [] [$($arms)*] [$($cur)*] [])
+    };
+    // `@stream [<pattern>] [<stream>] [<arms of when!>] [<arms>] [<current arm>] [<default>]`
+    (@stream $item:tt $stream:tt [$($acc:tt)*] [{ $($cond:tt)* } [@skip] $($arms:tt)*] $cur:tt $default:tt) => {
+        $crate::__when_wrap!(
+            @stream $item $stream [$($acc)* $($cond)* => ::core::option::Option::None,]
+            [$($arms)*] $cur $default
+        )
+    };
+    (@stream $item:tt $stream:tt [$($acc:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] $cur:tt $default:tt) => {
+        $crate::__when_wrap!(
+            @stream $item $stream [$($acc)* $($cond)* => ::core::option::Option::Some($($branch)*),]
+            [$($arms)*] $cur $default
+        )
+    };
+    (@stream [$item:pat] [$stream:expr] [$($acc:tt)*] [] [$($cur:tt)*] [$($default:expr)?]) => {
+        $crate::__private::futures::stream::StreamExt::filter_map($stream, move |$item| {
+            ::core::future::ready($crate::when! {
+                $($acc)* $($cur)*
+                _ => $crate::__when_wrap!(@or_none $($default)?),
+            })
+        })
+    };
+    (@or_none $default:expr) => {
+        ::core::option::Option::Some($default)
+    };
+    (@or_none) => {
+        ::core::option::Option::None
+    };
+    ([$($wrap:tt)*] [@exclusive] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@exclusive [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@exclusive] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@exclusive [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@exclusive] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@exclusive [$($arms)*] [$($cur)*])
+    };
+    (@exclusive [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($default:tt)*]) => {
+        match () {
+            #[cfg(debug_assertions)]
+            () => {
+                // Each condition is evaluated once, then the arm which fired is picked by its index
+                let fired: &[bool] = &[$($crate::when! { $($cond)* => true, _ => false }),*];
+                let labels: &[&str] = &[$($crate::__when_wrap!(@label_of $($cond)*)),*];
+                let count = fired.iter().filter(|&&fired| fired).count();
+
+                if count > 1 {
+                    let mut labels = labels.iter().zip(fired).filter(|(_, &fired)| fired);
+                    ::core::panic!(
+                        "{} arms of `exclusive_when!` fired ({}:{}), including `{}` and `{}`",
+                        count,
+                        ::core::file!(),
+                        ::core::line!(),
+                        labels.next().map_or("", |(label, _)| label),
+                        labels.next().map_or("", |(label, _)| label),
+                    );
+                }
+
+                #[allow(unused_variables)]
+                let index = fired.iter().position(|&fired| fired);
+                $crate::__when_wrap!(
+                    @exclusive_arms [index] [0] [] [$({ $($cond)* } [$($branch)*])*] [$($default)*]
+                )
+            }
+            #[cfg(not(debug_assertions))]
+            () => $crate::when! { $($($cond)* => $($branch)*,)* $($default)* },
+        }
+    };
+    // Arms are dispatched by `@exclusive_arms [<index>] [<arm index>] [<arms>] ...`. Conditions
+    // with bindings (or attributes) are evaluated again for the arm which fired, the others are not
+    (@exclusive_arms [$index:ident] [$($n:tt)*] [$($acc:tt)*] [{ # $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] $default:tt) => {
+        $crate::__when_wrap!(
+            @exclusive_arms [$index] [$($n)* + 1]
+            [
+                $($acc)*
+                $index == ::core::option::Option::Some($($n)*) =>
+                    $crate::__when_wrap!(@exclusive_again [# $($cond)*] [$($branch)*]),
+            ]
+            [$($arms)*] $default
+        )
+    };
+    (@exclusive_arms [$index:ident] [$($n:tt)*] [$($acc:tt)*] [{ $label:tt : $cond:expr } [$($branch:tt)*] $($arms:tt)*] $default:tt) => {
+        $crate::__when_wrap!(
+            @exclusive_arms [$index] [$($n)* + 1]
+            [$($acc)* $index == ::core::option::Option::Some($($n)*) => $($branch)*,]
+            [$($arms)*] $default
+        )
+    };
+    (@exclusive_arms [$index:ident] [$($n:tt)*] [$($acc:tt)*] [{ $cond:expr } [$($branch:tt)*] $($arms:tt)*] $default:tt) => {
+        $crate::__when_wrap!(
+            @exclusive_arms [$index] [$($n)* + 1]
+            [$($acc)* $index == ::core::option::Option::Some($($n)*) => $($branch)*,]
+            [$($arms)*] $default
+        )
+    };
+    (@exclusive_arms [$index:ident] [$($n:tt)*] [$($acc:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] $default:tt) => {
+        $crate::__when_wrap!(
+            @exclusive_arms [$index] [$($n)* + 1]
+            [
+                $($acc)*
+                $index == ::core::option::Option::Some($($n)*) =>
+                    $crate::__when_wrap!(@exclusive_again [$($cond)*] [$($branch)*]),
+            ]
+            [$($arms)*] $default
+        )
+    };
+    (@exclusive_arms [$index:ident] [$($n:tt)*] [$($acc:tt)*] [] [$($default:tt)*]) => {
+        $crate::when! { $($acc)* $($default)* }
+    };
+    (@exclusive_again [$($cond:tt)*] [$($branch:tt)*]) => {
+        $crate::when! {
+            $($cond)* => $($branch)*,
+            _ => ::core::unreachable!("the condition of an arm of `exclusive_when!` isn't pure"),
+        }
+    };
+    ([$($wrap:tt)*] [@rand $pick:tt] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@rand $pick [0] [] [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@rand $pick:tt] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@rand $pick [0] [] [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@rand $pick:tt] [$($arms:tt)*] []) => {
+        $crate::__when_wrap!(@rand $pick [0] [] [$($arms)*] [])
+    };
+    (@rand $pick:tt [$($n:tt)*] [$($acc:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] [$($default:tt)*]) => {
+        $crate::__when_wrap!(
+            @rand $pick [$($n)* + 1] [$($acc)* [$($n)*] { $($cond)* } [$($branch)*]] [$($arms)*] [$($default)*]
+        )
+    };
+    (@rand [$pick:expr] [$($n:tt)*] [$([$($i:tt)*] { $($cond:tt)* } [$($branch:tt)*])*] [] [$($default:tt)*]) => {{
+        let fired = [$($crate::when! { $($cond)* => true, _ => false }),*];
+        let count = fired.iter().filter(|&&fired| fired).count();
+        let index = if count == 0 {
+            ::core::primitive::usize::MAX
+        } else {
+            // The closure may be `FnMut`
+            #[allow(unused_mut)]
+            let mut pick = $pick;
+            let target: ::core::primitive::usize = pick(count);
+            match fired.iter().enumerate().filter(|&(_, &fired)| fired).nth(target) {
+                ::core::option::Option::Some((index, _)) => index,
+                ::core::option::Option::None => ::core::panic!(
+                    "the closure passed to `rand_when!` returned {}, expected a number in `0..{}`",
+                    target,
+                    count,
+                ),
+            }
+        };
+
+        $crate::when! { $(index == $($i)* => $($branch)*,)* $($default)* }
+    }};
+    ([$($wrap:tt)*] [@bail] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! {
+            $(
+                $($cond)* => match $($branch)* {
+                    error => return ::core::result::Result::Err(::core::convert::From::from(error)),
+                },
+            )*
+            $($cur)*
+        }
+    };
+    ([$($wrap:tt)*] [@return] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! {
+            $($($cond)* => match $($branch)* { value => return value },)*
+            $($cur)*
+        }
+    };
+    ([$($wrap:tt)*] [@break [$($label:lifetime)?]] [] [$($cond:tt)+]) => {
+        $crate::when! { $($cond)+ => break $($label)? }
+    };
+    ([$($wrap:tt)*] [@break $label:tt] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! {
+            $($($cond)* => match $($branch)* { value => $crate::__when_wrap!(@break $label value) },)*
+            $($cur)*
+        }
+    };
+    (@break [$($label:lifetime)?] $value:ident) => {
+        break $($label)? $value
+    };
+    ([$($wrap:tt)*] [@yeet] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! {
+            $($($cond)* => match $($branch)* { error => do yeet error },)*
+            $($cur)*
+        }
+    };
+    ([$($wrap:tt)*] [@index] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@index [0] [] [$($arms)*] [$($cur)*])
+    };
+    ([$($wrap:tt)*] [@reorder] [$($arms:tt)*] [] _ => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@reorder [] [] [] [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@reorder] [$($arms:tt)*] [] else => $branch:expr $(,)?) => {
+        $crate::__when_wrap!(@reorder [] [] [] [$($arms)*] [_ => $branch])
+    };
+    ([$($wrap:tt)*] [@reorder] [$($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(@reorder [] [] [] [$($arms)*] [$($cur)*])
+    };
+    ([$($wrap:tt)*] [@labelled] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! {
+            $(
+                $($cond)* => ::core::option::Option::Some((
+                    $crate::__when_wrap!(@label_of $($cond)*),
+                    $($branch)*,
+                )),
+            )*
+            $($cur)*
+            _ => ::core::option::Option::None
+        }
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$({ $($cond:tt)* } [$($branch:tt)*])*] [$($cur:tt)*]) => {
+        $crate::when! { $($($cond)* => $($branch)*,)* $($cur)* _ => $($default)* }
+    };
+    (@index [$($n:tt)*] [$($acc:tt)*] [{ $($cond:tt)* } [$($branch:tt)*] $($arms:tt)*] [$($cur:tt)*]) => {
+        $crate::__when_wrap!(
+            @index [$($n)* + 1] [$($acc)* $($cond)* => ::core::option::Option::Some(($($n)*, $($branch)*)),]
+            [$($arms)*] [$($cur)*]
+        )
+    };
+    (@index [$($n:tt)*] [$($acc:tt)*] [] [$($cur:tt)*]) => {
+        $crate::when! { $($acc)* $($cur)* _ => ::core::option::Option::None }
+    };
+    // `@reorder [<cheap arms>] [<other arms>] [<expensive arms>] [<arms>] [<default arm>]`
+    (@reorder $cheap:tt $normal:tt $expensive:tt [{ $($cond:tt)* } $branch:tt $($arms:tt)*] $default:tt) => {
+        $crate::__when_wrap!(@cost [] $cheap $normal $expensive $branch [$($arms)*] $default $($cond)*)
+    };
+    (@reorder [$($cheap:tt)*] [$($normal:tt)*] [$($expensive:tt)*] [] [$($default:tt)*]) => {
+        $crate::when! { $($cheap)* $($normal)* $($expensive)* $($default)* }
+    };
+    // Looks for `#[cost(...)]` among the attributes of an arm:
+    // `@cost [<other attributes>] <buckets> [<branch>] [<arms>] [<default arm>] <condition>`
+    (@cost [$($attrs:tt)*] [$($cheap:tt)*] $normal:tt $expensive:tt [$($branch:tt)*] $arms:tt $default:tt #[cost(cheap)] $($cond:tt)*) => {
+        $crate::__when_wrap!(
+            @reorder [$($cheap)* $($attrs)* $($cond)* => $($branch)*,] $normal $expensive $arms $default
+        )
+    };
+    (@cost [$($attrs:tt)*] $cheap:tt $normal:tt [$($expensive:tt)*] [$($branch:tt)*] $arms:tt $default:tt #[cost(expensive)] $($cond:tt)*) => {
+        $crate::__when_wrap!(
+            @reorder $cheap $normal [$($expensive)* $($attrs)* $($cond)* => $($branch)*,] $arms $default
+        )
+    };
+    (@cost [$($attrs:tt)*] $cheap:tt $normal:tt $expensive:tt $branch:tt $arms:tt $default:tt #[$($attr:tt)*] $($cond:tt)*) => {
+        $crate::__when_wrap!(
+            @cost [$($attrs)* #[$($attr)*]] $cheap $normal $expensive $branch $arms $default $($cond)*
+        )
+    };
+    (@cost [$($attrs:tt)*] $cheap:tt [$($normal:tt)*] $expensive:tt [$($branch:tt)*] $arms:tt $default:tt $($cond:tt)*) => {
+        $crate::__when_wrap!(
+            @reorder $cheap [$($normal)* $($attrs)* $($cond)* => $($branch)*,] $expensive $arms $default
+        )
+    };
+    // The label of an arm, or its condition, if there is no label
+    (@label_of #[$($attr:tt)*] $($cond:tt)*) => {
+        $crate::__when_wrap!(@label_of $($cond)*)
+    };
+    (@label_of $label:tt : $($cond:tt)*) => {
+        $label
+    };
+    (@label_of $($cond:tt)*) => {
+        ::core::stringify!($($cond)*)
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [] _ => $($rest:tt)*) => {
+        ::core::compile_error!("default arm is not allowed, it's provided by the macro")
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [] else => $($rest:tt)*) => {
+        ::core::compile_error!("default arm is not allowed, it's provided by the macro")
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [$($cur:tt)*] => $branch:expr $(, $($rest:tt)*)?) => {
+        $crate::__when_wrap!(
+            [$($wrap)*] [$($default)*] [$($arms)* { $($cur)* } [$($wrap)*($branch)]] []
+            $($($rest)*)?
+        )
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [$($cur:tt)*] => { $($branch:tt)* } $($rest:tt)+) => {
+        $crate::__when_wrap!(
+            [$($wrap)*] [$($default)*] [$($arms)* { $($cur)* } [$($wrap)*({ $($branch)* })]] []
+            $($rest)+
+        )
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [$($cur:tt)*] $a:tt => $($rest:tt)*) => {
+        $crate::__when_wrap!([$($wrap)*] [$($default)*] [$($arms)*] [$($cur)* $a] => $($rest)*)
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [$($cur:tt)*] $a:tt $b:tt => $($rest:tt)*) => {
+        $crate::__when_wrap!([$($wrap)*] [$($default)*] [$($arms)*] [$($cur)* $a $b] => $($rest)*)
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [$($cur:tt)*] $a:tt $b:tt $c:tt => $($rest:tt)*) => {
+        $crate::__when_wrap!([$($wrap)*] [$($default)*] [$($arms)*] [$($cur)* $a $b $c] => $($rest)*)
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [$($cur:tt)*] $a:tt $b:tt $c:tt $d:tt $($rest:tt)*) => {
+        $crate::__when_wrap!([$($wrap)*] [$($default)*] [$($arms)*] [$($cur)* $a $b $c $d] $($rest)*)
+    };
+    ([$($wrap:tt)*] [$($default:tt)*] [$($arms:tt)*] [$($cur:tt)*] $($rest:tt)+) => {
+        $crate::__when_wrap!([$($wrap)*] [$($default)*] [$($arms)*] [$($cur)* $($rest)+])
+    };
+}
+
+/// Error returned by [`try_when!`] when none of the arms fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct NoMatch;
+
+impl core::fmt::Display for NoMatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("none of the arms matched")
+    }
+}
+
+/// Explanation of the decision made by [`explain_when!`].
+///
+/// Formats as a line per checked condition, with its result, followed by `none of the arms fired`
+/// if none of them held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Explanation {
+    conditions: &'static [&'static str],
+    fired: Option<usize>,
+}
+
+impl Explanation {
+    #[doc(hidden)]
+    pub const fn new(conditions: &'static [&'static str], fired: Option<usize>) -> Self {
+        Self { conditions, fired }
+    }
+
+    /// Returns the label (or the condition) of the arm which fired, `"_"` for the default arm.
+    pub fn fired(&self) -> Option<&'static str> {
+        self.fired.map(|index| self.conditions[index])
+    }
+
+    /// Returns the index of the arm which fired.
+    pub fn index(&self) -> Option<usize> {
+        self.fired
+    }
+
+    /// Returns the conditions which were checked, in order, and what they evaluated to.
+    pub fn checked(&self) -> impl Iterator<Item = (&'static str, bool)> + '_ {
+        let checked = self.fired.map_or(self.conditions.len(), |index| index + 1);
+        self.conditions[..checked]
+            .iter()
+            .enumerate()
+            .map(move |(index, &condition)| (condition, Some(index) == self.fired))
+    }
+}
+
+impl core::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (condition, result) in self.checked() {
+            writeln!(f, "{}: {}", condition, result)?;
+        }
+
+        match self.fired {
+            Some(_) => Ok(()),
+            None => f.write_str("none of the arms fired\n"),
+        }
+    }
+}
+
+/// Starts a chain of cases over `value`, the method counterpart of [`when!`].
+///
+/// The cases are checked in order and the action of the first case whose predicate holds for
+/// `value` is run, the following cases are skipped. Unlike the arms of [`when!`], the cases can be
+/// added in loops or only under some conditions:
+///
+/// ```rust
+/// # struct User { admin: bool, banned: bool }
+/// let decide = |user: User, maintenance: bool| {
+///     let mut branch = kiam::branch(user);
+///     if maintenance {
+///         branch = branch.case(|_| true, |_| "try again later");
+///     }
+///
+///     branch
+///         .case(|user| user.admin, |_| "granted")
+///         .case(|user| user.banned, |_| "denied")
+///         .default(|_| "review")
+///         .eval()
+/// };
+///
+/// assert_eq!(decide(User { admin: true, banned: false }, false), "granted");
+/// assert_eq!(decide(User { admin: true, banned: false }, true), "try again later");
+/// assert_eq!(decide(User { admin: false, banned: false }, false), "review");
+/// ```
+///
+/// The predicates get a reference to the value and the actions get the value itself.
+pub fn branch<T, R>(value: T) -> Branch<T, R> {
+    Branch { state: Err(value) }
+}
+
+/// A chain of cases, see [`branch`].
+#[must_use = "cases are only checked when they are added, `eval` returns the result"]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Branch<T, R> {
+    /// `Ok` with the result of the action, if a case fired, `Err` with the value otherwise
+    state: Result<R, T>,
+}
+
+impl<T, R> Branch<T, R> {
+    /// Adds a case: if none of the previous cases fired and `predicate` holds for the value,
+    /// `action` is run with the value.
+    pub fn case(self, predicate: impl FnOnce(&T) -> bool, action: impl FnOnce(T) -> R) -> Self {
+        let state = match self.state {
+            Err(value) if predicate(&value) => Ok(action(value)),
+            state => state,
+        };
+
+        Self { state }
+    }
+
+    /// Sets the action which is run if none of the cases fired, like the default arm of [`when!`].
+    pub fn default(self, action: impl FnOnce(T) -> R) -> Decided<R> {
+        Decided {
+            result: self.state.unwrap_or_else(action),
+        }
+    }
+
+    /// Returns the result of the action of the case which fired, or `None` if none of them did.
+    pub fn eval(self) -> Option<R> {
+        self.state.ok()
+    }
+}
+
+/// A chain of cases with a default action, see [`branch`].
+#[must_use = "`eval` returns the result"]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Decided<R> {
+    result: R,
+}
+
+impl<R> Decided<R> {
+    /// Returns the result of the action of the case which fired, or of the default action.
+    pub fn eval(self) -> R {
+        self.result
+    }
+}
+
+/// Two-way selection on `bool` in method position.
+///
+/// ```rust
+/// use kiam::BoolExt;
+///
+/// let labels: Vec<_> = (1..=4)
+///     .map(|x| (x % 2 == 0).when(|| "even").otherwise(|| "odd"))
+///     .collect();
+///
+/// assert_eq!(labels, ["odd", "even", "odd", "even"]);
+/// ```
+///
+/// This is the same as `if cond { f() } else { g() }` or `cond.then(f).unwrap_or_else(g)`, only
+/// one of the closures is called.
+pub trait BoolExt {
+    /// Starts the selection, `f` is called by [`otherwise`](BoolWhen::otherwise) if `self` is
+    /// `true`.
+    fn when<R, F: FnOnce() -> R>(self, f: F) -> BoolWhen<F>;
+}
+
+impl BoolExt for bool {
+    fn when<R, F: FnOnce() -> R>(self, f: F) -> BoolWhen<F> {
+        BoolWhen { cond: self, f }
+    }
+}
+
+/// A selection started by [`BoolExt::when`].
+#[must_use = "the closures are only called by `otherwise`"]
+#[derive(Debug, Clone, Copy)]
+pub struct BoolWhen<F> {
+    cond: bool,
+    f: F,
+}
+
+impl<F> BoolWhen<F> {
+    /// Calls the closure passed to [`when`](BoolExt::when) if the condition is `true` and `g`
+    /// otherwise.
+    pub fn otherwise<R>(self, g: impl FnOnce() -> R) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        if self.cond {
+            (self.f)()
+        } else {
+            g()
+        }
+    }
+}
+
+/// Methods on `Option` mirroring `let Some(x) = ... =>` arms of [`when!`].
+pub trait OptionExt<T> {
+    /// Calls `on_some` with the value if `self` is `Some` and `on_none` otherwise.
+    ///
+    /// ```rust
+    /// use kiam::OptionExt;
+    ///
+    /// let greet = |name: Option<&str>| {
+    ///     name.when_some(|name| format!("Hello, {}!", name), || "Hello!".to_owned())
+    /// };
+    ///
+    /// assert_eq!(greet(Some("Kiam")), "Hello, Kiam!");
+    /// assert_eq!(greet(None), "Hello!");
+    /// ```
+    ///
+    /// This is the same as `Option::map_or_else`, but with the closures in the order of the arms
+    /// of the equivalent [`when!`]:
+    ///
+    /// ```rust
+    /// # let name = Some("Kiam");
+    /// kiam::when! {
+    ///     let Some(name) = name => format!("Hello, {}!", name),
+    ///     _ => "Hello!".to_owned(),
+    /// }
+    /// # ;
+    /// ```
+    fn when_some<R>(self, on_some: impl FnOnce(T) -> R, on_none: impl FnOnce() -> R) -> R;
+}
+
+impl<T> OptionExt<T> for Option<T> {
+    fn when_some<R>(self, on_some: impl FnOnce(T) -> R, on_none: impl FnOnce() -> R) -> R {
+        match self {
+            Some(value) => on_some(value),
+            None => on_none(),
+        }
+    }
+}
+
+/// A timer for the `after` arms of [`select_when!`], which keeps it independent of the runtime.
+///
+/// ```rust,ignore
+/// use core::time::Duration;
+///
+/// struct Tokio;
+///
+/// impl kiam::Timer for Tokio {
+///     type Sleep = tokio::time::Sleep;
+///
+///     fn sleep(&self, duration: Duration) -> Self::Sleep {
+///         tokio::time::sleep(duration)
+///     }
+/// }
+/// ```
+pub trait Timer {
+    /// The future returned by [`sleep`](Timer::sleep).
+    type Sleep: core::future::Future<Output = ()>;
+
+    /// Returns a future which completes after `duration`.
+    fn sleep(&self, duration: core::time::Duration) -> Self::Sleep;
+}
+
+impl<T: Timer + ?Sized> Timer for &T {
+    type Sleep = T::Sleep;
+
+    fn sleep(&self, duration: core::time::Duration) -> Self::Sleep {
+        T::sleep(self, duration)
+    }
+}
+
+/// Values which can be empty, see [`first_non_empty!`].
+pub trait MaybeEmpty {
+    /// Returns `true` if the value is empty.
+    fn is_empty(&self) -> bool;
+}
+
+impl<T: MaybeEmpty + ?Sized> MaybeEmpty for &T {
+    fn is_empty(&self) -> bool {
+        T::is_empty(self)
+    }
+}
+
+impl<T: MaybeEmpty + ?Sized> MaybeEmpty for &mut T {
+    fn is_empty(&self) -> bool {
+        T::is_empty(self)
+    }
+}
+
+impl MaybeEmpty for str {
+    fn is_empty(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl<T> MaybeEmpty for [T] {
+    fn is_empty(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl<T, const N: usize> MaybeEmpty for [T; N] {
+    fn is_empty(&self) -> bool {
+        N == 0
+    }
+}
+
+#[cfg(feature = "alloc")]
+mod alloc_impls {
+    extern crate alloc;
+
+    use alloc::{
+        boxed::Box,
+        collections::{BTreeMap, BTreeSet, BinaryHeap, LinkedList, VecDeque},
+        string::String,
+        vec::Vec,
+    };
+
+    use crate::MaybeEmpty;
+
+    macro_rules! impl_maybe_empty {
+        ($($ty:ty $([$($generics:tt)*])?),* $(,)?) => {
+            $(
+                impl$(<$($generics)*>)? MaybeEmpty for $ty {
+                    fn is_empty(&self) -> bool {
+                        self.is_empty()
+                    }
+                }
+            )*
+        };
+    }
+
+    impl_maybe_empty! {
+        String,
+        Vec<T> [T],
+        VecDeque<T> [T],
+        LinkedList<T> [T],
+        BinaryHeap<T> [T],
+        BTreeSet<T> [T],
+        BTreeMap<K, V> [K, V],
+    }
+
+    impl<T: MaybeEmpty + ?Sized> MaybeEmpty for Box<T> {
+        fn is_empty(&self) -> bool {
+            T::is_empty(self)
+        }
+    }
+}
+
+/// Per-arm hit counters of [`covered_when!`].
+///
+/// Every invocation of [`covered_when!`] has a static [`Site`], which is registered when the
+/// invocation is evaluated for the first time. [`snapshot`] returns the counters of all the
+/// registered sites, so arms which never fire can be found in production without external coverage
+/// tooling.
+///
+/// ```rust
+/// use kiam::coverage;
+///
+/// let classify = |x: i32| kiam::covered_when! {
+///     "negative": x < 0 => -1,
+///     "zero": x == 0 => 0,
+///     _ => 1,
+/// };
+///
+/// classify(-5);
+/// classify(7);
+/// classify(8);
+///
+/// // The only site in this example
+/// let site = &coverage::snapshot()[0];
+/// assert_eq!(site.arms, [("negative", 1), ("zero", 0), ("_", 2)]);
+///
+/// coverage::reset();
+/// ```
+///
+/// Requires the `coverage` feature and `std`.
+///
+/// [`Site`]: crate::coverage::Site
+/// [`snapshot`]: crate::coverage::snapshot
+#[cfg(feature = "coverage")]
+pub mod coverage {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::{
+        sync::{Mutex, PoisonError},
+        vec::Vec,
+    };
+
+    static SITES: Mutex<Vec<&'static Site>> = Mutex::new(Vec::new());
+
+    /// Counters of an invocation of [`covered_when!`](crate::covered_when).
+    pub struct Site {
+        file: &'static str,
+        line: u32,
+        column: u32,
+        arms: &'static [Arm],
+        registered: AtomicBool,
+    }
+
+    /// The counter of a single arm.
+    pub struct Arm {
+        label: &'static str,
+        hits: AtomicUsize,
+    }
+
+    /// The counters of a [`Site`] at the time of [`snapshot`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Hits {
+        /// The file of the invocation
+        pub file: &'static str,
+        /// The line of the invocation
+        pub line: u32,
+        /// The column of the invocation
+        pub column: u32,
+        /// The label (or the condition) of every arm and the number of times it fired
+        pub arms: Vec<(&'static str, usize)>,
+    }
+
+    impl Site {
+        #[doc(hidden)]
+        pub const fn new(file: &'static str, line: u32, column: u32, arms: &'static [Arm]) -> Self {
+            Self {
+                file,
+                line,
+                column,
+                arms,
+                registered: AtomicBool::new(false),
+            }
+        }
+
+        #[doc(hidden)]
+        pub fn enter(&'static self) {
+            if !self.registered.load(Ordering::Relaxed)
+                && !self.registered.swap(true, Ordering::Relaxed)
+            {
+                lock().push(self);
+            }
+        }
+
+        #[doc(hidden)]
+        pub fn hit(&self, arm: usize) {
+            self.arms[arm].hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Arm {
+        #[doc(hidden)]
+        pub const fn new(label: &'static str) -> Self {
+            Self {
+                label,
+                hits: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    /// Returns the counters of all the invocations of [`covered_when!`](crate::covered_when) which
+    /// were evaluated at least once, in the order of the first evaluation.
+    pub fn snapshot() -> Vec<Hits> {
+        lock()
+            .iter()
+            .map(|site| Hits {
+                file: site.file,
+                line: site.line,
+                column: site.column,
+                arms: site
+                    .arms
+                    .iter()
+                    .map(|arm| (arm.label, arm.hits.load(Ordering::Relaxed)))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Resets all the counters to `0`.
+    pub fn reset() {
+        for site in lock().iter() {
+            for arm in site.arms {
+                arm.hits.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn lock() -> std::sync::MutexGuard<'static, Vec<&'static Site>> {
+        // The sites are only pushed, a panic can't leave the vector in an invalid state
+        SITES.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Rules assembled at runtime, the runtime counterpart of [`when!`].
+///
+/// Like the arms of [`when!`], the rules of a rule set are checked in order and the first rule whose
+/// predicate holds decides the output. There are two kinds of rule sets:
+///
+/// - `RuleSet` holds boxed closures and types implementing `Rule`, which can carry their own state,
+///   rules can be added and reordered at runtime. Requires the `alloc` feature.
+/// - [`StaticRuleSet`] holds a static table of function pointers, so it can be built in a `const`
+///   or a `static` and doesn't need a heap, see [`static_rules!`].
+///
+/// Rules can also be described with data instead of code: `Condition` is a condition over the
+/// fields of a context (exposed by implementing `Fields`) and `RuleSet::from_conditions` builds a
+/// rule set from conditions, e.g. read from a configuration file (with the `serde` feature).
+/// Requires the `alloc` feature.
+///
+/// Rules can also pass the context to a `Handler`, which is registered with a predicate deciding
+/// when it's used, see `handlers!`. Requires the `alloc` feature.
+///
+/// `AsyncRuleSet` is a rule set whose predicates and actions are asynchronous. Requires the
+/// `alloc` feature.
+///
+/// `Pipeline` generalizes rule sets to several steps: its stages transform the context in order,
+/// until one of them decides the output. Requires the `alloc` feature.
+///
+/// [`StaticRuleSet`]: crate::rules::StaticRuleSet
+pub mod rules {
+    use core::fmt;
+
+    #[cfg(feature = "alloc")]
+    pub use self::condition::{Condition, Fields, Op, Value};
+    #[cfg(feature = "alloc")]
+    pub use self::dynamic::{
+        AsyncRuleSet, BoxFuture, Explained, Handler, Pipeline, Rule, RuleId, RuleSet,
+    };
+
+    /// Rules checked in order, the first one whose predicate holds decides the output.
+    ///
+    /// The rules are pairs of function pointers (a predicate and an action), so the table can be
+    /// built at compile time:
+    ///
+    /// ```rust
+    /// use kiam::rules::StaticRuleSet;
+    ///
+    /// fn is_ok(status: &u16) -> bool {
+    ///     *status < 300
+    /// }
+    ///
+    /// static STATUSES: StaticRuleSet<u16, &str> = StaticRuleSet::new(&[
+    ///     (is_ok, |_| "ok"),
+    ///     (|status| *status < 400, |_| "redirect"),
+    /// ])
+    /// .otherwise(|_| "error");
+    ///
+    /// assert_eq!(STATUSES.evaluate(&204), Some("ok"));
+    /// assert_eq!(STATUSES.evaluate(&404), Some("error"));
+    /// ```
+    ///
+    /// [`static_rules!`](crate::static_rules) builds the table from the syntax of
+    /// [`when!`](crate::when).
+    pub struct StaticRuleSet<Ctx: ?Sized + 'static, Out: 'static> {
+        rules: &'static [StaticRule<Ctx, Out>],
+        default: Option<fn(&Ctx) -> Out>,
+    }
+
+    /// A rule of a [`StaticRuleSet`]: a predicate and an action.
+    pub type StaticRule<Ctx, Out> = (fn(&Ctx) -> bool, fn(&Ctx) -> Out);
+
+    impl<Ctx: ?Sized, Out> StaticRuleSet<Ctx, Out> {
+        /// Creates a rule set with the rules from `rules`.
+        pub const fn new(rules: &'static [StaticRule<Ctx, Out>]) -> Self {
+            Self {
+                rules,
+                default: None,
+            }
+        }
+
+        /// Sets the action which is run if none of the rules matched, like the default arm of
+        /// [`when!`](crate::when).
+        pub const fn otherwise(self, action: fn(&Ctx) -> Out) -> Self {
+            Self {
+                rules: self.rules,
+                default: Some(action),
+            }
+        }
+
+        /// Runs the action of the first rule whose predicate holds for `ctx`, or the default
+        /// action, if none of them do. Returns `None` if there is no default action and none of
+        /// the rules matched.
+        pub fn evaluate(&self, ctx: &Ctx) -> Option<Out> {
+            match self.rules.iter().find(|(predicate, _)| predicate(ctx)) {
+                Some((_, action)) => Some(action(ctx)),
+                None => self.default.map(|action| action(ctx)),
+            }
+        }
+
+        /// Runs the actions of all the rules whose predicates hold for `ctx`, in order. The
+        /// default action is not run.
+        pub fn evaluate_all<'a>(&'a self, ctx: &'a Ctx) -> impl Iterator<Item = Out> + 'a {
+            self.rules
+                .iter()
+                .filter(move |(predicate, _)| predicate(ctx))
+                .map(move |(_, action)| action(ctx))
+        }
+
+        /// Returns the rules, not including the default action.
+        pub const fn rules(&self) -> &'static [StaticRule<Ctx, Out>] {
+            self.rules
+        }
+
+        /// Returns the number of rules, not counting the default action.
+        pub const fn len(&self) -> usize {
+            self.rules.len()
+        }
+
+        /// Returns `true` if there are no rules.
+        pub const fn is_empty(&self) -> bool {
+            self.rules.is_empty()
+        }
+    }
+
+    impl<Ctx: ?Sized, Out> Clone for StaticRuleSet<Ctx, Out> {
+        fn clone(&self) -> Self {
+            *self
+        }
+    }
+
+    impl<Ctx: ?Sized, Out> Copy for StaticRuleSet<Ctx, Out> {}
+
+    impl<Ctx: ?Sized, Out> fmt::Debug for StaticRuleSet<Ctx, Out> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("StaticRuleSet")
+                .field("rules", &self.rules.len())
+                .field("default", &self.default.is_some())
+                .finish()
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    mod dynamic {
+        extern crate alloc;
+
+        use alloc::{boxed::Box, string::String, vec::Vec};
+        use core::{fmt, future::Future, ops::ControlFlow, pin::Pin};
+
+        type Action<Ctx, Out> = Box<dyn Fn(&Ctx) -> Out>;
+        type AsyncAction<Ctx, Out> = Box<dyn for<'a> Fn(&'a Ctx) -> BoxFuture<'a, Out>>;
+
+        /// A pinned, boxed future, returned by the predicates and the actions of an
+        /// [`AsyncRuleSet`].
+        pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+        type Stage<Ctx, Out> = (
+            Box<dyn Fn(&Ctx) -> bool>,
+            Box<dyn Fn(Ctx) -> ControlFlow<Out, Ctx>>,
+        );
+
+        /// A rule of a [`RuleSet`].
+        ///
+        /// ```rust
+        /// use kiam::rules::{Rule, RuleSet};
+        ///
+        /// /// Matches the requests to paths starting with `prefix`.
+        /// struct Route {
+        ///     prefix: &'static str,
+        ///     backend: &'static str,
+        /// }
+        ///
+        /// impl Rule<str, &'static str> for Route {
+        ///     fn matches(&self, path: &str) -> bool {
+        ///         path.starts_with(self.prefix)
+        ///     }
+        ///
+        ///     fn apply(&self, _: &str) -> &'static str {
+        ///         self.backend
+        ///     }
+        /// }
+        ///
+        /// let routes = RuleSet::new()
+        ///     .with_rule(Route { prefix: "/api/", backend: "api" })
+        ///     .rule(|path: &str| path.ends_with(".png"), |_| "static")
+        ///     .otherwise(|_| "web");
+        ///
+        /// assert_eq!(routes.evaluate("/api/users"), Some("api"));
+        /// assert_eq!(routes.evaluate("/logo.png"), Some("static"));
+        /// ```
+        ///
+        /// Pairs of closures (a predicate and an action) implement this trait too.
+        pub trait Rule<Ctx: ?Sized, Out> {
+            /// Returns `true` if the rule applies to `ctx`.
+            fn matches(&self, ctx: &Ctx) -> bool;
+
+            /// Returns the output of the rule, only called if [`matches`](Rule::matches) returned
+            /// `true` for the same `ctx`.
+            fn apply(&self, ctx: &Ctx) -> Out;
+
+            /// Returns the name of the rule, which is reported by
+            /// [`RuleSet::evaluate_explained`]. Rules don't have a name by default.
+            fn name(&self) -> Option<&str> {
+                None
+            }
+        }
+
+        impl<Ctx: ?Sized, Out, P, A> Rule<Ctx, Out> for (P, A)
+        where
+            P: Fn(&Ctx) -> bool,
+            A: Fn(&Ctx) -> Out,
+        {
+            fn matches(&self, ctx: &Ctx) -> bool {
+                (self.0)(ctx)
+            }
+
+            fn apply(&self, ctx: &Ctx) -> Out {
+                (self.1)(ctx)
+            }
+        }
+
+        impl<Ctx: ?Sized, Out, R: Rule<Ctx, Out> + ?Sized> Rule<Ctx, Out> for Box<R> {
+            fn matches(&self, ctx: &Ctx) -> bool {
+                R::matches(self, ctx)
+            }
+
+            fn apply(&self, ctx: &Ctx) -> Out {
+                R::apply(self, ctx)
+            }
+
+            fn name(&self) -> Option<&str> {
+                R::name(self)
+            }
+        }
+
+        /// A handler registered in a [`RuleSet`] with a predicate which decides when it's used, see
+        /// [`RuleSet::handler`] and [`handlers!`](crate::handlers).
+        ///
+        /// Unlike [`Rule`], a handler doesn't decide whether it applies, so the same handler type
+        /// can be registered for different conditions, e.g. by plugins.
+        pub trait Handler<Ctx: ?Sized, Out> {
+            /// Handles `ctx`.
+            fn handle(&self, ctx: &Ctx) -> Out;
+        }
+
+        impl<Ctx: ?Sized, Out, H: Handler<Ctx, Out> + ?Sized> Handler<Ctx, Out> for Box<H> {
+            fn handle(&self, ctx: &Ctx) -> Out {
+                H::handle(self, ctx)
+            }
+        }
+
+        /// A predicate and an action with a name, see [`RuleSet::named_rule`].
+        struct Named<P, A> {
+            name: String,
+            predicate: P,
+            action: A,
+        }
+
+        impl<Ctx: ?Sized, Out, P, A> Rule<Ctx, Out> for Named<P, A>
+        where
+            P: Fn(&Ctx) -> bool,
+            A: Fn(&Ctx) -> Out,
+        {
+            fn matches(&self, ctx: &Ctx) -> bool {
+                (self.predicate)(ctx)
+            }
+
+            fn apply(&self, ctx: &Ctx) -> Out {
+                (self.action)(ctx)
+            }
+
+            fn name(&self) -> Option<&str> {
+                Some(&self.name)
+            }
+        }
+
+        /// Rules checked in order, the first one which matches decides the output.
+        ///
+        /// ```rust
+        /// use kiam::rules::RuleSet;
+        ///
+        /// struct Order {
+        ///     total: u32,
+        ///     is_vip: bool,
+        /// }
+        ///
+        /// let fees = RuleSet::new()
+        ///     .rule(|order: &Order| order.is_vip, |_| 0)
+        ///     .rule(|order| order.total > 100, |order| order.total / 50)
+        ///     .otherwise(|_| 10);
+        ///
+        /// assert_eq!(fees.evaluate(&Order { total: 250, is_vip: true }), Some(0));
+        /// assert_eq!(fees.evaluate(&Order { total: 250, is_vip: false }), Some(5));
+        /// assert_eq!(fees.evaluate(&Order { total: 20, is_vip: false }), Some(10));
+        /// ```
+        ///
+        /// Rules with a higher priority are checked first, rules with the same priority are
+        /// checked in the order they were added. The priority of a rule is `0` unless it's changed
+        /// with [`set_priority`](RuleSet::set_priority), [`promote`](RuleSet::promote) or
+        /// [`demote`](RuleSet::demote).
+        pub struct RuleSet<Ctx: ?Sized, Out> {
+            /// Sorted by priority (descending) and id
+            rules: Vec<Entry<Ctx, Out>>,
+            default: Option<Action<Ctx, Out>>,
+            next_id: usize,
+        }
+
+        struct Entry<Ctx: ?Sized, Out> {
+            id: RuleId,
+            priority: i32,
+            rule: Box<dyn Rule<Ctx, Out>>,
+        }
+
+        /// The result of [`RuleSet::evaluate_explained`].
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Explained<'a, Out> {
+            /// The output of the rule which matched or of the default action, `None` if there is
+            /// no default action and none of the rules matched
+            pub output: Option<Out>,
+            /// The rule which matched
+            pub rule: Option<RuleId>,
+            /// The position of the rule which matched in the order the rules are checked in
+            pub index: Option<usize>,
+            /// The name of the rule which matched, if it has one
+            pub name: Option<&'a str>,
+            /// How many rules were checked, including the one which matched
+            pub evaluated: usize,
+        }
+
+        /// Identifier of a rule in a [`RuleSet`], returned by [`RuleSet::push`] and
+        /// [`RuleSet::push_rule`].
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct RuleId(usize);
+
+        impl<Ctx: ?Sized, Out> RuleSet<Ctx, Out> {
+            /// Creates a rule set without rules.
+            pub fn new() -> Self {
+                Self {
+                    rules: Vec::new(),
+                    default: None,
+                    next_id: 0,
+                }
+            }
+
+            /// Adds a rule after the existing ones with the same priority.
+            pub fn rule(
+                mut self,
+                predicate: impl Fn(&Ctx) -> bool + 'static,
+                action: impl Fn(&Ctx) -> Out + 'static,
+            ) -> Self {
+                self.push(predicate, action);
+                self
+            }
+
+            /// Adds a rule implementing [`Rule`] after the existing ones with the same priority.
+            pub fn with_rule(mut self, rule: impl Rule<Ctx, Out> + 'static) -> Self {
+                self.push_rule(rule);
+                self
+            }
+
+            /// Adds a rule with a name, which is reported by
+            /// [`evaluate_explained`](RuleSet::evaluate_explained), after the existing ones with
+            /// the same priority.
+            pub fn named_rule(
+                mut self,
+                name: impl Into<String>,
+                predicate: impl Fn(&Ctx) -> bool + 'static,
+                action: impl Fn(&Ctx) -> Out + 'static,
+            ) -> Self {
+                self.push_named(name, predicate, action);
+                self
+            }
+
+            /// Adds a rule which passes the contexts matching `predicate` to `handler`, after the
+            /// existing ones with the same priority.
+            ///
+            /// ```rust
+            /// use kiam::rules::{Handler, RuleSet};
+            ///
+            /// struct Static(&'static str);
+            ///
+            /// impl Handler<str, String> for Static {
+            ///     fn handle(&self, path: &str) -> String {
+            ///         format!("{}{}", self.0, path)
+            ///     }
+            /// }
+            ///
+            /// let routes = RuleSet::new()
+            ///     .handler(|path: &str| path.ends_with(".png"), Static("/srv/images"))
+            ///     .handler(|path| path.ends_with(".css"), Box::new(Static("/srv/css")));
+            ///
+            /// assert_eq!(routes.evaluate("/logo.png").unwrap(), "/srv/images/logo.png");
+            /// ```
+            pub fn handler(
+                mut self,
+                predicate: impl Fn(&Ctx) -> bool + 'static,
+                handler: impl Handler<Ctx, Out> + 'static,
+            ) -> Self {
+                self.push_handler(predicate, handler);
+                self
+            }
+
+            /// Sets the handler which is used if none of the rules matched, see
+            /// [`otherwise`](RuleSet::otherwise).
+            pub fn otherwise_handler(self, handler: impl Handler<Ctx, Out> + 'static) -> Self {
+                self.otherwise(move |ctx| handler.handle(ctx))
+            }
+
+            /// Sets the action which is run if none of the rules matched, like the default arm of
+            /// [`when!`](crate::when).
+            pub fn otherwise(mut self, action: impl Fn(&Ctx) -> Out + 'static) -> Self {
+                self.default = Some(Box::new(action));
+                self
+            }
+
+            /// Same as [`rule`](RuleSet::rule), but doesn't take the rule set by value, which is
+            /// more convenient in loops.
+            pub fn push(
+                &mut self,
+                predicate: impl Fn(&Ctx) -> bool + 'static,
+                action: impl Fn(&Ctx) -> Out + 'static,
+            ) -> RuleId {
+                self.push_rule((predicate, action))
+            }
+
+            /// Same as [`handler`](RuleSet::handler), but doesn't take the rule set by value.
+            pub fn push_handler(
+                &mut self,
+                predicate: impl Fn(&Ctx) -> bool + 'static,
+                handler: impl Handler<Ctx, Out> + 'static,
+            ) -> RuleId {
+                self.push(predicate, move |ctx| handler.handle(ctx))
+            }
+
+            /// Same as [`named_rule`](RuleSet::named_rule), but doesn't take the rule set by
+            /// value.
+            pub fn push_named(
+                &mut self,
+                name: impl Into<String>,
+                predicate: impl Fn(&Ctx) -> bool + 'static,
+                action: impl Fn(&Ctx) -> Out + 'static,
+            ) -> RuleId {
+                self.push_rule(Named {
+                    name: name.into(),
+                    predicate,
+                    action,
+                })
+            }
+
+            /// Same as [`with_rule`](RuleSet::with_rule), but doesn't take the rule set by value.
+            pub fn push_rule(&mut self, rule: impl Rule<Ctx, Out> + 'static) -> RuleId {
+                let id = RuleId(self.next_id);
+                self.next_id += 1;
+                self.rules.push(Entry {
+                    id,
+                    priority: 0,
+                    rule: Box::new(rule),
+                });
+                self.sort();
+                id
+            }
+
+            /// Returns the priority of the rule `id`, or `None` if there is no such rule.
+            pub fn priority(&self, id: RuleId) -> Option<i32> {
+                self.rules
+                    .iter()
+                    .find(|entry| entry.id == id)
+                    .map(|entry| entry.priority)
+            }
+
+            /// Sets the priority of the rule `id`, rules with a higher priority are checked first.
+            ///
+            /// # Panics
+            ///
+            /// Panics if there is no rule `id` in this rule set.
+            pub fn set_priority(&mut self, id: RuleId, priority: i32) {
+                match self.rules.iter_mut().find(|entry| entry.id == id) {
+                    Some(entry) => entry.priority = priority,
+                    None => panic!("there is no rule {:?} in this rule set", id),
+                }
+                self.sort();
+            }
+
+            /// Sets the priority of the rule `id` above the priorities of all the other rules, so
+            /// that it's checked first.
+            ///
+            /// # Panics
+            ///
+            /// Panics if there is no rule `id` in this rule set.
+            pub fn promote(&mut self, id: RuleId) {
+                let others = self.rules.iter().filter(|entry| entry.id != id);
+                let max = others.map(|entry| entry.priority).max();
+                self.set_priority(id, max.map_or(0, |max| max.saturating_add(1)));
+            }
+
+            /// Sets the priority of the rule `id` below the priorities of all the other rules, so
+            /// that it's checked last.
+            ///
+            /// # Panics
+            ///
+            /// Panics if there is no rule `id` in this rule set.
+            pub fn demote(&mut self, id: RuleId) {
+                let others = self.rules.iter().filter(|entry| entry.id != id);
+                let min = others.map(|entry| entry.priority).min();
+                self.set_priority(id, min.map_or(0, |min| min.saturating_sub(1)));
+            }
+
+            fn sort(&mut self) {
+                self.rules
+                    .sort_by_key(|entry| (core::cmp::Reverse(entry.priority), entry.id));
+            }
+
+            /// Applies the first rule which matches `ctx`, or runs the default action, if none of
+            /// them do. Returns `None` if there is no default action and none of the rules matched.
+            pub fn evaluate(&self, ctx: &Ctx) -> Option<Out> {
+                match self.rules.iter().find(|entry| entry.rule.matches(ctx)) {
+                    Some(entry) => Some(entry.rule.apply(ctx)),
+                    None => self.default.as_ref().map(|action| action(ctx)),
+                }
+            }
+
+            /// Same as [`evaluate`](RuleSet::evaluate), but also reports which rule matched and how
+            /// many rules were checked.
+            ///
+            /// ```rust
+            /// use kiam::rules::RuleSet;
+            ///
+            /// let fees = RuleSet::new()
+            ///     .named_rule("vip", |&(_, is_vip): &(u32, bool)| is_vip, |_| 0)
+            ///     .named_rule("large order", |&(total, _)| total > 100, |&(total, _)| total / 50)
+            ///     .otherwise(|_| 10);
+            ///
+            /// let explained = fees.evaluate_explained(&(250, false));
+            /// assert_eq!(explained.output, Some(5));
+            /// assert_eq!(explained.name, Some("large order"));
+            /// assert_eq!((explained.index, explained.evaluated), (Some(1), 2));
+            /// ```
+            pub fn evaluate_explained(&self, ctx: &Ctx) -> Explained<'_, Out> {
+                for (index, entry) in self.rules.iter().enumerate() {
+                    if entry.rule.matches(ctx) {
+                        return Explained {
+                            output: Some(entry.rule.apply(ctx)),
+                            rule: Some(entry.id),
+                            index: Some(index),
+                            name: entry.rule.name(),
+                            evaluated: index + 1,
+                        };
+                    }
+                }
+
+                Explained {
+                    output: self.default.as_ref().map(|action| action(ctx)),
+                    rule: None,
+                    index: None,
+                    name: None,
+                    evaluated: self.rules.len(),
+                }
+            }
+
+            /// Applies all the rules which match `ctx`, in order, like
+            /// [`when_all!`](crate::when_all). The default action is not run.
+            ///
+            /// The rules are applied lazily, as the returned iterator is advanced.
+            pub fn evaluate_all<'a>(&'a self, ctx: &'a Ctx) -> impl Iterator<Item = Out> + 'a {
+                self.rules
+                    .iter()
+                    .filter(move |entry| entry.rule.matches(ctx))
+                    .map(move |entry| entry.rule.apply(ctx))
+            }
+
+            /// Returns the number of rules, not counting the default action.
+            pub fn len(&self) -> usize {
+                self.rules.len()
+            }
+
+            /// Returns `true` if there are no rules.
+            pub fn is_empty(&self) -> bool {
+                self.rules.is_empty()
+            }
+        }
+
+        impl<Ctx: ?Sized, Out> Default for RuleSet<Ctx, Out> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<Ctx: ?Sized, Out> fmt::Debug for RuleSet<Ctx, Out> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct("RuleSet")
+                    .field("rules", &self.rules.len())
+                    .field("default", &self.default.is_some())
+                    .finish()
+            }
+        }
+
+        /// Same as [`RuleSet`], but the predicates and the actions are asynchronous, e.g. to
+        /// consult a cache or a remote service.
+        ///
+        /// The predicates and the actions return [`BoxFuture`]s, usually `Box::pin(async move { ...
+        /// })`. The rules are checked sequentially: each predicate is awaited before the next one
+        /// is started. Doesn't depend on a runtime, so it can be used with any executor.
+        ///
+        /// ```rust
+        /// use kiam::rules::AsyncRuleSet;
+        ///
+        /// async fn is_blocked(user: &str) -> bool {
+        ///     user == "mallory"
+        /// }
+        ///
+        /// let access = AsyncRuleSet::new()
+        ///     .rule(|user: &str| Box::pin(is_blocked(user)), |_| Box::pin(async { "denied" }))
+        ///     .rule(|user| Box::pin(async move { user == "root" }), |_| Box::pin(async { "all" }))
+        ///     .otherwise(|_| Box::pin(async { "user" }));
+        ///
+        /// # async fn example(access: AsyncRuleSet<str, &str>) {
+        /// assert_eq!(access.evaluate("mallory").await, Some("denied"));
+        /// assert_eq!(access.evaluate("root").await, Some("all"));
+        /// assert_eq!(access.evaluate("alice").await, Some("user"));
+        /// # }
+        /// ```
+        pub struct AsyncRuleSet<Ctx: ?Sized, Out> {
+            rules: Vec<(AsyncAction<Ctx, bool>, AsyncAction<Ctx, Out>)>,
+            default: Option<AsyncAction<Ctx, Out>>,
+        }
+
+        impl<Ctx: ?Sized, Out> AsyncRuleSet<Ctx, Out> {
+            /// Creates a rule set without rules.
+            pub fn new() -> Self {
+                Self {
+                    rules: Vec::new(),
+                    default: None,
+                }
+            }
+
+            /// Adds a rule after the existing ones.
+            pub fn rule<P, A>(mut self, predicate: P, action: A) -> Self
+            where
+                P: for<'a> Fn(&'a Ctx) -> BoxFuture<'a, bool> + 'static,
+                A: for<'a> Fn(&'a Ctx) -> BoxFuture<'a, Out> + 'static,
+            {
+                self.push(predicate, action);
+                self
+            }
+
+            /// Sets the action which is run if none of the rules matched.
+            pub fn otherwise<A>(mut self, action: A) -> Self
+            where
+                A: for<'a> Fn(&'a Ctx) -> BoxFuture<'a, Out> + 'static,
+            {
+                self.default = Some(Box::new(action));
+                self
+            }
+
+            /// Same as [`rule`](AsyncRuleSet::rule), but doesn't take the rule set by value.
+            pub fn push<P, A>(&mut self, predicate: P, action: A)
+            where
+                P: for<'a> Fn(&'a Ctx) -> BoxFuture<'a, bool> + 'static,
+                A: for<'a> Fn(&'a Ctx) -> BoxFuture<'a, Out> + 'static,
+            {
+                self.rules.push((Box::new(predicate), Box::new(action)));
+            }
+
+            /// Awaits the predicates in order and applies the first rule which matches `ctx`, or
+            /// runs the default action, if none of them do. Returns `None` if there is no default
+            /// action and none of the rules matched.
+            pub async fn evaluate(&self, ctx: &Ctx) -> Option<Out> {
+                for (predicate, action) in &self.rules {
+                    if predicate(ctx).await {
+                        return Some(action(ctx).await);
+                    }
+                }
+
+                match &self.default {
+                    Some(action) => Some(action(ctx).await),
+                    None => None,
+                }
+            }
+
+            /// Returns the number of rules, not counting the default action.
+            pub fn len(&self) -> usize {
+                self.rules.len()
+            }
+
+            /// Returns `true` if there are no rules.
+            pub fn is_empty(&self) -> bool {
+                self.rules.is_empty()
+            }
+        }
+
+        impl<Ctx: ?Sized, Out> Default for AsyncRuleSet<Ctx, Out> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<Ctx: ?Sized, Out> fmt::Debug for AsyncRuleSet<Ctx, Out> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct("AsyncRuleSet")
+                    .field("rules", &self.rules.len())
+                    .field("default", &self.default.is_some())
+                    .finish()
+            }
+        }
+
+        /// Stages run in order, each one transforming the context, until one of them decides the
+        /// output, like middleware.
+        ///
+        /// A stage is a condition and a transform, the transform runs only if the condition holds
+        /// for the current context. It returns either [`ControlFlow::Continue`] with the context
+        /// for the next stages or [`ControlFlow::Break`] with the output, which stops the pipeline.
+        ///
+        /// ```rust
+        /// use core::ops::ControlFlow;
+        /// use kiam::rules::Pipeline;
+        ///
+        /// #[derive(Debug, PartialEq)]
+        /// struct Request {
+        ///     path: String,
+        ///     user: Option<&'static str>,
+        /// }
+        ///
+        /// let pipeline = Pipeline::new()
+        ///     .stage(
+        ///         |req: &Request| req.path.ends_with('/'),
+        ///         |mut req| {
+        ///             req.path.pop();
+        ///             ControlFlow::Continue(req)
+        ///         },
+        ///     )
+        ///     .stage(
+        ///         |req| req.path.starts_with("/admin") && req.user.is_none(),
+        ///         |_| ControlFlow::Break(401),
+        ///     )
+        ///     .stage(|req| req.path == "/admin", |_| ControlFlow::Break(200));
+        ///
+        /// let request = |path: &str, user| Request { path: path.into(), user };
+        ///
+        /// assert_eq!(pipeline.run(request("/admin/", None)), ControlFlow::Break(401));
+        /// assert_eq!(pipeline.run(request("/admin/", Some("root"))), ControlFlow::Break(200));
+        /// assert_eq!(
+        ///     pipeline.run(request("/home/", None)),
+        ///     ControlFlow::Continue(request("/home", None)),
+        /// );
+        /// ```
+        pub struct Pipeline<Ctx, Out> {
+            stages: Vec<Stage<Ctx, Out>>,
+        }
+
+        impl<Ctx, Out> Pipeline<Ctx, Out> {
+            /// Creates a pipeline without stages.
+            pub fn new() -> Self {
+                Self { stages: Vec::new() }
+            }
+
+            /// Adds a stage after the existing ones.
+            pub fn stage(
+                mut self,
+                condition: impl Fn(&Ctx) -> bool + 'static,
+                transform: impl Fn(Ctx) -> ControlFlow<Out, Ctx> + 'static,
+            ) -> Self {
+                self.push(condition, transform);
+                self
+            }
+
+            /// Same as [`stage`](Pipeline::stage), but doesn't take the pipeline by value.
+            pub fn push(
+                &mut self,
+                condition: impl Fn(&Ctx) -> bool + 'static,
+                transform: impl Fn(Ctx) -> ControlFlow<Out, Ctx> + 'static,
+            ) {
+                self.stages.push((Box::new(condition), Box::new(transform)));
+            }
+
+            /// Threads `ctx` through the stages whose conditions hold. Returns the output of the
+            /// stage which broke, or the context after the last stage if none of them did.
+            pub fn run(&self, mut ctx: Ctx) -> ControlFlow<Out, Ctx> {
+                for (condition, transform) in &self.stages {
+                    if condition(&ctx) {
+                        ctx = transform(ctx)?;
+                    }
+                }
+
+                ControlFlow::Continue(ctx)
+            }
+
+            /// Returns the number of stages.
+            pub fn len(&self) -> usize {
+                self.stages.len()
+            }
+
+            /// Returns `true` if there are no stages.
+            pub fn is_empty(&self) -> bool {
+                self.stages.is_empty()
+            }
+        }
+
+        impl<Ctx, Out> Default for Pipeline<Ctx, Out> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<Ctx, Out> fmt::Debug for Pipeline<Ctx, Out> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct("Pipeline")
+                    .field("stages", &self.stages.len())
+                    .finish()
+            }
+        }
+    }
+    #[cfg(feature = "alloc")]
+    mod condition {
+        extern crate alloc;
+
+        use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+        use core::cmp::Ordering;
+
+        use super::RuleSet;
+
+        /// Access to the fields of a context by name, which [`Condition`]s are evaluated against.
+        ///
+        /// ```rust
+        /// use kiam::rules::{Fields, Value};
+        ///
+        /// struct Order {
+        ///     total: i64,
+        ///     country: &'static str,
+        /// }
+        ///
+        /// impl Fields for Order {
+        ///     fn field(&self, name: &str) -> Option<Value> {
+        ///         match name {
+        ///             "total" => Some(Value::Int(self.total)),
+        ///             "country" => Some(Value::Str(self.country.into())),
+        ///             _ => None,
+        ///         }
+        ///     }
+        /// }
+        /// ```
+        pub trait Fields {
+            /// Returns the value of the field `name`, or `None` if there is no such field.
+            fn field(&self, name: &str) -> Option<Value>;
+        }
+
+        impl Fields for BTreeMap<String, Value> {
+            fn field(&self, name: &str) -> Option<Value> {
+                self.get(name).cloned()
+            }
+        }
+
+        impl<T: Fields + ?Sized> Fields for &T {
+            fn field(&self, name: &str) -> Option<Value> {
+                T::field(self, name)
+            }
+        }
+
+        /// The value of a field, or a value a field is compared with.
+        ///
+        /// With the `serde` feature, values are (de)serialized as plain booleans, numbers and
+        /// strings.
+        #[derive(Debug, Clone, PartialEq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(untagged))]
+        pub enum Value {
+            /// A boolean
+            Bool(bool),
+            /// An integer, comparable with [`Float`](Value::Float)s
+            Int(i64),
+            /// A floating point number, comparable with [`Int`](Value::Int)s
+            Float(f64),
+            /// A string
+            Str(String),
+        }
+
+        impl Value {
+            /// Compares values of the same kind (and integers with floats), returns `None` for
+            /// values which can't be compared.
+            pub fn compare(&self, other: &Value) -> Option<Ordering> {
+                match (self, other) {
+                    (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+                    (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+                    (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+                    (&Value::Int(a), Value::Float(b)) => (a as f64).partial_cmp(b),
+                    (Value::Float(a), &Value::Int(b)) => a.partial_cmp(&(b as f64)),
+                    (Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
+                    _ => None,
+                }
+            }
+        }
+
+        /// A comparison operator of [`Condition::Compare`].
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+        pub enum Op {
+            /// `==`
+            Eq,
+            /// `!=`
+            Ne,
+            /// `<`
+            Lt,
+            /// `<=`
+            Le,
+            /// `>`
+            Gt,
+            /// `>=`
+            Ge,
+        }
+
+        /// A condition over the [`Fields`] of a context, which can be built from data (e.g. read
+        /// from a configuration file) instead of code.
+        ///
+        /// A condition on a field which doesn't exist or has a value which can't be compared with
+        /// the values in the condition doesn't hold (but `Not` of it does).
+        ///
+        /// ```rust
+        /// use kiam::rules::{Condition, Op, Value};
+        /// use std::collections::BTreeMap;
+        ///
+        /// // total >= 100 && country in ["DE", "FR"]
+        /// let condition = Condition::All(vec![
+        ///     Condition::Compare {
+        ///         field: "total".into(),
+        ///         op: Op::Ge,
+        ///         value: Value::Int(100),
+        ///     },
+        ///     Condition::In {
+        ///         field: "country".into(),
+        ///         values: vec![Value::Str("DE".into()), Value::Str("FR".into())],
+        ///     },
+        /// ]);
+        ///
+        /// let mut order = BTreeMap::new();
+        /// order.insert("total".to_owned(), Value::Float(150.0));
+        /// order.insert("country".to_owned(), Value::Str("FR".into()));
+        /// assert!(condition.matches(&order));
+        ///
+        /// order.insert("country".to_owned(), Value::Str("US".into()));
+        /// assert!(!condition.matches(&order));
+        /// ```
+        ///
+        /// With the `serde` feature, conditions implement `Serialize` and `Deserialize`, the
+        /// variants and the operators are written in snake case. The condition above is written as
+        /// this in JSON:
+        ///
+        /// ```json
+        /// {
+        ///     "all": [
+        ///         { "compare": { "field": "total", "op": "ge", "value": 100 } },
+        ///         { "in": { "field": "country", "values": ["DE", "FR"] } }
+        ///     ]
+        /// }
+        /// ```
+        #[derive(Debug, Clone, PartialEq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+        pub enum Condition {
+            /// Always holds, like the default arm of [`when!`](crate::when)
+            Always,
+            /// Holds if the field compares with `value` as `op` says
+            Compare {
+                /// The name of the field
+                field: String,
+                /// The operator
+                op: Op,
+                /// The value the field is compared with
+                value: Value,
+            },
+            /// Holds if the field is in `start..end`, a missing bound is unbounded
+            Range {
+                /// The name of the field
+                field: String,
+                /// The inclusive start of the range
+                start: Option<Value>,
+                /// The exclusive end of the range
+                end: Option<Value>,
+            },
+            /// Holds if the field is equal to one of `values`
+            In {
+                /// The name of the field
+                field: String,
+                /// The values the field is compared with
+                values: Vec<Value>,
+            },
+            /// Holds if all of the conditions hold (or if there are none)
+            All(Vec<Condition>),
+            /// Holds if any of the conditions holds
+            Any(Vec<Condition>),
+            /// Holds if the condition doesn't
+            Not(Box<Condition>),
+        }
+
+        impl Condition {
+            /// Returns `true` if the condition holds for `ctx`.
+            pub fn matches<Ctx: Fields + ?Sized>(&self, ctx: &Ctx) -> bool {
+                let cmp = |field: &str, value: &Value| ctx.field(field)?.compare(value);
+
+                match self {
+                    Condition::Always => true,
+                    Condition::Compare { field, op, value } => match cmp(field, value) {
+                        Some(ordering) => match op {
+                            Op::Eq => ordering == Ordering::Equal,
+                            Op::Ne => ordering != Ordering::Equal,
+                            Op::Lt => ordering == Ordering::Less,
+                            Op::Le => ordering != Ordering::Greater,
+                            Op::Gt => ordering == Ordering::Greater,
+                            Op::Ge => ordering != Ordering::Less,
+                        },
+                        None => false,
+                    },
+                    Condition::Range { field, start, end } => {
+                        let after_start = match start {
+                            Some(start) => {
+                                matches!(cmp(field, start), Some(o) if o != Ordering::Less)
+                            }
+                            None => ctx.field(field).is_some(),
+                        };
+                        let before_end = match end {
+                            Some(end) => cmp(field, end) == Some(Ordering::Less),
+                            None => true,
+                        };
+                        after_start && before_end
+                    }
+                    Condition::In { field, values } => values
+                        .iter()
+                        .any(|value| cmp(field, value) == Some(Ordering::Equal)),
+                    Condition::All(conditions) => conditions.iter().all(|c| c.matches(ctx)),
+                    Condition::Any(conditions) => conditions.iter().any(|c| c.matches(ctx)),
+                    Condition::Not(condition) => !condition.matches(ctx),
+                }
+            }
+        }
+
+        impl<Ctx: Fields + ?Sized, Out: Clone + 'static> RuleSet<Ctx, Out> {
+            /// Creates a rule set from pairs of a condition and the output of the rule, e.g. read
+            /// from a configuration file.
+            ///
+            /// ```rust
+            /// use kiam::rules::{Condition, Op, RuleSet, Value};
+            /// use std::collections::BTreeMap;
+            ///
+            /// let total = |op, value| Condition::Compare {
+            ///     field: "total".into(),
+            ///     op,
+            ///     value: Value::Int(value),
+            /// };
+            /// let fees = RuleSet::from_conditions(vec![
+            ///     (total(Op::Gt, 1000), 0),
+            ///     (total(Op::Gt, 100), 5),
+            ///     (Condition::Always, 10),
+            /// ]);
+            ///
+            /// let order: BTreeMap<_, _> = vec![("total".to_owned(), Value::Int(250))]
+            ///     .into_iter()
+            ///     .collect();
+            /// assert_eq!(fees.evaluate(&order), Some(5));
+            /// ```
+            pub fn from_conditions(rules: impl IntoIterator<Item = (Condition, Out)>) -> Self {
+                let mut set = Self::new();
+                for (condition, output) in rules {
+                    set.push(move |ctx| condition.matches(ctx), move |_| output.clone());
+                }
+                set
+            }
+        }
+    }
+}
+
+// Branch prediction hints of `#[likely]` and `#[unlikely]` arms. On stable the hints are expressed
+// by calling a `#[cold]` function on the unlikely path.
+#[doc(hidden)]
+#[inline(always)]
+pub const fn __likely(b: bool) -> bool {
+    #[cfg(feature = "nightly")]
+    {
+        core::hint::likely(b)
+    }
+    #[cfg(not(feature = "nightly"))]
+    {
+        if !b {
+            __cold();
+        }
+        b
+    }
+}
+
+#[doc(hidden)]
+#[inline(always)]
+pub const fn __unlikely(b: bool) -> bool {
+    #[cfg(feature = "nightly")]
+    {
+        core::hint::unlikely(b)
+    }
+    #[cfg(not(feature = "nightly"))]
+    {
+        if b {
+            __cold();
+        }
+        b
+    }
+}
+
+#[doc(hidden)]
+#[cold]
+pub const fn __cold() {}
+
+#[cfg(test)]
+// Constant conditions and unreachable arms are used on purpose, `kiam_macros::when!` would report
+// them
+#[cfg_attr(feature = "macros", allow(deprecated))]
+mod tests {
+    #[test]
+    fn it_works() {
+        let r = when! {
+            false => 0,
+            true => 1,
+            true => 2,
+            _ => 42,
+        };
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    fn many_arms() {
+        let f = |x: u32| {
+            when! {
+                x == 0 => 0, x == 1 => 1, x == 2 => 2, x == 3 => 3,
+                x == 4 => 4, x == 5 => 5, x == 6 => 6, x == 7 => 7,
+                x == 8 => 8, x == 9 => 9, x == 10 => 10, x == 11 => 11,
+                x == 12 => 12, x == 13 => 13, x == 14 => 14, x == 15 => 15,
+                x == 16 => 16, x == 17 => 17, x == 18 => 18, x == 19 => 19,
+                x == 20 => 20, x == 21 => 21, x == 22 => 22, x == 23 => 23,
+                x == 24 => 24, x == 25 => 25, x == 26 => 26, x == 27 => 27,
+                x == 28 => 28, x == 29 => 29, x == 30 => 30, x == 31 => 31,
+                x == 32 => 32, x == 33 => 33, x == 34 => 34, x == 35 => 35,
+                x == 36 => 36, x == 37 => 37, x == 38 => 38, x == 39 => 39,
+                x == 40 => 40, x == 41 => 41, x == 42 => 42, x == 43 => 43,
+                x == 44 => 44, x == 45 => 45, x == 46 => 46, x == 47 => 47,
+                x == 48 => 48, x == 49 => 49, x == 50 => 50, x == 51 => 51,
+                x == 52 => 52, x == 53 => 53, x == 54 => 54, x == 55 => 55,
+                x == 56 => 56, x == 57 => 57, x == 58 => 58, x == 59 => 59,
+                x == 60 => 60, x == 61 => 61, x == 62 => 62, x == 63 => 63,
+                x == 64 => 64, x == 65 => 65, x == 66 => 66, x == 67 => 67,
+                x == 68 => 68, x == 69 => 69, x == 70 => 70, x == 71 => 71,
+                x == 72 => 72, x == 73 => 73, x == 74 => 74, x == 75 => 75,
+                x == 76 => 76, x == 77 => 77, x == 78 => 78, x == 79 => 79,
+                x == 80 => 80, x == 81 => 81, x == 82 => 82, x == 83 => 83,
+                x == 84 => 84, x == 85 => 85, x == 86 => 86, x == 87 => 87,
+                x == 88 => 88, x == 89 => 89, x == 90 => 90, x == 91 => 91,
+                x == 92 => 92, x == 93 => 93, x == 94 => 94, x == 95 => 95,
+                x == 96 => 96, x == 97 => 97, x == 98 => 98, x == 99 => 99,
+                x == 100 => 100, x == 101 => 101, x == 102 => 102, x == 103 => 103,
+                x == 104 => 104, x == 105 => 105, x == 106 => 106, x == 107 => 107,
+                x == 108 => 108, x == 109 => 109, x == 110 => 110, x == 111 => 111,
+                x == 112 => 112, x == 113 => 113, x == 114 => 114, x == 115 => 115,
+                x == 116 => 116, x == 117 => 117, x == 118 => 118, x == 119 => 119,
+                x == 120 => 120, x == 121 => 121, x == 122 => 122, x == 123 => 123,
+                x == 124 => 124, x == 125 => 125, x == 126 => 126, x == 127 => 127,
+                x == 128 => 128, x == 129 => 129, x == 130 => 130, x == 131 => 131,
+                x == 132 => 132, x == 133 => 133, x == 134 => 134, x == 135 => 135,
+                x == 136 => 136, x == 137 => 137, x == 138 => 138, x == 139 => 139,
+                x == 140 => 140, x == 141 => 141, x == 142 => 142, x == 143 => 143,
+                x == 144 => 144, x == 145 => 145, x == 146 => 146, x == 147 => 147,
+                x == 148 => 148, x == 149 => 149, x == 150 => 150, x == 151 => 151,
+                x == 152 => 152, x == 153 => 153, x == 154 => 154, x == 155 => 155,
+                x == 156 => 156, x == 157 => 157, x == 158 => 158, x == 159 => 159,
+                x == 160 => 160, x == 161 => 161, x == 162 => 162, x == 163 => 163,
+                x == 164 => 164, x == 165 => 165, x == 166 => 166, x == 167 => 167,
+                x == 168 => 168, x == 169 => 169, x == 170 => 170, x == 171 => 171,
+                x == 172 => 172, x == 173 => 173, x == 174 => 174, x == 175 => 175,
+                x == 176 => 176, x == 177 => 177, x == 178 => 178, x == 179 => 179,
+                x == 180 => 180, x == 181 => 181, x == 182 => 182, x == 183 => 183,
+                x == 184 => 184, x == 185 => 185, x == 186 => 186, x == 187 => 187,
+                x == 188 => 188, x == 189 => 189, x == 190 => 190, x == 191 => 191,
+                x == 192 => 192, x == 193 => 193, x == 194 => 194, x == 195 => 195,
+                x == 196 => 196, x == 197 => 197, x == 198 => 198, x == 199 => 199,
+                x == 200 => 200, x == 201 => 201, x == 202 => 202, x == 203 => 203,
+                x == 204 => 204, x == 205 => 205, x == 206 => 206, x == 207 => 207,
+                x == 208 => 208, x == 209 => 209, x == 210 => 210, x == 211 => 211,
+                x == 212 => 212, x == 213 => 213, x == 214 => 214, x == 215 => 215,
+                x == 216 => 216, x == 217 => 217, x == 218 => 218, x == 219 => 219,
+                x == 220 => 220, x == 221 => 221, x == 222 => 222, x == 223 => 223,
+                x == 224 => 224, x == 225 => 225, x == 226 => 226, x == 227 => 227,
+                x == 228 => 228, x == 229 => 229, x == 230 => 230, x == 231 => 231,
+                x == 232 => 232, x == 233 => 233, x == 234 => 234, x == 235 => 235,
+                x == 236 => 236, x == 237 => 237, x == 238 => 238, x == 239 => 239,
+                x == 240 => 240, x == 241 => 241, x == 242 => 242, x == 243 => 243,
+                x == 244 => 244, x == 245 => 245, x == 246 => 246, x == 247 => 247,
+                x == 248 => 248, x == 249 => 249, x == 250 => 250, x == 251 => 251,
+                x == 252 => 252, x == 253 => 253, x == 254 => 254, x == 255 => 255,
+                x == 256 => 256, x == 257 => 257, x == 258 => 258, x == 259 => 259,
+                x == 260 => 260, x == 261 => 261, x == 262 => 262, x == 263 => 263,
+                x == 264 => 264, x == 265 => 265, x == 266 => 266, x == 267 => 267,
+                x == 268 => 268, x == 269 => 269, x == 270 => 270, x == 271 => 271,
+                x == 272 => 272, x == 273 => 273, x == 274 => 274, x == 275 => 275,
+                x == 276 => 276, x == 277 => 277, x == 278 => 278, x == 279 => 279,
+                x == 280 => 280, x == 281 => 281, x == 282 => 282, x == 283 => 283,
+                x == 284 => 284, x == 285 => 285, x == 286 => 286, x == 287 => 287,
+                x == 288 => 288, x == 289 => 289, x == 290 => 290, x == 291 => 291,
+                x == 292 => 292, x == 293 => 293, x == 294 => 294, x == 295 => 295,
+                x == 296 => 296, x == 297 => 297, x == 298 => 298, x == 299 => 299,
+                let Some(y) = x.checked_sub(1000) => y,
+                _ => 300,
+            }
+        };
+
+        assert_eq!(f(0), 0);
+        assert_eq!(f(299), 299);
+        assert_eq!(f(1002), 2);
+        assert_eq!(f(500), 300);
+    }
+
+    #[test]
+    fn many_complex_arms() {
+        let f = |x: Option<u32>| {
+            when! {
+                let Some(y) = x if y == 0 => 0, let Some(y) = x, y == 1 => 1,
+                x == Some(2) or x == Some(1002) => 2, x matches Some(3) => 3,
+                let Some(y) = x if y == 4 => 4, let Some(y) = x, y == 5 => 5,
+                x == Some(6) or x == Some(1006) => 6, x matches Some(7) => 7,
+                let Some(y) = x if y == 8 => 8, let Some(y) = x, y == 9 => 9,
+                x == Some(10) or x == Some(1010) => 10, x matches Some(11) => 11,
+                let Some(y) = x if y == 12 => 12, let Some(y) = x, y == 13 => 13,
+                x == Some(14) or x == Some(1014) => 14, x matches Some(15) => 15,
+                let Some(y) = x if y == 16 => 16, let Some(y) = x, y == 17 => 17,
+                x == Some(18) or x == Some(1018) => 18, x matches Some(19) => 19,
+                let Some(y) = x if y == 20 => 20, let Some(y) = x, y == 21 => 21,
+                x == Some(22) or x == Some(1022) => 22, x matches Some(23) => 23,
+                let Some(y) = x if y == 24 => 24, let Some(y) = x, y == 25 => 25,
+                x == Some(26) or x == Some(1026) => 26, x matches Some(27) => 27,
+                let Some(y) = x if y == 28 => 28, let Some(y) = x, y == 29 => 29,
+                x == Some(30) or x == Some(1030) => 30, x matches Some(31) => 31,
+                let Some(y) = x if y == 32 => 32, let Some(y) = x, y == 33 => 33,
+                x == Some(34) or x == Some(1034) => 34, x matches Some(35) => 35,
+                let Some(y) = x if y == 36 => 36, let Some(y) = x, y == 37 => 37,
+                x == Some(38) or x == Some(1038) => 38, x matches Some(39) => 39,
+                let Some(y) = x if y == 40 => 40, let Some(y) = x, y == 41 => 41,
+                x == Some(42) or x == Some(1042) => 42, x matches Some(43) => 43,
+                let Some(y) = x if y == 44 => 44, let Some(y) = x, y == 45 => 45,
+                x == Some(46) or x == Some(1046) => 46, x matches Some(47) => 47,
+                let Some(y) = x if y == 48 => 48, let Some(y) = x, y == 49 => 49,
+                x == Some(50) or x == Some(1050) => 50, x matches Some(51) => 51,
+                let Some(y) = x if y == 52 => 52, let Some(y) = x, y == 53 => 53,
+                x == Some(54) or x == Some(1054) => 54, x matches Some(55) => 55,
+                let Some(y) = x if y == 56 => 56, let Some(y) = x, y == 57 => 57,
+                x == Some(58) or x == Some(1058) => 58, x matches Some(59) => 59,
+                let Some(y) = x if y == 60 => 60, let Some(y) = x, y == 61 => 61,
+                x == Some(62) or x == Some(1062) => 62, x matches Some(63) => 63,
+                let Some(y) = x if y == 64 => 64, let Some(y) = x, y == 65 => 65,
+                x == Some(66) or x == Some(1066) => 66, x matches Some(67) => 67,
+                let Some(y) = x if y == 68 => 68, let Some(y) = x, y == 69 => 69,
+                x == Some(70) or x == Some(1070) => 70, x matches Some(71) => 71,
+                let Some(y) = x if y == 72 => 72, let Some(y) = x, y == 73 => 73,
+                x == Some(74) or x == Some(1074) => 74, x matches Some(75) => 75,
+                let Some(y) = x if y == 76 => 76, let Some(y) = x, y == 77 => 77,
+                x == Some(78) or x == Some(1078) => 78, x matches Some(79) => 79,
+                let Some(y) = x if y == 80 => 80, let Some(y) = x, y == 81 => 81,
+                x == Some(82) or x == Some(1082) => 82, x matches Some(83) => 83,
+                let Some(y) = x if y == 84 => 84, let Some(y) = x, y == 85 => 85,
+                x == Some(86) or x == Some(1086) => 86, x matches Some(87) => 87,
+                let Some(y) = x if y == 88 => 88, let Some(y) = x, y == 89 => 89,
+                x == Some(90) or x == Some(1090) => 90, x matches Some(91) => 91,
+                let Some(y) = x if y == 92 => 92, let Some(y) = x, y == 93 => 93,
+                x == Some(94) or x == Some(1094) => 94, x matches Some(95) => 95,
+                let Some(y) = x if y == 96 => 96, let Some(y) = x, y == 97 => 97,
+                x == Some(98) or x == Some(1098) => 98, x matches Some(99) => 99,
+                _ => 1000,
+            }
+        };
+        assert_eq!(f(Some(0)), 0);
+        assert_eq!(f(Some(1)), 1);
+        assert_eq!(f(Some(98)), 98);
+        assert_eq!(f(Some(1002)), 2);
+        assert_eq!(f(Some(1003)), 1000);
+        assert_eq!(f(None), 1000);
+
+        // A long guard isn't scanned token by token either
+        let g = |x: Option<u32>| {
+            when! {
+                let Some(y) = x if
+                    y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y +
+                    y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y +
+                    y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y +
+                    y + y + y + y + y + y + y + y + y + y + y + y + y + y + y + y == 70 => 1,
+                _ => 0,
+            }
+        };
+        assert_eq!(g(Some(1)), 1);
+        assert_eq!(g(Some(2)), 0);
+    }
+
+    #[test]
+    fn pattern() {
+        let r = when! {
+            let Some(x) = None => x,
+            let Some(y) = Some(12) => y + 1,
+            _ => 0,
+        };
+
+        assert_eq!(r, 13);
+    }
+
+    #[test]
+    fn guard() {
+        let r = when! {
+            let Some(x) = Some(2) if x > 3 => x,
+            let Some(x) = Some(5) if x > 3 => x + 1,
+            _ => 0,
+        };
+
+        assert_eq!(r, 6);
+    }
+
+    #[test]
+    fn chain() {
+        let parse = |s: &str| s.parse::<i32>();
+
+        let r = when! {
+            let Some(a) = Some("x"), let Ok(b) = parse(a) => b,
+            let Some(a) = Some("3"), let Ok(b) = parse(a), let Some(c) = b.checked_mul(2) => c,
+            _ => 0,
+        };
+
+        assert_eq!(r, 6);
+
+        let r = when! {
+            let Some(a) = Some(1), let Some(b) = Some(a + 1) if b > 2 => 1,
+            _ => 2,
+        };
+
+        assert_eq!(r, 2);
+    }
+
+    #[test]
+    fn chain_mixed() {
+        let config = Some((true, "5"));
+
+        let r = when! {
+            let Some((enabled, _)) = config, !enabled => 0,
+            let Some((enabled, n)) = config, enabled, let Ok(n) = n.parse::<i32>() => n,
+            _ => 1,
+        };
+
+        assert_eq!(r, 5);
+
+        let r = when! {
+            false, let Some(x) = Some(1) => x,
+            true, true, let Some(x) = Some(2) => x,
+            _ => 0,
+        };
+
+        assert_eq!(r, 2);
+    }
+
+    #[test]
+    fn or_pattern() {
+        let classify = |x: Option<i32>| {
+            when! {
+                let Some(1) | Some(2) = x => 0,
+                let Some(n) | Some(n) = x if n > 10 => 1,
+                let Some(0) | None = x, x.is_none() => 2,
+                _ => 3,
+            }
+        };
+
+        assert_eq!(classify(Some(2)), 0);
+        assert_eq!(classify(Some(11)), 1);
+        assert_eq!(classify(None), 2);
+        assert_eq!(classify(Some(5)), 3);
+    }
+
+    #[test]
+    fn matches() {
+        let classify = |x: Option<i32>| {
+            when! {
+                x matches Some(1) | Some(2) => 0,
+                x matches Some(n) if n > 10 => 1,
+                x.is_some(), x.map(|n| n * 2) matches Some(8) => 2,
+                _ => 3,
+            }
+        };
+
+        assert_eq!(classify(Some(1)), 0);
+        assert_eq!(classify(Some(11)), 1);
+        assert_eq!(classify(Some(4)), 2);
+        assert_eq!(classify(None), 3);
+    }
+
+    #[test]
+    fn r#else() {
+        let r = when! {
+            false => 0,
+            else => 1,
+        };
+
+        assert_eq!(r, 1);
+
+        let r = when! {
+            r;
+            0 => 0,
+            else => 2,
+        };
+
+        assert_eq!(r, 2);
+    }
+
+    #[test]
+    fn blocks() {
+        let mut x = 0;
+
+        when! {
+            false => { x = 1; }
+            let Some(y) = None::<i32> => { x = y; }
+            let Some(y) = Some(1), y > 1 => { x = y; }
+            Some(2) matches Some(_) => { x = 2; }
+            _ => { x = 3; }
+        }
+
+        assert_eq!(x, 2);
+
+        when! {
+            x;
+            0 => { x = 0; }
+            in 1..3 => { x = 4; }
+            _ => { x = 5; }
+        }
+
+        assert_eq!(x, 4);
+
+        let v: &dyn core::any::Any = &4;
+
+        when! {
+            v;
+            is u8 => { x = 0; }
+            is i32 as v => { x += v; }
+            _ => { x = 5; }
+        }
+
+        assert_eq!(x, 8);
+    }
+
+    #[test]
+    fn turbofish() {
+        let r = when! {
+            false => core::mem::size_of::<(u8, u16)>(),
+            let Some(x) = "4".parse::<usize>().ok(), x > 1 => core::cmp::max::<usize>(x, 2),
+            _ => 0,
+        };
+
+        assert_eq!(r, 4);
+    }
+
+    #[test]
+    fn labels() {
+        let f = |x: i32| {
+            when! {
+                "negative": x < 0 => -1,
+                #[allow(unused_variables)]
+                "zero": let 0 = x => 0,
+                "small": x matches 1..=9 => 1,
+                "fallback": _ => 2,
+            }
+        };
+
+        assert_eq!(f(-4), -1);
+        assert_eq!(f(0), 0);
+        assert_eq!(f(5), 1);
+        assert_eq!(f(-0x10 + 50), 2);
+    }
+
+    #[test]
+    fn integer_chains() {
+        struct Op {
+            code: u8,
+        }
+
+        let f = |op: Op| {
+            match_when! {
+                let code = op.code;
+                code == 0 => "nop",
+                code == 0x01 => "push",
+                #[cfg(all())]
+                code == 2u8 => "pop",
+                #[cfg(any())]
+                code == 3 => "dup",
+                _ => "unknown",
+            }
+        };
+
+        assert_eq!(f(Op { code: 0 }), "nop");
+        assert_eq!(f(Op { code: 1 }), "push");
+        assert_eq!(f(Op { code: 2 }), "pop");
+        assert_eq!(f(Op { code: 3 }), "unknown");
+
+        let mut x = 0;
+        let g = |op: &Op, x: &mut i32| {
+            match_when! {
+                op.code == 1 => *x += 1,
+                op.code == 2 => *x -= 1,
+            }
+        };
+        g(&Op { code: 1 }, &mut x);
+        g(&Op { code: 1 }, &mut x);
+        g(&Op { code: 7 }, &mut x);
+        assert_eq!(x, 2);
+
+        let sign =
+            |n: i64| match_when! { n == -1 => '-', n == 0 => '0', n == 1 => '+', else => '?' };
+        assert_eq!(sign(-1), '-');
+        assert_eq!(sign(1), '+');
+        assert_eq!(sign(5), '?');
+    }
+
+    #[test]
+    fn partial_eq_chains() {
+        struct Op(u8);
+
+        impl PartialEq<u8> for Op {
+            fn eq(&self, other: &u8) -> bool {
+                self.0 == *other
+            }
+        }
+
+        let f = |op: Op| {
+            when! {
+                op == 0 => "nop",
+                op == 1 => "push",
+                _ => "unknown",
+            }
+        };
+
+        assert_eq!(f(Op(1)), "push");
+        assert_eq!(f(Op(5)), "unknown");
+    }
+
+    #[test]
+    fn partial_eq_string_chains() {
+        use std::ffi::OsStr;
+
+        let f = |s: &OsStr| {
+            when! {
+                s == "fn" => 0,
+                s == "let" => 1,
+                _ => -1,
+            }
+        };
+
+        assert_eq!(f(OsStr::new("let")), 1);
+        assert_eq!(f(OsStr::new("if")), -1);
+    }
+
+    #[test]
+    fn string_chains() {
+        let f = |token: &str| {
+            match_when! {
+                token == "fn" => 0,
+                token == "let" => 1,
+                token == "if" => 2,
+                #[cfg(any())]
+                token == "else" => 3,
+                token == r"impl" => 4,
+                token == "€" => 5,
+                _ => -1,
+            }
+        };
+
+        assert_eq!(f("fn"), 0);
+        assert_eq!(f("let"), 1);
+        assert_eq!(f("if"), 2);
+        assert_eq!(f("else"), -1);
+        assert_eq!(f("impl"), 4);
+        assert_eq!(f("€"), 5);
+        assert_eq!(f("ifs"), -1);
+        assert_eq!(f(""), -1);
+
+        // `s` and `arm` are used by the lowering of the `macros` feature too
+        let s = std::string::String::from("b");
+        let arm = 10;
+        let mut r = 0;
+        match_when! {
+            s == "a" => r = arm,
+            s == "b" => r = arm + 1,
+        }
+        assert_eq!(r, 11);
+    }
+
+    #[test]
+    fn hints() {
+        let f = |x: i32, o: Option<i32>| {
+            when! {
+                #[unlikely]
+                x < 0 => -1,
+                #[likely]
+                "small": x < 10 => {
+                    x
+                }
+                #[unlikely]
+                let Some(y) = o => y,
+                #[likely]
+                let 10 | 20 = x => {
+                    0
+                }
+                #[unlikely]
+                x > 100, x < 1000 => 100,
+                #[cfg(all())]
+                #[likely]
+                x == 50 => 50,
+                _ => 1000,
+            }
+        };
+
+        assert_eq!(f(-1, None), -1);
+        assert_eq!(f(5, None), 5);
+        assert_eq!(f(15, Some(3)), 3);
+        assert_eq!(f(20, None), 0);
+        assert_eq!(f(500, None), 100);
+        assert_eq!(f(50, None), 50);
+        assert_eq!(f(5000, None), 1000);
+
+        let g = |x: u8| when! { #[likely] x == 1 => 1, x == 2 => 2, _ => 0 };
+        assert_eq!(g(1), 1);
+        assert_eq!(g(2), 2);
+    }
+
+    #[test]
+    fn cfg() {
+        let r = when! {
+            #[cfg(any())]
+            does_not_exist() => 0,
+            #[cfg(all())]
+            #[cfg(any())]
+            let Some(x) = does_not_exist() => x,
+            #[cfg(all())]
+            let Some(x) = Some(1) if x > 0 => x,
+            _ => 2,
+        };
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    fn lint_attrs() {
+        let r = when! {
+            #[deny(unused_variables)]
+            let Some(_) = Some(0) => 1,
+            #[allow(unused_variables)]
+            let Some(x) = Some(0) => 1,
+            _ => 2,
+        };
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    fn not_let() {
+        let r = when! {
+            not let Some(_) = Some(1) => 0,
+            let Some(x) = Some(2), not let 0 | 1 = x => x,
+            _ => 1,
+        };
+
+        assert_eq!(r, 2);
+    }
+
+    #[test]
+    fn or() {
+        let f = |x: Option<i32>| {
+            when! {
+                x == Some(0) or x == Some(1) => 0,
+                let Some(y) = x, y > 10 or x matches Some(2 | 3) => 1,
+                x.or(Some(5)) == Some(5) or let Some(_) = Some(x) if x == Some(6) => 2,
+                _ => 3,
+            }
+        };
+
+        assert_eq!(f(Some(1)), 0);
+        assert_eq!(f(Some(11)), 1);
+        assert_eq!(f(Some(3)), 1);
+        assert_eq!(f(None), 2);
+        assert_eq!(f(Some(6)), 2);
+        assert_eq!(f(Some(7)), 3);
+    }
+
+    #[test]
+    fn mixed() {
+        let r = when! {
+            false => 0,
+            let Some(_) = None::<bool> => 0,
+            let Some(y) = Some(12) => y + 1,
+            true => 1,
+            _ => 0,
+        };
+
+        assert_eq!(r, 13);
+    }
+
+    #[test]
+    fn r#struct() {
+        #[derive(PartialEq)]
+        struct Struct {
+            x: i32,
+        }
+
+        // won't work with if/elseif/else
+        #[allow(clippy::eq_op)]
+        let r = when! {
+            Struct { x: 0 } == Struct { x: 1 } => 0,
+            Struct { x: 22 } == Struct { x: 22 } => 1,
+            _ => 2,
+        };
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    fn empty() {
+        when! {}
+
+        let r = when! { _ => 1 };
+        when! { r; }
+        let s = when! { r; else => 2 };
+
+        assert_eq!(r, 1);
+        assert_eq!(s, 2);
+    }
+
+    #[test]
+    fn preamble() {
+        let mut calls = 0;
+        let mut setup = || {
+            calls += 1;
+            "42"
+        };
+
+        let r = when! {
+            let s = setup();
+            let n: Result<i32, _> = s.parse();
+            s.is_empty() => 0,
+            let Err(_) = n => 1,
+            _ => n.unwrap() + s.len() as i32,
+        };
+
+        assert_eq!(r, 44);
+        assert_eq!(calls, 1);
+
+        let r = when! {
+            let (a, b) = (1, 2);
+            a + b;
+            3 => "three",
+            _ => "other",
+        };
+
+        assert_eq!(r, "three");
+    }
+
+    #[test]
+    fn generated_arms() {
+        macro_rules! arms {
+            (($($n:literal),*) $($rest:tt)*) => {
+                when! { $(let Some($n) = X => $n * 10,)* $($rest)* }
+            };
+        }
+
+        const X: Option<i32> = Some(2);
+
+        let r = when! {
+            X.is_none() => 0,
+            ..arms!(0, 1),
+            ..arms![2],
+            _ => 1,
+        };
+
+        assert_eq!(r, 20);
+
+        let r = when! {
+            ..arms!(),
+        };
+
+        assert_eq!(r, ());
+    }
+
+    #[test]
+    fn macro_conditions() {
+        let x = Some(3);
+
+        let r = when! {
+            cfg!(all()), x.is_none() => 0,
+            cfg!(all()), x == Some(3) => 1,
+            _ => 2,
+        };
+
+        assert_eq!(r, 1);
+
+        let r = when! {
+            matches!(x, Some(1..=5)), false => 0,
+            matches!(x, Some(1..=5)), true => 1,
+            _ => 2,
+        };
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    fn closure_branches() {
+        let f = |x: i32| -> fn(i32, i32) -> i32 {
+            when! {
+                x > 0 => |a, b| a + b,
+                x < 0 => move |a, b| a - b,
+                _ => |_, _| 0,
+            }
+        };
+
+        assert_eq!(f(1)(2, 3), 5);
+        assert_eq!(f(-1)(2, 3), -1);
+        assert_eq!(f(0)(2, 3), 0);
+    }
+
+    #[test]
+    fn negated_comparison() {
+        // `!0 > 1` is `(!0) > 1`, not `!(0 > 1)`, so the default arm is reachable
+        let r = when! {
+            !0 > 1 => 1,
+            _ => 2,
+        };
+
+        assert_eq!(r, 2);
+    }
+
+    #[test]
+    fn no_def() {
+        let mut x = 0;
+
+        when! {
+            false => x = 18,
+            true => x += 1,
+        }
+
+        assert_eq!(x, 1);
+    }
+
+    #[test]
+    fn opt() {
+        let f = |x: i32| {
+            when_opt! {
+                x < 0 => 0,
+                let Some(v) = x.checked_mul(1000), v > 10_000 => {
+                    1
+                }
+                x matches 1 | 2 => 2,
+            }
+        };
+
+        assert_eq!(f(-1), Some(0));
+        assert_eq!(f(11), Some(1));
+        assert_eq!(f(2), Some(2));
+        assert_eq!(f(5), None);
+
+        let r = when_opt! { Some(1); None => "none", Some(1) => "one" };
+        assert_eq!(r, Some("one"));
+
+        let r: Option<()> = when_opt! {};
+        assert_eq!(r, None);
+    }
+
+    #[test]
+    fn try_when() {
+        let f = |x: i32| -> Result<i32, crate::NoMatch> {
+            let r = try_when! {
+                x > 0 => 1,
+                x < 0 => {
+                    -1
+                }
+            }?;
+
+            Ok(r * 10)
+        };
+
+        assert_eq!(f(5), Ok(10));
+        assert_eq!(f(-5), Ok(-10));
+        assert_eq!(f(0), Err(crate::NoMatch));
+    }
+
+    #[test]
+    fn assert_when() {
+        let x = 3;
+
+        let r = assert_when! {
+            x < 0 => 0,
+            x > 0 => {
+                1
+            }
+        };
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "), checked conditions:\n    x < 0\n    let Some(_) = None")]
+    fn assert_when_panics() {
+        let x = 3;
+
+        assert_when! {
+            x < 0 => (),
+            let Some(_) = None::<i32> => (),
+        }
+    }
+
+    #[test]
+    fn unreachable() {
+        let f = |x: bool| {
+            when_unreachable! {
+                x => 1,
+                !x => 0,
+            }
+        };
+
+        assert_eq!(f(true), 1);
+        assert_eq!(f(false), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "no when! arm matched")]
+    fn unreachable_panics() {
+        when_unreachable! {
+            false => (),
+        }
+    }
+
+    #[test]
+    fn first_some() {
+        let mut calls = 0;
+        let mut get = |x: Option<i32>| {
+            calls += 1;
+            x
+        };
+
+        assert_eq!(first_some!(get(None), get(Some(1)), get(Some(2))), Some(1));
+        assert_eq!(first_some!(get(None), get(None),), None);
+        assert_eq!(calls, 4);
+
+        let r: Option<()> = first_some!();
+        assert_eq!(r, None);
+    }
+
+    #[test]
+    fn first_ok() {
+        let mut calls = 0;
+        let mut get = |x: Result<i32, i32>| {
+            calls += 1;
+            x
+        };
+
+        assert_eq!(first_ok!(get(Err(0)), get(Ok(1)), get(Ok(2))), Ok(1));
+        assert_eq!(first_ok!(get(Err(0)), get(Err(1)),), Err(1));
+        assert_eq!(calls, 4);
+
+        let r: Result<i32, u8> = first_ok!(Err::<i32, ()>(()), Err(1));
+        assert_eq!(r, Err(1));
+    }
+
+    #[test]
+    fn first_non_empty() {
+        let mut calls = 0;
+        let mut get = |s: &'static str| {
+            calls += 1;
+            s
+        };
+
+        assert_eq!(first_non_empty!(get(""), get("a"), get("b")), "a");
+        assert_eq!(first_non_empty!(get(""), get(""),), "");
+        assert_eq!(calls, 4);
+
+        let slice: &[i32] = &[];
+        assert_eq!(first_non_empty!(slice, &[1, 2][..]), [1, 2]);
+        assert_eq!(first_non_empty!([0; 0], []), [0; 0]);
+    }
+
+    #[test]
+    fn choose() {
+        let x = 5;
+
+        assert_eq!(choose!(x > 3 => 1, _ => 0), 1);
+        assert_eq!(choose!(x > 10 => 1, else => 0,), 0);
+    }
+
+    #[test]
+    fn unless() {
+        let x = 5;
+        let mut y = 0;
+
+        assert_eq!(unless!(x > 3 => 1, _ => 0), 0);
+        assert_eq!(unless!(x > 10 => 1, else => 0,), 1);
+
+        unless!(x == 5 => y = 1);
+        unless!(x == 6 => y = 2,);
+        assert_eq!(y, 2);
+    }
+
+    #[test]
+    fn guard_clause() {
+        let f = |x: Option<(i32, i32)>| {
+            guard!(let Some((a, b)) = x else return 0);
+            guard!(let 1 | 2 = a else { return -1 });
+            a + b
+        };
+
+        assert_eq!(f(None), 0);
+        assert_eq!(f(Some((3, 1))), -1);
+        assert_eq!(f(Some((2, 1))), 3);
+    }
+
+    #[test]
+    fn when_loop() {
+        let mut i = 0;
+
+        let r = when_loop! {
+            i >= 10 => break i * 2,
+            i % 2 == 0 => {
+                i += 3;
+                continue;
+            }
+            _ => i += 1,
+        };
+
+        assert_eq!(r, 22);
+    }
+
+    #[test]
+    fn while_when() {
+        let mut i = 0;
+        let mut evens = 0;
+
+        while_when! {
+            i >= 10 => break,
+            i % 2 == 0 => {
+                evens += 1;
+                i += 1;
+            }
+            i < 8 => i += 1,
+        }
+
+        assert_eq!(i, 9);
+        assert_eq!(evens, 5);
+    }
+
+    #[test]
+    fn when_all() {
+        let x: i32 = 6;
+        let mut fired = [false; 4];
+
+        when_all! {
+            x > 0 => fired[0] = true,
+            let Some(y) = x.checked_sub(10) => fired[1] = y > 0,
+            x % 2 == 0, x % 3 == 0 => {
+                fired[2] = true;
+            }
+            x matches 1..=9 => fired[3] = true,
+        }
+
+        assert_eq!(fired, [true, false, true, true]);
+    }
+
+    #[test]
+    fn when_index() {
+        let f = |x: i32| {
+            when_index! {
+                x < 0 => (),
+                let 0 | 1 = x => {}
+                x matches 2..=9 => (),
+                x.checked_mul(1000).is_none() => (),
+            }
+            .map(|(i, ())| i)
+        };
+
+        assert_eq!(f(-1), Some(0));
+        assert_eq!(f(1), Some(1));
+        assert_eq!(f(5), Some(2));
+        assert_eq!(f(i32::MAX), Some(3));
+        assert_eq!(f(100), None);
+    }
+
+    #[test]
+    #[cfg(feature = "coverage")]
+    fn covered_when() {
+        let f = |x: i32| {
+            covered_when! {
+                "negative": x < 0 => -1,
+                x == 0 => 0,
+                "unreachable": x == i32::MIN => unreachable!(),
+                _ => 1,
+            }
+        };
+        let line = line!() - 7;
+
+        assert_eq!(f(-1), -1);
+        assert_eq!(f(0), 0);
+        assert_eq!(f(0), 0);
+
+        let hits = || {
+            crate::coverage::snapshot()
+                .into_iter()
+                .find(|site| site.file == file!() && site.line == line)
+                .unwrap()
+                .arms
+        };
+        assert_eq!(
+            hits(),
+            [("negative", 1), ("x == 0", 2), ("unreachable", 0), ("_", 0)]
+        );
+
+        crate::coverage::reset();
+        assert_eq!(f(5), 1);
+        assert_eq!(
+            hits(),
+            [("negative", 0), ("x == 0", 0), ("unreachable", 0), ("_", 1)]
+        );
+    }
+
+    #[test]
+    fn explain_when() {
+        let f = |x: i32| {
+            explain_when! {
+                "negative": x < 0 => (),
+                let 0 = x => {}
+                x == 1 or x == 2 => (),
+            }
+        };
+
+        let ((), why) = f(-5);
+        assert_eq!((why.fired(), why.index()), (Some("negative"), Some(0)));
+        assert!(why.checked().eq([("negative", true)]));
+
+        let ((), why) = f(2);
+        assert_eq!(
+            (why.fired(), why.index()),
+            (Some("x == 1 or x == 2"), Some(2))
+        );
+        assert!(why.checked().eq([
+            ("negative", false),
+            ("let 0 = x", false),
+            ("x == 1 or x == 2", true)
+        ]));
+
+        let ((), why) = f(3);
+        assert_eq!((why.fired(), why.index()), (None, None));
+        assert_eq!(
+            std::format!("{}", why),
+            "negative: false\nlet 0 = x: false\nx == 1 or x == 2: false\nnone of the arms fired\n"
+        );
+
+        let (sign, why) = explain_when! {
+            1 < 0 => -1,
+            _ => 1,
+        };
+        assert_eq!(sign, 1);
+        assert_eq!(std::format!("{}", why), "1 < 0: false\n_: true\n");
+    }
+
+    #[test]
+    fn branch() {
+        let f = |x: i32| {
+            let mut branch = crate::branch(x).case(|x| *x < 0, |_| "negative");
+            for (limit, name) in [(10, "small"), (100, "medium")] {
+                branch = branch.case(|x| *x < limit, move |_| name);
+            }
+            branch
+        };
+
+        assert_eq!(f(-5).eval(), Some("negative"));
+        assert_eq!(f(5).eval(), Some("small"));
+        assert_eq!(f(50).eval(), Some("medium"));
+        assert_eq!(f(500).eval(), None);
+        assert_eq!(f(500).default(|_| "large").eval(), "large");
+        assert_eq!(f(5).default(|_| "large").eval(), "small");
+
+        // Only the predicates of the cases before the one which fired are checked
+        let mut checked = 0;
+        let value = crate::branch(3)
+            .case(
+                |_| {
+                    checked += 1;
+                    false
+                },
+                |x| x,
+            )
+            .case(
+                |_| {
+                    checked += 1;
+                    true
+                },
+                |x| x * 2,
+            )
+            .case(
+                |_| {
+                    checked += 1;
+                    true
+                },
+                |x| x * 3,
+            )
+            .eval();
+        assert_eq!((value, checked), (Some(6), 2));
+    }
+
+    #[test]
+    fn bool_ext() {
+        use crate::BoolExt;
+        use core::cell::Cell;
+
+        let called = [Cell::new(0), Cell::new(0)];
+        let f = |x: i32| {
+            (x > 0)
+                .when(|| {
+                    called[0].set(called[0].get() + 1);
+                    "positive"
+                })
+                .otherwise(|| {
+                    called[1].set(called[1].get() + 1);
+                    "not positive"
+                })
+        };
+
+        assert_eq!(f(1), "positive");
+        assert_eq!(f(0), "not positive");
+        assert_eq!(f(2), "positive");
+        assert_eq!([called[0].get(), called[1].get()], [2, 1]);
+    }
+
+    #[test]
+    fn option_ext() {
+        use crate::OptionExt;
+
+        let f = |x: Option<i32>| x.when_some(|x| x * 2, || -1);
+
+        assert_eq!(f(Some(21)), 42);
+        assert_eq!(f(None), -1);
+        assert_eq!(
+            [Some("a"), None].map(|s| s.when_some(str::len, || 0)),
+            [1, 0]
+        );
+    }
+
+    #[test]
+    fn static_rules() {
+        use crate::rules::StaticRuleSet;
+
+        const FIZZ_BUZZ: StaticRuleSet<i32, &str> = static_rules! {
+            |x: &i32| -> &'static str;
+            x % 15 == 0 => "fizzbuzz",
+            x % 3 == 0 => "fizz",
+            x % 5 == 0 => { "buzz" }
+        };
+
+        assert_eq!(FIZZ_BUZZ.len(), 3);
+        assert_eq!(FIZZ_BUZZ.evaluate(&30), Some("fizzbuzz"));
+        assert_eq!(FIZZ_BUZZ.evaluate(&9), Some("fizz"));
+        assert_eq!(FIZZ_BUZZ.evaluate(&10), Some("buzz"));
+        assert_eq!(FIZZ_BUZZ.evaluate(&7), None);
+        assert!(FIZZ_BUZZ.evaluate_all(&15).eq(["fizzbuzz", "fizz", "buzz"]));
+
+        let len = static_rules! {
+            |s: &str| -> usize;
+            s.is_empty() => 0,
+            let "x" | "y" = s => 1,
+            else => s.len() + 1,
+        };
+
+        assert_eq!(len.evaluate(""), Some(0));
+        assert_eq!(len.evaluate("x"), Some(1));
+        assert_eq!(len.evaluate("ab"), Some(3));
+        assert_eq!(len.rules().len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn rule_set() {
+        use crate::rules::{Rule, RuleSet};
+        use std::boxed::Box;
+
+        let mut rules = RuleSet::new()
+            .rule(|x: &i32| *x < 0, |_| "negative")
+            .rule(|x| *x == 0, |_| "zero");
+        for (limit, name) in [(10, "small"), (100, "medium")] {
+            rules.push(move |x| *x < limit, move |_| name);
+        }
+
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules.evaluate(&-1), Some("negative"));
+        assert_eq!(rules.evaluate(&0), Some("zero"));
+        assert_eq!(rules.evaluate(&5), Some("small"));
+        assert_eq!(rules.evaluate(&50), Some("medium"));
+        assert_eq!(rules.evaluate(&500), None);
+
+        let rules = rules.otherwise(|x| if *x > 1000 { "huge" } else { "large" });
+        assert_eq!(rules.evaluate(&500), Some("large"));
+        assert_eq!(rules.evaluate(&5000), Some("huge"));
+        assert_eq!(rules.evaluate(&-1), Some("negative"));
+
+        struct Multiple(i32);
+
+        impl Rule<i32, &'static str> for Multiple {
+            fn matches(&self, x: &i32) -> bool {
+                x % self.0 == 0
+            }
+
+            fn apply(&self, _: &i32) -> &'static str {
+                "multiple"
+            }
+        }
+
+        let boxed: Box<dyn Rule<i32, &'static str>> = Box::new(Multiple(7));
+        let rules = RuleSet::new()
+            .with_rule(Multiple(3))
+            .with_rule(boxed)
+            .rule(|x| *x > 0, |_| "positive");
+        assert_eq!(rules.evaluate(&9), Some("multiple"));
+        assert_eq!(rules.evaluate(&14), Some("multiple"));
+        assert_eq!(rules.evaluate(&5), Some("positive"));
+        assert_eq!(rules.evaluate(&-1), None);
+
+        let mut rules = RuleSet::new();
+        let default = rules.push(|_: &i32| true, |_| "default");
+        let even = rules.push(|x| x % 2 == 0, |_| "even");
+        let big = rules.push(|x| *x > 100, |_| "big");
+        assert_eq!(rules.evaluate(&102), Some("default"));
+
+        rules.demote(default);
+        assert_eq!(rules.priority(default), Some(-1));
+        assert_eq!(rules.evaluate(&101), Some("big"));
+        assert_eq!(rules.evaluate(&102), Some("even"));
+
+        rules.promote(big);
+        assert_eq!(rules.priority(big), Some(1));
+        assert_eq!(rules.evaluate(&102), Some("big"));
+
+        // Rules with the same priority are checked in the order they were added
+        rules.set_priority(even, 1);
+        assert_eq!(rules.evaluate(&102), Some("even"));
+        rules.set_priority(even, 0);
+        assert_eq!(rules.evaluate(&102), Some("big"));
+        assert_eq!(rules.evaluate(&2), Some("even"));
+        assert_eq!(rules.evaluate(&1), Some("default"));
+
+        let mut rules = RuleSet::new()
+            .rule(|x: &i32| x % 3 == 0, |_| "fizz")
+            .rule(|x| x % 5 == 0, |_| "buzz")
+            .otherwise(|_| "default");
+        let big = rules.push(|x| *x > 10, |_| "big");
+        rules.promote(big);
+        assert!(rules.evaluate_all(&15).eq(["big", "fizz", "buzz"]));
+        assert!(rules.evaluate_all(&5).eq(["buzz"]));
+        assert_eq!(rules.evaluate_all(&7).count(), 0);
+
+        let mut rules = RuleSet::new()
+            .named_rule("negative", |x: &i32| *x < 0, |_| "negative")
+            .with_rule(Multiple(2))
+            .otherwise(|_| "default");
+        let one = rules.push_named(std::string::String::from("one"), |x| *x == 1, |_| "one");
+        rules.promote(one);
+
+        let explained = rules.evaluate_explained(&1);
+        assert_eq!(explained.output, Some("one"));
+        assert_eq!((explained.rule, explained.index), (Some(one), Some(0)));
+        assert_eq!((explained.name, explained.evaluated), (Some("one"), 1));
+        let explained = rules.evaluate_explained(&-2);
+        assert_eq!((explained.name, explained.evaluated), (Some("negative"), 2));
+        let explained = rules.evaluate_explained(&4);
+        assert_eq!(
+            (explained.output, explained.index),
+            (Some("multiple"), Some(2))
+        );
+        assert_eq!((explained.name, explained.evaluated), (None, 3));
+        let explained = rules.evaluate_explained(&3);
+        assert_eq!((explained.output, explained.rule), (Some("default"), None));
+        assert_eq!((explained.index, explained.evaluated), (None, 3));
+
+        let empty = RuleSet::<str, ()>::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.evaluate("x"), None);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn handlers() {
+        use crate::rules::Handler;
+        use std::boxed::Box;
+
+        struct Add(i32);
+
+        impl Handler<i32, i32> for Add {
+            fn handle(&self, x: &i32) -> i32 {
+                x + self.0
+            }
+        }
+
+        let limit = 10;
+        let mut registry = handlers! {
+            |x: &i32| -> i32;
+            *x < 0 => Add(100),
+            let 0 | 1 = *x => Box::new(Add(1)) as Box<dyn Handler<_, _>>,
+            *x > limit => Add(-limit),
+        };
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.evaluate(&-1), Some(99));
+        assert_eq!(registry.evaluate(&1), Some(2));
+        assert_eq!(registry.evaluate(&15), Some(5));
+        assert_eq!(registry.evaluate(&5), None);
+
+        let even = registry.push_handler(|x| x % 2 == 0, Add(1000));
+        assert_eq!(registry.evaluate(&4), Some(1004));
+        assert_eq!(registry.evaluate(&12), Some(2));
+        registry.promote(even);
+        assert_eq!(registry.evaluate(&12), Some(1012));
+
+        let registry = handlers! {
+            |x: &i32| -> i32;
+            *x > 0 => Add(1),
+            else => Add(-1),
+        };
+        assert_eq!(registry.evaluate(&5), Some(6));
+        assert_eq!(registry.evaluate(&0), Some(-1));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn async_rule_set() {
+        use crate::rules::AsyncRuleSet;
+        use core::cell::Cell;
+        use std::{boxed::Box, rc::Rc};
+
+        let checked = Rc::new(Cell::new(0));
+        let counter = checked.clone();
+        let mut rules = AsyncRuleSet::new()
+            .rule(
+                move |x: &i32| {
+                    counter.set(counter.get() + 1);
+                    Box::pin(async move { *x < 0 })
+                },
+                |_| Box::pin(async { "negative" }),
+            )
+            .rule(
+                |x| Box::pin(async move { *x == 0 }),
+                |_| Box::pin(async { "zero" }),
+            );
+        rules.push(
+            |x| Box::pin(async move { *x < 10 }),
+            |x| {
+                Box::pin(async move {
+                    if x % 2 == 0 {
+                        "small even"
+                    } else {
+                        "small odd"
+                    }
+                })
+            },
+        );
+
+        assert_eq!(rules.len(), 3);
+        assert_eq!(block_on(rules.evaluate(&-5)), Some("negative"));
+        assert_eq!(block_on(rules.evaluate(&0)), Some("zero"));
+        assert_eq!(block_on(rules.evaluate(&3)), Some("small odd"));
+        assert_eq!(block_on(rules.evaluate(&30)), None);
+        assert_eq!(checked.get(), 4);
+
+        let rules = rules.otherwise(|_| Box::pin(async { "large" }));
+        assert_eq!(block_on(rules.evaluate(&30)), Some("large"));
+
+        let empty = AsyncRuleSet::<str, ()>::default();
+        assert!(empty.is_empty());
+        assert_eq!(block_on(empty.evaluate("x")), None);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn pipeline() {
+        use crate::rules::Pipeline;
+        use core::ops::ControlFlow;
+
+        let mut pipeline = Pipeline::new()
+            .stage(|x: &i32| *x < 0, |x| ControlFlow::Continue(-x))
+            .stage(|x| *x > 100, |_| ControlFlow::Break("too big"));
+        pipeline.push(|x| x % 2 == 1, |x| ControlFlow::Continue(x + 1));
+        pipeline.push(|x| *x == 0, |_| ControlFlow::Break("zero"));
+
+        assert_eq!(pipeline.len(), 4);
+        assert_eq!(pipeline.run(-3), ControlFlow::Continue(4));
+        assert_eq!(pipeline.run(-200), ControlFlow::Break("too big"));
+        assert_eq!(pipeline.run(0), ControlFlow::Break("zero"));
+        assert_eq!(pipeline.run(7), ControlFlow::Continue(8));
+        // Later stages see the transformed context
+        assert_eq!(pipeline.run(101), ControlFlow::Break("too big"));
+
+        let empty = Pipeline::<(), ()>::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.run(()), ControlFlow::Continue(()));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn conditions() {
+        use crate::rules::{Condition, Op, RuleSet, Value};
+        use std::{boxed::Box, collections::BTreeMap, string::String, vec};
+
+        let field = |name: &str, value| (String::from(name), value);
+        let ctx: BTreeMap<_, _> = vec![
+            field("age", Value::Int(30)),
+            field("score", Value::Float(7.5)),
+            field("name", Value::Str("kiam".into())),
+            field("admin", Value::Bool(false)),
+        ]
+        .into_iter()
+        .collect();
+
+        let compare = |name: &str, op, value| Condition::Compare {
+            field: name.into(),
+            op,
+            value,
+        };
+        assert!(compare("age", Op::Eq, Value::Int(30)).matches(&ctx));
+        assert!(compare("age", Op::Ge, Value::Float(29.5)).matches(&ctx));
+        assert!(compare("score", Op::Lt, Value::Int(8)).matches(&ctx));
+        assert!(compare("name", Op::Ne, Value::Str("when".into())).matches(&ctx));
+        assert!(!compare("age", Op::Gt, Value::Int(30)).matches(&ctx));
+        // Missing fields and values of different kinds never compare
+        assert!(!compare("height", Op::Ne, Value::Int(0)).matches(&ctx));
+        assert!(!compare("admin", Op::Ne, Value::Int(0)).matches(&ctx));
+
+        let range = |start, end| Condition::Range {
+            field: "age".into(),
+            start,
+            end,
+        };
+        assert!(range(Some(Value::Int(18)), Some(Value::Int(65))).matches(&ctx));
+        assert!(range(Some(Value::Int(30)), None).matches(&ctx));
+        assert!(!range(None, Some(Value::Int(30))).matches(&ctx));
+
+        let names = Condition::In {
+            field: "name".into(),
+            values: vec![Value::Str("when".into()), Value::Str("kiam".into())],
+        };
+        assert!(names.matches(&ctx));
+        assert!(Condition::All(vec![names.clone(), Condition::Always]).matches(&ctx));
+        assert!(Condition::All(vec![]).matches(&ctx));
+        assert!(!Condition::Any(vec![]).matches(&ctx));
+        assert!(!Condition::Not(Box::new(names)).matches(&ctx));
+
+        let rules = RuleSet::from_conditions(vec![
+            (compare("admin", Op::Eq, Value::Bool(true)), "admin"),
+            (range(None, Some(Value::Int(18))), "minor"),
+            (Condition::Always, "adult"),
+        ]);
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules.evaluate(&ctx), Some("adult"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn conditions_serde() {
+        use crate::rules::{Condition, Op, Value};
+        use std::{boxed::Box, vec};
+
+        let json = r#"{
+            "all": [
+                { "compare": { "field": "total", "op": "ge", "value": 100 } },
+                { "range": { "field": "weight", "start": 0.5, "end": null } },
+                { "not": { "in": { "field": "country", "values": ["US", true] } } },
+                "always"
+            ]
+        }"#;
+        let condition = Condition::All(vec![
+            Condition::Compare {
+                field: "total".into(),
+                op: Op::Ge,
+                value: Value::Int(100),
+            },
+            Condition::Range {
+                field: "weight".into(),
+                start: Some(Value::Float(0.5)),
+                end: None,
+            },
+            Condition::Not(Box::new(Condition::In {
+                field: "country".into(),
+                values: vec![Value::Str("US".into()), Value::Bool(true)],
+            })),
+            Condition::Always,
+        ]);
+
+        let parsed: Condition = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed, condition);
+
+        let serialized = serde_json::to_string(&condition).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Condition>(&serialized).unwrap(),
+            condition
+        );
+    }
+
+    #[test]
+    fn reorder_when() {
+        let f = |x: i32| {
+            let mut checked = [0; 4];
+            let mut check = |i: usize, cond: bool| {
+                checked[i] = checked.iter().max().unwrap() + 1;
+                cond
+            };
+
+            let r = reorder_when! {
+                #[cost(expensive)]
+                check(0, x < 0) => "negative",
+                check(1, x == 0) => "zero",
+                #[cfg(all())]
+                #[cost(cheap)]
+                "big": check(2, x > 100) => "big",
+                #[cost(cheap)]
+                check(3, x > 10) => {
+                    "medium"
+                }
+                _ => "small",
+            };
+
+            (r, checked)
+        };
+
+        assert_eq!(f(-5), ("negative", [4, 3, 1, 2]));
+        assert_eq!(f(0), ("zero", [0, 3, 1, 2]));
+        assert_eq!(f(500), ("big", [0, 0, 1, 0]));
+        assert_eq!(f(50), ("medium", [0, 0, 1, 2]));
+        assert_eq!(f(5), ("small", [4, 3, 1, 2]));
+
+        // Costs are ignored by `when!`
+        let g =
+            |x: i32| when! { #[cost(expensive)] x < 0 => -1, #[cost(cheap)] x > 0 => 1, _ => 0 };
+        assert_eq!(g(-3), -1);
+        assert_eq!(g(3), 1);
+    }
+
+    #[test]
+    fn when_label() {
+        let f = |x: i32| {
+            when_label! {
+                "negative": x < 0 => (),
+                let 0 | 1 = x => {}
+                #[allow(unused_comparisons)]
+                "small": x matches 2..=9 => (),
+            }
+            .map(|(label, ())| label)
+        };
+
+        assert_eq!(f(-1), Some("negative"));
+        assert_eq!(f(1), Some("let 0 | 1 = x"));
+        assert_eq!(f(5), Some("small"));
+        assert_eq!(f(100), None);
+    }
+
+    #[test]
+    #[cfg(feature = "macros")]
+    fn when_which() {
+        let f = |x: i32| {
+            when_which! {
+                let y = x * 2;
+                negative: y < 0 => "negative",
+                let 0 | 2 = y => "small",
+                #[allow(unused_comparisons)]
+                "medium": y matches 4..=18 => {
+                    "medium"
+                }
+                big: else => "big",
+            }
+        };
+
+        let g = |x| {
+            let (value, which) = f(x);
+            (value, which.name(), which.index())
+        };
+
+        assert_eq!(g(-1), ("negative", "negative", 0));
+        assert_eq!(g(1), ("small", "let 0 | 2 = y", 1));
+        assert_eq!(g(5), ("medium", "medium", 2));
+        assert_eq!(g(100), ("big", "big", 3));
+        assert_eq!(f(2).1, f(3).1);
+        assert_ne!(f(2).1, f(10).1);
+        assert_eq!(std::format!("{:?}", f(0).1), "Arm1");
+
+        let h = |c: char| {
+            when_which! {
+                c;
+                'a' | 'e' => true,
+                _ => false,
+            }
+        };
+
+        assert_eq!(std::format!("{:?}", h('a')), "(true, Arm0)");
+        assert_eq!(std::format!("{:?}", h('b')), "(false, Default)");
+
+        // A type named `Which` can be used in the branches
+        #[derive(Debug, PartialEq)]
+        struct Which(i32);
+
+        let which = |x: i32| when_which! { x > 0 => Which(x), _ => Which(0) };
+        assert_eq!(which(5).0, Which(5));
+        assert_eq!(which(-5).1.name(), "_");
+    }
+
+    #[test]
+    #[cfg(feature = "macros")]
+    fn when_which_named() {
+        #[derive(Debug, PartialEq)]
+        enum Decision {
+            Negative,
+            Arm1,
+            Default,
+        }
+
+        fn f(x: i32) -> (&'static str, Decision) {
+            when_which! {
+                enum Decision;
+                let y = x * 2;
+                Negative: y < 0 => "negative",
+                "small": y < 10 => "small",
+                _ => "big",
+            }
+        }
+
+        assert_eq!(f(-1), ("negative", Decision::Negative));
+        assert_eq!(f(1), ("small", Decision::Arm1));
+        assert_eq!(f(100), ("big", Decision::Default));
+
+        #[derive(Debug, PartialEq)]
+        enum Vowel {
+            Arm0,
+            Default,
+        }
+
+        let g = |c: char| when_which! { enum Vowel; c; 'a' | 'e' => true, _ => false };
+        assert_eq!(g('a'), (true, Vowel::Arm0));
+        assert_eq!(g('b'), (false, Vowel::Default));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn collect_when() {
+        let f = |x: i32| {
+            collect_when! {
+                x % 3 == 0 => "fizz",
+                let 5 | 10 = x => { "five or ten" }
+                x % 5 == 0 => "buzz",
+                #[cfg(any())]
+                true => "removed",
+            }
+        };
+
+        assert_eq!(f(1), std::vec::Vec::<&str>::new());
+        assert_eq!(f(3), ["fizz"]);
+        assert_eq!(f(10), ["five or ten", "buzz"]);
+        assert_eq!(f(15), ["fizz", "buzz"]);
+    }
+
+    #[test]
+    fn switch() {
+        let f = |x: i32| {
+            let mut log = [0; 4];
+
+            switch! {
+                x < 0 => log[0] = 1,
+                let 0 = x => log[1] = 1,
+                x == 1 => {
+                    log[2] = 1;
+                    break;
+                }
+                _ => log[3] = 1,
+            }
+
+            log
+        };
+
+        assert_eq!(f(-1), [1, 1, 1, 0]);
+        assert_eq!(f(0), [0, 1, 1, 0]);
+        assert_eq!(f(1), [0, 0, 1, 0]);
+        assert_eq!(f(2), [0, 0, 0, 1]);
+
+        // `continue` leaves the switch, labels target the enclosing loops
+        let mut log = std::vec::Vec::new();
+        'outer: for x in 0..4 {
+            switch! {
+                x == 0 => continue,
+                x == 1 => continue 'outer,
+                x == 2 => break 'outer,
+            }
+            log.push(x);
+        }
+
+        assert_eq!(log, [0]);
+    }
+
+    #[test]
+    fn cfg_when() {
+        let f = || {
+            cfg_when! {
+                #[cfg(any())] => does_not_exist(),
+                #[cfg(all())]
+                #[cfg(any())] => does_not_exist(),
+                #[cfg(all())] => {
+                    let x = 1;
+                    x + 1
+                }
+                #[cfg(all())] => 3,
+                _ => 0,
+            }
+        };
+
+        let g = || cfg_when! { #[cfg(any())] => 1, else => 0 };
+
+        assert_eq!(f(), 2);
+        assert_eq!(g(), 0);
+    }
+
+    #[test]
+    fn const_when() {
+        const fn f(x: i32, opt: Option<i32>) -> i32 {
+            const_when! {
+                x < 0 => -1,
+                let Some(y) = opt, y > 3 or x == 7 => 1,
+                opt matches Some(0) => 2,
+                _ => 0,
+            }
+        }
+
+        const fn g(x: u8) -> u8 {
+            const_when! {
+                x as value;
+                1 | 2 => 1,
+                3, 4 => 2,
+                let 5..=9 => 3,
+                _ if value > 100 => 4,
+                _ => 0,
+            }
+        }
+
+        const R: [i32; 5] = [
+            f(-1, None),
+            f(1, Some(4)),
+            f(7, None),
+            f(1, Some(0)),
+            f(1, None),
+        ];
+        const S: [u8; 6] = [g(1), g(4), g(7), g(200), g(0), g(2)];
+
+        assert_eq!(R, [-1, 1, 1, 2, 0]);
+        assert_eq!(S, [1, 2, 3, 4, 0, 1]);
+
+        // Hints are `const` too
+        const fn h(x: i32, opt: Option<i32>) -> i32 {
+            const_when! {
+                #[likely]
+                x > 0 => 1,
+                #[unlikely]
+                let Some(y) = opt => y,
+                _ => 0,
+            }
+        }
+
+        const T: [i32; 3] = [h(1, None), h(0, Some(5)), h(0, None)];
+
+        assert_eq!(T, [1, 5, 0]);
+    }
+
+    #[test]
+    fn matches_any() {
+        #[allow(dead_code)]
+        enum E {
+            A(i32),
+            B { x: i32, y: i32 },
+            C,
+        }
+
+        let f = |e: &E| matches_any!(*e, E::A(0), E::A(a) if a < 0, E::B { x, y } if x == y, E::C,);
+
+        assert!(f(&E::A(0)));
+        assert!(f(&E::A(-1)));
+        assert!(!f(&E::A(1)));
+        assert!(f(&E::B { x: 1, y: 1 }));
+        assert!(!f(&E::B { x: 1, y: 2 }));
+        assert!(f(&E::C));
+    }
+
+    #[test]
+    fn when_flags() {
+        const A: u32 = 0b001;
+        const B: u32 = 0b010;
+        const C: u32 = 0b100;
+        const AB: u32 = A | B;
+
+        let f = |x: u32| {
+            when_flags! {
+                x;
+                AB => 0,
+                C => {
+                    assert_ne!(x & C, 0);
+                    1
+                }
+                A | B => 2,
+                else => 3,
+            }
+        };
+
+        assert_eq!(f(A | B | C), 0);
+        assert_eq!(f(A | C), 1);
+        assert_eq!(f(B), 2);
+        assert_eq!(f(0), 3);
+    }
+
+    #[test]
+    fn when_str() {
+        let f = |s: &str, flag: bool| {
+            when_str! {
+                s;
+                "a" | "b" => 0,
+                "c" if flag => 1,
+                "c" => {
+                    assert!(!flag);
+                    2
+                }
+                x if x.len() > 3 => 3,
+                _ => 4,
+            }
+        };
+
+        assert_eq!(f("b", false), 0);
+        assert_eq!(f("c", true), 1);
+        assert_eq!(f("c", false), 2);
+        assert_eq!(f("long", false), 3);
+        assert_eq!(f("d", false), 4);
+
+        // A type named `str` doesn't change the type the value is matched as
+        {
+            #[allow(non_camel_case_types, dead_code)]
+            struct str;
+
+            let g = |s: std::string::String| when_str! { s; "a" => 0, _ => 1 };
+
+            assert_eq!(g("a".into()), 0);
+        }
+    }
+
+    #[test]
+    fn dbg_when() {
+        let f = |x: i32| {
+            dbg_when! {
+                x < 0 => -1,
+                let Some(y) = Some(x), y > 10 => {
+                    assert!(x > 10);
+                    1
+                }
+                _ => 0,
+            }
+        };
+
+        assert_eq!(f(-5), -1);
+        assert_eq!(f(11), 1);
+        assert_eq!(f(5), 0);
+
+        dbg_when! { f(0) > 0 => () }
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn traced_when() {
+        use core::fmt::{Debug, Write};
+        use std::{
+            format,
+            string::String,
+            sync::{Arc, Mutex},
+            vec::Vec,
+        };
+        use tracing::{field, span, Event, Metadata};
+
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+        struct Fields(String);
+
+        impl field::Visit for Fields {
+            fn record_debug(&mut self, field: &field::Field, value: &dyn Debug) {
+                write!(self.0, " {}={:?}", field.name(), value).unwrap();
+            }
+        }
+
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(format!("{}", event.metadata().level()));
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0);
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Recorder(Arc::clone(&events)), || {
+            let f = |x: i32| {
+                traced_when! {
+                    "small": x < 5 => "small",
+                    x < 10 => "medium",
+                    _ => "large",
+                }
+            };
+
+            assert_eq!(f(1), "small");
+            assert_eq!(f(7), "medium");
+            assert_eq!(traced_when! { DEBUG; _ => 1 }, 1);
+        });
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "TRACE message=`when!` arm taken arm=\"small\" index=0",
+                "TRACE message=`when!` arm taken arm=\"x < 10\" index=1",
+                "DEBUG message=`when!` arm taken arm=\"_\" index=0",
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "log")]
+    fn log_when() {
+        use std::{format, string::String, sync::Mutex, vec::Vec};
+
+        static MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        struct Recorder;
+
+        impl log::Log for Recorder {
+            fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record<'_>) {
+                let message = format!("{} {}", record.level(), record.args());
+                MESSAGES.lock().unwrap().push(message);
+            }
+            fn flush(&self) {}
+        }
+
+        log::set_logger(&Recorder).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let f = |x: i32| {
+            log_when! {
+                "small": x < 5 => "small",
+                x < 10 => "medium",
+                _ => "large",
+            }
+        };
+        let line = line!() - 6;
+
+        assert_eq!(f(1), "small");
+        assert_eq!(f(7), "medium");
+        assert_eq!(log_when! { Debug; _ => 1 }, 1);
+
+        assert_eq!(
+            *MESSAGES.lock().unwrap(),
+            [
+                format!("TRACE [{}:{}] `when!` arm taken: small (#0)", file!(), line),
+                format!(
+                    "TRACE [{}:{}] `when!` arm taken: x < 10 (#1)",
+                    file!(),
+                    line
+                ),
+                format!(
+                    "DEBUG [{}:{}] `when!` arm taken: _ (#0)",
+                    file!(),
+                    line + 10
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn when_either() {
+        #[derive(Debug, PartialEq)]
+        enum Either<L, R> {
+            Left(L),
+            Right(R),
+        }
+
+        let f = |x: i32| {
+            crate::__when_wrap!([] [@either Either] [] []
+                x < 0 => "negative",
+                let Some(y) = x.checked_mul(2), y > 10 => {
+                    assert!(x > 5);
+                    y
+                }
+                _ => (),
+            )
+        };
+
+        assert_eq!(f(-1), Either::Left("negative"));
+        assert_eq!(f(6), Either::Right(Either::Left(12)));
+        assert_eq!(f(1), Either::Right(Either::Right(())));
+
+        let g = |x: bool| crate::__when_wrap!([] [@either Either] [] [] x => 1, else => "1");
+
+        assert_eq!(g(true), Either::Left(1));
+        assert_eq!(g(false), Either::Right("1"));
+    }
+
+    #[test]
+    #[cfg(feature = "either")]
+    fn when_either_dep() {
+        use crate::__private::either::Either;
+
+        let v = [1, 2, 3];
+        let f = |x: i32| {
+            when_either! {
+                x < 0 => v.iter().rev(),
+                _ => v.iter(),
+            }
+        };
+
+        assert!(matches!(f(-1), Either::Left(_)));
+        assert_eq!(f(-1).copied().collect::<std::vec::Vec<_>>(), [3, 2, 1]);
+        assert_eq!(f(1).copied().collect::<std::vec::Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn when_enum() {
+        let f = |x: i32| {
+            when_enum! {
+                impl Iterator, Debug;
+                x < 0 => core::iter::empty(),
+                let Some(y) = x.checked_sub(10), y > 0 => (0..2).map(move |_| y),
+                x == 5 => {
+                    assert_eq!(x, 5);
+                    core::iter::once(5)
+                }
+                else => 0..x,
+            }
+        };
+
+        let sum = |x: i32| f(x).sum::<i32>();
+
+        assert_eq!(sum(-1), 0);
+        assert_eq!(sum(12), 4);
+        assert_eq!(sum(5), 5);
+        assert_eq!(sum(3), 3);
+
+        assert_eq!(std::format!("{:?}", f(3)), "0..3");
+
+        let g = |x: i32| when_enum! { impl Display; x > 0 => x, _ => "none" };
+
+        assert_eq!(std::format!("{}", g(1)), "1");
+        assert_eq!(std::format!("{}", g(0)), "none");
+
+        // The branches don't see the generated enum
+        #[derive(Debug, PartialEq)]
+        enum WhenEnum {
+            A,
+        }
+
+        let h = |x: bool| when_enum! { impl Debug; x => WhenEnum::A, _ => 0 };
+
+        assert_eq!(std::format!("{:?}", h(true)), "A");
+
+        let fut = |x: i32| {
+            when_enum! {
+                impl Future;
+                x < 0 => core::future::ready(0),
+                x == 0 => core::future::pending(),
+                _ => std::boxed::Box::pin(async move { x * 2 }),
+            }
+        };
+
+        assert_eq!(block_on(fut(-1)), 0);
+        assert_eq!(block_on(fut(21)), 42);
+    }
+
+    #[test]
+    fn when_cf() {
+        use core::ops::ControlFlow;
+
+        let f = |x: Option<i32>| {
+            when_cf! {
+                let Some(y) = x, y < 0 => "negative",
+                x.is_none() => {
+                    assert_eq!(x, None);
+                    "none"
+                }
+            }
+        };
+
+        assert_eq!(f(Some(-1)), ControlFlow::Break("negative"));
+        assert_eq!(f(None), ControlFlow::Break("none"));
+        assert_eq!(f(Some(1)), ControlFlow::Continue(()));
+    }
+
+    #[test]
+    fn retry_when() {
+        let mut calls = 0;
+        let mut next = || {
+            calls += 1;
+            calls
+        };
+
+        let r = retry_when! {
+            10;
+            let x @ 1.. = next(), x % 4 == 0 => {
+                assert_eq!(x, 4);
+                x
+            }
+            _ => 0,
+        };
+
+        assert_eq!(r, 4);
+
+        let mut backoffs = 0;
+        let r = retry_when! {
+            3, |attempt| backoffs += attempt;
+            next() > 100 => 1,
+            else => 2,
+        };
+
+        assert_eq!(r, 2);
+        assert_eq!(backoffs, 1 + 2);
+        assert_eq!(calls, 4 + 3);
+
+        let mut count = 0;
+        retry_when! {
+            0;
+            { count += 1; false } => (),
+        }
+
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn poll_when() {
+        use core::task::Poll;
+
+        fn f(a: Poll<i32>, b: Option<i32>) -> Poll<i32> {
+            let x = poll_when! {
+                let Poll::Ready(x) = a, x > 0 => x,
+                let Some(x) = b => {
+                    assert!(a.is_pending() || a.map(|a| a <= 0) == Poll::Ready(true));
+                    -x
+                }
+            };
+
+            Poll::Ready(x * 10)
+        }
+
+        assert_eq!(f(Poll::Ready(1), None), Poll::Ready(10));
+        assert_eq!(f(Poll::Pending, Some(2)), Poll::Ready(-20));
+        assert_eq!(f(Poll::Ready(0), None), Poll::Pending);
+        assert_eq!(f(Poll::Pending, None), Poll::Pending);
+    }
+
+    fn block_on<F: core::future::Future>(f: F) -> F::Output {
+        use core::task::{Context, Poll, Waker};
+
+        let mut f = core::pin::pin!(f);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(x) = f.as_mut().poll(&mut cx) {
+                break x;
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "futures")]
+    fn stream_when() {
+        use futures::stream::{self, StreamExt};
+        use std::vec::Vec;
+
+        let lines = stream::iter(["# comment", "", "answer=42", "name", "-"]);
+        let entries = stream_when! {
+            for line in lines;
+            line.starts_with('#') => continue,
+            let Some((key, value)) = line.split_once('=') => (key, Some(value)),
+            line == "-" => ("dash", None),
+            !line.is_empty() => (line, None),
+        };
+        assert_eq!(
+            block_on(entries.collect::<Vec<_>>()),
+            [("answer", Some("42")), ("name", None), ("dash", None)]
+        );
+
+        let numbers = stream::iter(1..=6);
+        let parity = stream_when! {
+            for n in numbers;
+            n == 3 => continue,
+            n % 2 == 0 => "even",
+            _ => "odd",
+        };
+        assert_eq!(
+            block_on(parity.collect::<Vec<_>>()),
+            ["odd", "even", "even", "odd", "even"]
+        );
+    }
+
+    #[test]
+    fn when_async() {
+        let f = |x: i32| async move {
+            let mut log = [false; 2];
+            let r = when_async! {
+                async { log[0] = true; x < 0 } => -1,
+                let Some(y) = async { log[1] = true; x.checked_sub(10) } => {
+                    assert!(y >= -10);
+                    y
+                }
+                _ => 0,
+            };
+
+            (r, log)
+        };
+
+        assert_eq!(block_on(f(-1)), (-1, [true, false]));
+        assert_eq!(block_on(f(12)), (2, [true, true]));
+    }
+
+    #[test]
+    fn select_when() {
+        use core::task::Poll;
+
+        // Completes with `value` after being polled `n + 1` times
+        fn after(n: usize, value: i32) -> impl core::future::Future<Output = i32> {
+            let mut polls = 0;
+            core::future::poll_fn(move |cx| {
+                if polls == n {
+                    return Poll::Ready(value);
+                }
+
+                polls += 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            })
+        }
+
+        let r = block_on(async {
+            select_when! {
+                let 1 = after(1, 0) => "a",
+                let x = after(2, 1) => {
+                    assert_eq!(x, 1);
+                    "b"
+                }
+                let _ = after(3, 2) => "c",
+            }
+        });
+
+        assert_eq!(r, "b");
+
+        let r = block_on(async {
+            select_when! {
+                let _ = after(1, 0) => 1,
+                _ => 0,
+            }
+        });
+
+        assert_eq!(r, 0);
+
+        let r = block_on(async {
+            select_when! {
+                let 1 = after(1, 0) => 1,
+                let x = after(0, 7) => x,
+                else => 0,
+            }
+        });
+
+        assert_eq!(r, 7);
+
+        // Branches are executed outside of the polling, so they may move captured values and use
+        // `.await` and `?`
+        let f = |x: i32| async move {
+            let s = std::string::String::from("moved");
+            let r: Result<std::string::String, i32> = select_when! {
+                let 1 = after(0, x) => {
+                    let n = after(1, 5).await;
+                    Err(n)?
+                }
+                let _ = after(1, 0) => Ok(s),
+            };
+
+            r
+        };
+
+        assert_eq!(block_on(f(0)).as_deref(), Ok("moved"));
+        assert_eq!(block_on(f(1)), Err(5));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn when_boxed() {
+        use crate::rules::BoxFuture;
+        use core::future::ready;
+
+        async fn double(x: i32) -> i32 {
+            x * 2
+        }
+
+        let s = std::string::String::from("borrowed");
+        let f = |x: i32| -> BoxFuture<'_, i32> {
+            when_boxed! {
+                x < 0 => async { -1 },
+                x == 0 => ready(0),
+                let 1 | 2 = x => async { s.len() as i32 },
+                else => double(x),
+            }
+        };
+
+        assert_eq!(block_on(f(-5)), -1);
+        assert_eq!(block_on(f(0)), 0);
+        assert_eq!(block_on(f(2)), 8);
+        assert_eq!(block_on(f(21)), 42);
+    }
+
+    #[test]
+    fn select_when_order() {
+        use core::{
+            future::Future,
+            task::{Context, Poll, Waker},
+        };
+
+        // Completes with `value` after being polled twice
+        fn second_poll(value: usize) -> impl core::future::Future<Output = usize> {
+            let mut polled = false;
+            core::future::poll_fn(move |cx| {
+                if polled {
+                    return Poll::Ready(value);
+                }
+
+                polled = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            })
+        }
+
+        let fair = || {
+            block_on(async {
+                select_when! {
+                    let x = async { 0 } => x,
+                    let x = async { 1 } => x,
+                    let x = async { 2 } => x,
+                }
+            })
+        };
+        let biased = || {
+            block_on(async {
+                select_when! {
+                    biased;
+                    let x = async { 0 } => x,
+                    let x = async { 1 } => x,
+                    let x = async { 2 } => x,
+                }
+            })
+        };
+
+        // Every evaluation starts with the next arm, so always ready arms win in turn
+        assert_eq!([fair(), fair(), fair(), fair()], [0, 1, 2, 0]);
+        assert_eq!([biased(), biased(), biased()], [0, 0, 0]);
+
+        // The first arm rotates between the polls of an evaluation
+        let fair = || {
+            block_on(async {
+                select_when! {
+                    let x = second_poll(0) => x,
+                    let x = second_poll(1) => x,
+                    let x = second_poll(2) => x,
+                }
+            })
+        };
+        let biased = || {
+            block_on(async {
+                select_when! {
+                    timer = &();
+                    biased;
+                    let x = second_poll(0) => x,
+                    let x = second_poll(1) => x,
+                }
+            })
+        };
+
+        assert_eq!([fair(), fair(), fair()], [1, 2, 0]);
+        assert_eq!([biased(), biased()], [0, 0]);
+
+        // Polls of other evaluations don't change the rotation between the polls of an evaluation
+        let fair = || async {
+            select_when! {
+                let x = second_poll(0) => x,
+                let x = second_poll(1) => x,
+            }
+        };
+        let mut first = core::pin::pin!(fair());
+        let mut second = core::pin::pin!(fair());
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(first.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(second.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(first.as_mut().poll(&mut cx), Poll::Ready(1));
+        assert_eq!(second.as_mut().poll(&mut cx), Poll::Ready(0));
+    }
+
+    #[test]
+    fn select_when_after() {
+        use crate::Timer;
+        use core::{
+            future::Future,
+            pin::Pin,
+            task::{Context, Poll},
+            time::Duration,
+        };
+
+        // Each millisecond is a poll
+        struct Polls;
+
+        struct Sleep(u128);
+
+        impl Future for Sleep {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+                if self.0 == 0 {
+                    return Poll::Ready(());
+                }
+
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+
+        impl Timer for Polls {
+            type Sleep = Sleep;
+
+            fn sleep(&self, duration: Duration) -> Sleep {
+                Sleep(duration.as_millis())
+            }
+        }
+
+        let race = |n: u64| {
+            block_on(async move {
+                select_when! {
+                    timer = Polls;
+                    let 1 = async { 0 } => "unmatched",
+                    let () = Sleep(3) => "done",
+                    after Duration::from_millis(n) => "timed out",
+                }
+            })
+        };
+
+        assert_eq!(race(1), "timed out");
+        assert_eq!(race(5), "done");
+
+        // The timer is only evaluated once, a reference can be used too
+        let timer = &Polls;
+        let r = block_on(async {
+            select_when! {
+                timer = timer;
+                after Duration::from_millis(2) => { 2 }
+                after Duration::from_millis(1) => { 1 }
+            }
+        });
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "none of the outputs matched")]
+    fn select_when_panics() {
+        block_on(async {
+            select_when! {
+                let 1 = after_async(0) => (),
+                let 2 = after_async(1) => (),
+            }
+        });
+
+        async fn after_async(x: i32) -> i32 {
+            x
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn par_when() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        let evaluated = AtomicUsize::new(0);
+        let check = |x: bool| {
+            evaluated.fetch_add(1, Ordering::Relaxed);
+            x
+        };
+
+        let r = par_when! {
+            check(false) => 0,
+            check(true) => {
+                assert_eq!(evaluated.load(Ordering::Relaxed), 3);
+                1
+            }
+            check(true) => 2,
+            _ => 3,
+        };
+
+        assert_eq!(r, 1);
+
+        let r = par_when! { check(false) => 0, else => 1 };
+        assert_eq!(r, 1);
+
+        let mut n = 0;
+        par_when! {
+            check(false) => n = 1,
+        }
+        assert_eq!(n, 0);
+        assert_eq!(evaluated.load(Ordering::Relaxed), 5);
+
+        // Conditions are evaluated by `when!`, so labels, hints, `let`s and struct literals work
+        #[derive(PartialEq)]
+        struct P {
+            x: i32,
+        }
+
+        let f = |p: P, opt: Option<i32>| {
+            par_when! {
+                "small": p.x < 10 => 0,
+                #[likely]
+                let Some(y) = opt, y > 3 => 1,
+                p == P { x: 20 } => 2,
+                _ => 3,
+            }
+        };
+
+        assert_eq!(f(P { x: 1 }, Some(5)), 0);
+        assert_eq!(f(P { x: 10 }, Some(5)), 1);
+        assert_eq!(f(P { x: 20 }, None), 2);
+        assert_eq!(f(P { x: 30 }, Some(1)), 3);
+    }
+
+    #[test]
+    fn memo_when() {
+        let mut cache = std::collections::BTreeMap::new();
+        let mut evaluated = 0;
+
+        let mut f = |x: i32| {
+            memo_when! {
+                cache, x;
+                { evaluated += 1; x < 0 } => -1,
+                x > 10 => {
+                    assert!(x > 10);
+                    1
+                }
+                _ => 0,
+            }
+        };
+
+        assert_eq!(f(-5), -1);
+        assert_eq!(f(-5), -1);
+        assert_eq!(f(20), 1);
+        assert_eq!(f(0), 0);
+        assert_eq!(f(20), 1);
+        assert_eq!(f(0), 0);
+        assert_eq!(evaluated, 3);
+
+        let mut calls = 0;
+        let mut map = std::collections::BTreeMap::new();
+        for _ in 0..3 {
+            memo_when! {
+                map, "key";
+                { calls += 1; true } => (),
+            }
+        }
+
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn exclusive_when() {
+        let f = |x: Option<i32>| {
+            exclusive_when! {
+                let Some(y) = x, y < 0 => -1,
+                x == Some(0) => {
+                    assert_eq!(x, Some(0));
+                    0
+                }
+                let Some(1..) = x => 1,
+                _ => 2,
+            }
+        };
+
+        assert_eq!(f(Some(-3)), -1);
+        assert_eq!(f(Some(0)), 0);
+        assert_eq!(f(Some(3)), 1);
+        assert_eq!(f(None), 2);
+    }
+
+    #[test]
+    fn exclusive_when_evaluates_once() {
+        use core::cell::Cell;
+
+        let evaluated = Cell::new(0);
+        let check = |x: bool| {
+            evaluated.set(evaluated.get() + 1);
+            x
+        };
+
+        let r = exclusive_when! {
+            check(false) => 0,
+            "second": check(true) => 1,
+            check(false) => 2,
+            _ => 3,
+        };
+
+        assert_eq!(r, 1);
+        // In release builds the conditions after the arm which fired are not checked
+        assert_eq!(evaluated.get(), if cfg!(debug_assertions) { 3 } else { 2 });
+    }
+
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        should_panic(expected = "3 arms of `exclusive_when!` fired")
+    )]
+    fn exclusive_when_panics() {
+        let x = 5;
+
+        exclusive_when! {
+            x > 0 => (),
+            x > 10 => (),
+            x < 10 => (),
+            x == 5 => (),
+        }
+    }
+
+    #[test]
+    fn rand_when() {
+        let f = |x: i32, pick: usize| {
+            let mut picks = 0;
+            let r = rand_when! {
+                |n: usize| {
+                    picks = n;
+                    pick
+                };
+                x > 0 => 1,
+                x > 10 => {
+                    assert!(x > 10);
+                    2
+                }
+                x < 0 => 3,
+                x > 20 => 4,
+                _ => 0,
+            };
+
+            (r, picks)
+        };
+
+        assert_eq!(f(15, 0), (1, 2));
+        assert_eq!(f(15, 1), (2, 2));
+        assert_eq!(f(25, 2), (4, 3));
+        assert_eq!(f(-1, 0), (3, 1));
+        assert_eq!(f(0, 0), (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "the closure passed to `rand_when!` returned 7")]
+    fn rand_when_out_of_range() {
+        let x = 5;
+
+        rand_when! {
+            |_| 7;
+            x > 0 => (),
+            _ => (),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rand")]
+    fn rand_when_uniform() {
+        let mut seen = [false; 3];
+
+        for _ in 0..100 {
+            let x = 5;
+            let i = rand_when! {
+                x > 0 => 0,
+                x < 10 => 1,
+                x < 0 => 2,
+                _ => 2,
+            };
+
+            seen[i] = true;
+        }
+
+        assert_eq!(seen, [true, true, false]);
+    }
+
+    #[test]
+    fn when_chain() {
+        let f = |x: Option<i32>| {
+            when_chain! {
+                let Some(y) = x;
+                y > 0;
+                let Some(z) = y.checked_mul(1000);
+                z < 10_000 && z != 5000;
+                then {
+                    assert!(y < 10);
+                    z
+                }
+                else { -1 }
+            }
+        };
+
+        assert_eq!(f(Some(3)), 3000);
+        assert_eq!(f(Some(5)), -1);
+        assert_eq!(f(Some(0)), -1);
+        assert_eq!(f(None), -1);
+        assert_eq!(f(Some(12)), -1);
+
+        let mut r = 0;
+        when_chain! {
+            let Some(x) = Some(1);
+            then { r = x; }
+        }
+
+        assert_eq!(r, 1);
+    }
+
+    #[test]
+    fn bail_when() {
+        struct Error(&'static str);
+
+        impl From<&'static str> for Error {
+            fn from(s: &'static str) -> Self {
+                Error(s)
+            }
+        }
+
+        fn f(x: Option<i32>) -> Result<i32, Error> {
+            bail_when! {
+                x.is_none() => "none",
+                let Some(y) = x, y < 0 => {
+                    assert!(y < 0);
+                    "negative"
+                }
+            }
+
+            Ok(x.unwrap() * 2)
+        }
+
+        assert_eq!(f(Some(2)).ok(), Some(4));
+        assert_eq!(f(None).err().map(|e| e.0), Some("none"));
+        assert_eq!(f(Some(-1)).err().map(|e| e.0), Some("negative"));
+    }
+
+    #[test]
+    fn return_when() {
+        fn f(x: Option<i32>) -> i32 {
+            return_when! {
+                let Some(y) = x, y < 0 => -y,
+                x.is_none() => {
+                    assert_eq!(x, None);
+                    0
+                }
+            }
+
+            x.unwrap() * 10
+        }
+
+        assert_eq!(f(Some(-3)), 3);
+        assert_eq!(f(None), 0);
+        assert_eq!(f(Some(2)), 20);
+    }
+
+    #[test]
+    fn break_when() {
+        let mut i = 0;
+        loop {
+            i += 1;
+            break_when!(let 3..=10 = i, i % 2 == 0 or i > 20);
+        }
+
+        assert_eq!(i, 4);
+
+        let r = 'outer: loop {
+            for j in 0.. {
+                break_when! {
+                    'outer:
+                    j == 10 => "ten",
+                    let 20 = j => "twenty",
+                }
+                break_when!(j > 100);
+            }
+        };
+
+        assert_eq!(r, "ten");
+
+        let r = loop {
+            break_when! {
+                i > 2 => {
+                    assert_eq!(i, 4);
+                    i * 10
+                }
+            }
+        };
+
+        assert_eq!(r, 40);
+    }
+
+    #[test]
+    fn continue_when() {
+        let mut odd = 0;
+        for i in 0..10 {
+            continue_when!(i % 2 == 0);
+            odd += 1;
+        }
+
+        assert_eq!(odd, 5);
+
+        let mut count = 0;
+        'outer: for i in 0..5 {
+            for j in 0..5 {
+                continue_when!('outer: j > i or let 3 = i);
+                count += 1;
+            }
+        }
+
+        assert_eq!(count, 1 + 2 + 3 + 5);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn yeet_when() {
+        fn f(x: Option<i32>) -> Option<i32> {
+            yeet_when! {
+                x.is_none() => (),
+                let Some(y) = x, y < 0 => {
+                    assert!(y < 0);
+                }
+            }
+
+            Some(x? * 2)
+        }
+
+        assert_eq!(f(Some(2)), Some(4));
+        assert_eq!(f(Some(-2)), None);
+        assert_eq!(f(None), None);
+    }
+
+    #[test]
+    fn subject() {
+        let mut calls = 0;
+        let mut status = || {
+            calls += 1;
+            404
+        };
+
+        let r = when! {
+            status();
+            200 => 0,
+            404 => 1,
+            _ => 2,
+        };
+
+        assert_eq!(r, 1);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn subject_as() {
+        let mut calls = 0;
+        let mut fetch = || {
+            calls += 1;
+            "hello"
+        };
+
+        let r = when! {
+            fetch() as s;
+            "" => 0,
+            "hi" => s.len() * 10,
+            _ => s.len(),
+        };
+
+        assert_eq!(r, 5);
+        assert_eq!(calls, 1);
+
+        let r = when! {
+            (300_i32 as u8);
+            44 => true,
+            _ => false,
+        };
+
+        assert!(r);
+    }
+
+    #[test]
+    fn subject_pattern_by_ref() {
+        let x = Some(std::string::String::from("kiam"));
+
+        let r = when! {
+            x;
+            let Some(s) if s.is_empty() => 0,
+            let Some(s) => s.len(),
+            _ => 1,
+        };
+
+        assert_eq!(r, 4);
+        assert_eq!(x.as_deref(), Some("kiam"));
+    }
+
+    #[test]
+    fn subject_pattern() {
+        let f = |x: Option<i32>| {
+            when! {
+                x;
+                None => 0,
+                let Some(n) if *n < 0 => 1,
+                Some(5) => 2,
+                let Some(10 | 20) => 3,
+                let Some(n) if *n > 100 => *n,
+                _ => 4,
+            }
+        };
+
+        assert_eq!(f(None), 0);
+        assert_eq!(f(Some(-1)), 1);
+        assert_eq!(f(Some(5)), 2);
+        assert_eq!(f(Some(20)), 3);
+        assert_eq!(f(Some(101)), 101);
+        assert_eq!(f(Some(7)), 4);
+
+        // Not `Copy`, so it can only be bound by reference
+        struct Len(usize);
+
+        let s = when! {
+            Some(Len(4));
+            let Some(ref l) if l.0 > 10 => 0,
+            let Some(ref l) => l.0,
+            _ => 1,
+        };
+
+        assert_eq!(s, 4);
+    }
+
+    #[test]
+    fn subject_guard() {
+        let f = |x: u32, verbose: bool| {
+            when! {
+                x as n;
+                0 => "zero",
+                _ if verbose && n % 2 == 0 => "even",
+                _ if verbose => "odd",
+                _ => "number",
+            }
+        };
+
+        assert_eq!(f(0, true), "zero");
+        assert_eq!(f(2, true), "even");
+        assert_eq!(f(3, true), "odd");
+        assert_eq!(f(2, false), "number");
+    }
+
+    #[test]
+    fn subject_alternatives() {
+        mod consts {
+            pub const ZERO: u8 = 0;
+            pub const MAX: u8 = 255;
+        }
+
+        let f = |x: u8| {
+            when! {
+                x;
+                consts::ZERO | consts::MAX => 0,
+                1 | 2 | 3 => 1,
+                4, 2 + 3, 6 => 2,
+                7 => {
+                    3
+                }
+                8 | 9 => {
+                    4
+                }
+                _ => 5,
+            }
+        };
+
+        assert_eq!(f(0), 0);
+        assert_eq!(f(255), 0);
+        assert_eq!(f(2), 1);
+        assert_eq!(f(5), 2);
+        assert_eq!(f(7), 3);
+        assert_eq!(f(9), 4);
+        assert_eq!(f(10), 5);
+    }
+
+    #[test]
+    fn exhaustive() {
+        enum E {
+            A(u8),
+            B,
+        }
+
+        let f = |e: E| {
+            when_exhaustive! {
+                e;
+                let E::A(n) if *n > 1 => *n,
+                "a": let E::A(_) => {
+                    1
+                }
+                let E::B => 0,
+            }
+        };
+
+        assert_eq!(f(E::A(5)), 5);
+        assert_eq!(f(E::A(0)), 1);
+        assert_eq!(f(E::B), 0);
+    }
+
+    #[test]
+    fn subject_labels() {
+        let f = |x: i32| {
+            when! {
+                x;
+                "one": 1 => "one",
+                "small": in 2..10 => "small",
+                "even": let n if n % 2 == 0 => "even",
+                _ => "odd",
+            }
+        };
+
+        assert_eq!(f(1), "one");
+        assert_eq!(f(3), "small");
+        assert_eq!(f(12), "even");
+        assert_eq!(f(13), "odd");
+    }
+
+    #[test]
+    fn subject_in() {
+        let bucket = |x: u32| {
+            when! {
+                x;
+                0 => 0,
+                in 1..10 => 1,
+                in 10..=100 => 2,
+                _ => 3,
+            }
+        };
+
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(9), 1);
+        assert_eq!(bucket(10), 2);
+        assert_eq!(bucket(100), 2);
+        assert_eq!(bucket(101), 3);
+    }
+
+    #[test]
+    fn subject_not_in() {
+        let kind = |x: i32| {
+            when! {
+                x;
+                in [2, 3, 5, 7] => 0,
+                !in 0..10 => 1,
+                _ => 2,
+            }
+        };
+
+        assert_eq!(kind(5), 0);
+        assert_eq!(kind(12), 1);
+        assert_eq!(kind(4), 2);
+    }
+
+    #[test]
+    fn subject_is() {
+        use core::any::Any;
+
+        let kind = |x: &dyn Any| {
+            when! {
+                x;
+                is i32 => 0,
+                is &str => 1,
+                _ => 2,
+            }
+        };
+
+        assert_eq!(kind(&17), 0);
+        assert_eq!(kind(&"hi"), 1);
+        assert_eq!(kind(&()), 2);
+    }
+
+    #[test]
+    fn subject_is_as() {
+        use core::any::Any;
+
+        let double = |x: &dyn Any| {
+            when! {
+                x;
+                is i32 as n => n * 2,
+                is u8 as n => i32::from(*n) * 2,
+                _ => 0,
+            }
+        };
+
+        assert_eq!(double(&17), 34);
+        assert_eq!(double(&2u8), 4);
+        assert_eq!(double(&"hi"), 0);
     }
 }
@@ -0,0 +1,31 @@
+// `defmt` loggers are implemented with `unsafe`, which is forbidden in the crate itself
+#![cfg(feature = "defmt")]
+
+// A logger is needed to link the test, the messages are filtered out at compile time unless
+// `DEFMT_LOG` is set
+defmt::timestamp!("");
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_: &[u8]) {}
+}
+
+#[test]
+fn defmt_when() {
+    let f = |x: i32| {
+        kiam::defmt_when! {
+            "small": x < 5 => "small",
+            x < 10 => "medium",
+            _ => "large",
+        }
+    };
+
+    assert_eq!(f(1), "small");
+    assert_eq!(f(7), "medium");
+    assert_eq!(kiam::defmt_when! { debug; _ => 1 }, 1);
+}
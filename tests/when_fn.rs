@@ -0,0 +1,72 @@
+// `#[when_fn]` expands to paths starting with `::kiam`, which only resolve outside of the crate
+#![cfg(feature = "macros")]
+
+use kiam::when_fn;
+
+#[derive(Debug, PartialEq)]
+struct Error;
+
+impl From<kiam::NoMatch> for Error {
+    fn from(_: kiam::NoMatch) -> Self {
+        Error
+    }
+}
+
+#[when_fn]
+fn parity(x: i32) -> Option<&'static str> {
+    when! {
+        x % 2 == 0 => "even",
+        let 1 | -1 = x % 2 => "odd",
+    }
+}
+
+#[when_fn]
+fn digit(c: char) -> core::result::Result<u32, Error> {
+    kiam::when! {
+        let Some(d) = c.to_digit(10) => d,
+    }
+}
+
+#[when_fn]
+fn larger<'a, T>(a: &'a T, b: &'a T) -> ::std::option::Option<&'a T>
+where
+    T: PartialOrd,
+{
+    when! {
+        a > b => a,
+        b > a => b,
+    }
+}
+
+#[when_fn]
+fn len<T: AsRef<str> + Copy, F: Fn(T) -> bool>(s: T, skip: F) -> usize {
+    when! {
+        !skip(s) => s.as_ref().len(),
+    }
+}
+
+#[when_fn]
+fn log(x: i32, out: &mut [i32; 2]) {
+    when! {
+        x < 0 => out[0] += 1,
+        _ => out[1] += 1,
+    }
+}
+
+#[test]
+fn when_fn() {
+    assert_eq!(parity(3), Some("odd"));
+    assert_eq!(parity(-4), Some("even"));
+    assert_eq!(digit('7'), Ok(7));
+    assert_eq!(digit('x'), Err(Error));
+    assert_eq!(larger(&1, &2), Some(&2));
+    assert_eq!(larger(&2, &2), None);
+    assert_eq!(len("abc", |_| false), 3);
+    assert_eq!(len("abc", |_| true), 0);
+
+    let mut out = [0; 2];
+    log(-1, &mut out);
+    log(1, &mut out);
+    log(2, &mut out);
+    assert_eq!(out, [1, 2]);
+}